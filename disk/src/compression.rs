@@ -0,0 +1,65 @@
+use serde::Deserialize;
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Write};
+
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xB5, 0x2F, 0xFD];
+const LZ4_MAGIC: [u8; 4] = [0x04, 0x22, 0x4D, 0x18];
+
+/// Algorithm used to compress segments before they are written to disk
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    #[default]
+    None,
+    Zstd,
+    Lz4,
+}
+
+impl Compression {
+    /// Identifies compression of a segment from the magic number at the start
+    /// of the file. Uncompressed segments start with the length of their first
+    /// record, which would have to be larger than 60MB to be mistaken for either
+    pub(crate) fn detect(head: &[u8]) -> Compression {
+        if head.starts_with(&ZSTD_MAGIC) {
+            Compression::Zstd
+        } else if head.starts_with(&LZ4_MAGIC) {
+            Compression::Lz4
+        } else {
+            Compression::None
+        }
+    }
+
+    /// Writes `data` into `file`, compressed with this algorithm
+    pub(crate) fn write(&self, file: &mut File, data: &[u8]) -> io::Result<()> {
+        match self {
+            Compression::None => file.write_all(data)?,
+            Compression::Zstd => {
+                let mut encoder = zstd::Encoder::new(file, 0)?;
+                encoder.write_all(data)?;
+                encoder.finish()?;
+            }
+            Compression::Lz4 => {
+                let mut encoder = lz4_flex::frame::FrameEncoder::new(file);
+                encoder.write_all(data)?;
+                encoder.finish()?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Opens a segment for reading, transparently decompressing it based on
+/// its contents
+pub(crate) fn open(file: File) -> io::Result<(Compression, Box<dyn Read + Send>)> {
+    let mut reader = BufReader::new(file);
+    let compression = Compression::detect(reader.fill_buf()?);
+    let reader: Box<dyn Read + Send> = match compression {
+        Compression::None => Box::new(reader),
+        Compression::Zstd => Box::new(zstd::Decoder::with_buffer(reader)?),
+        Compression::Lz4 => Box::new(lz4_flex::frame::FrameDecoder::new(reader)),
+    };
+
+    Ok((compression, reader))
+}
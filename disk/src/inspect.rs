@@ -0,0 +1,130 @@
+//! Read only access to segments of a persistence directory, for inspecting
+//! it offline. Nothing on disk is modified, not even torn tails, unlike
+//! [`Storage`](crate::Storage)
+use std::fs::{self, File};
+use std::io::{self, ErrorKind, Read};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use crate::{compression, file_name, get_file_ids, now, Compression, Header, HEADER_SIZE};
+
+/// A segment on disk
+#[derive(Debug, Clone)]
+pub struct Segment {
+    pub priority: u8,
+    pub id: u64,
+    pub path: PathBuf,
+    /// size on disk, after compression
+    pub size: u64,
+    pub compression: Compression,
+    pub modified: SystemTime,
+}
+
+impl Segment {
+    pub fn name(&self) -> String {
+        file_name(self.priority, self.id)
+    }
+
+    /// Iterates over records of the segment, in the order they were written
+    pub fn records(&self) -> io::Result<Records> {
+        let file = File::open(&self.path)?;
+        let (_, reader) = compression::open(file)?;
+        Ok(Records { reader, done: false })
+    }
+}
+
+/// Lists segments in `backlog_dir` in the order they are reloaded, i.e
+/// oldest segment of highest priority first
+pub fn segments<P: AsRef<Path>>(backlog_dir: P) -> io::Result<Vec<Segment>> {
+    let backlog_dir = backlog_dir.as_ref();
+    let mut segments = Vec::new();
+
+    for (priority, ids) in get_file_ids(backlog_dir)?.into_iter().rev() {
+        for id in ids {
+            let path = backlog_dir.join(file_name(priority, id));
+            let metadata = fs::metadata(&path)?;
+            let (compression, _) = compression::open(File::open(&path)?)?;
+            segments.push(Segment {
+                priority,
+                id,
+                path,
+                size: metadata.len(),
+                compression,
+                modified: metadata.modified()?,
+            });
+        }
+    }
+
+    Ok(segments)
+}
+
+/// A record along with the state of its frame
+#[derive(Debug, Clone)]
+pub struct Record {
+    pub data: Vec<u8>,
+    /// expiry in milliseconds since unix epoch, 0 if the record never expires
+    pub expiry: u64,
+    /// false if checksum of the record doesn't match
+    pub valid: bool,
+}
+
+impl Record {
+    /// True if record is past its expiry and won't be reloaded
+    pub fn is_expired(&self) -> bool {
+        self.expiry != 0 && self.expiry <= now()
+    }
+}
+
+/// Iterator over records of a segment. Yields an error of kind
+/// `UnexpectedEof` for a torn record at the tail and stops
+pub struct Records {
+    reader: Box<dyn Read + Send>,
+    done: bool,
+}
+
+impl Records {
+    fn read(&mut self) -> io::Result<Option<Record>> {
+        let mut header = [0; HEADER_SIZE];
+        let mut read = 0;
+        while read < HEADER_SIZE {
+            match self.reader.read(&mut header[read..])? {
+                0 if read == 0 => return Ok(None),
+                0 => return Err(io::Error::new(ErrorKind::UnexpectedEof, "Torn record header")),
+                n => read += n,
+            }
+        }
+
+        // Data is read as it comes, to not allocate a lot for a corrupt size
+        let header = Header::read(&header);
+        let mut data = Vec::new();
+        (&mut self.reader).take(header.size as u64).read_to_end(&mut data)?;
+        if data.len() < header.size {
+            return Err(io::Error::new(ErrorKind::UnexpectedEof, "Torn record"));
+        }
+
+        let valid = header.is_valid(&data);
+        Ok(Some(Record { data, expiry: header.expiry, valid }))
+    }
+}
+
+impl Iterator for Records {
+    type Item = io::Result<Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        match self.read() {
+            Ok(Some(record)) => Some(Ok(record)),
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
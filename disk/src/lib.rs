@@ -2,24 +2,201 @@
 extern crate log;
 
 use bytes::{Buf, BufMut, BytesMut};
+use seahash::SeaHasher;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::ffi::OsStr;
 use std::fs::{File, OpenOptions};
-use std::io::{ErrorKind, Read, Write};
+use std::hash::Hasher;
+use std::io::{BufRead, BufReader, ErrorKind, Read, Write};
+use std::os::unix::fs::FileExt;
 use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use std::{fs, io, mem};
 
+mod compression;
+pub mod inspect;
+
+pub use compression::Compression;
+
+/// Every record is prefixed with a 4 byte length, an 8 byte seahash checksum
+/// and an 8 byte expiry timestamp in milliseconds, 0 if the record never
+/// expires. Checksum covers the expiry and contents of the record. This lets
+/// us detect torn writes and bit rot on segments written before an abrupt
+/// power cut
+const HEADER_SIZE: usize = 20;
+
+/// Segments on disk are read in chunks of atleast this size, keeping memory
+/// usage during catchup independent of the configured segment size
+const READ_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Name of the file holding acknowledgement progress of the oldest segment
+/// that is being caught up. Persisted as priority(1) + id(8) + acked(8)
+const CURSOR_FILE: &str = "cursor";
+const CURSOR_SIZE: usize = 17;
+
 pub struct Storage {
-    /// list of backlog file ids. Mutated only be the serialization part of the sender
-    backlog_file_ids: Vec<u64>,
     /// persistence path
     backup_path: PathBuf,
     /// maximum allowed file size
     max_file_size: usize,
-    /// maximum number of files before deleting old file
+    /// maximum number of files, across all priorities and including those
+    /// that are yet to be acknowledged, before deleting old file
     max_file_count: usize,
-    /// current open file
-    current_write_file: BytesMut,
+    /// compression of segments written to disk
+    compression: Compression,
+    /// age beyond which all the records of a segment are expired
+    max_age: Option<Duration>,
+    /// maximum number of bytes, across all segments, allowed on disk
+    max_disk_usage: Option<usize>,
+    /// free space to leave on the filesystem of `backup_path`
+    min_free_space: Option<usize>,
+    /// number of bytes in segments that are currently on disk
+    disk_usage: usize,
+    /// segments deleted or dropped to stay within disk usage limits
+    quota_evictions: usize,
+    /// total size of segments flushed to disk, before compression
+    flushed_size: usize,
+    /// total size of segments flushed to disk, after compression
+    flushed_disk_size: usize,
+    /// backlog of every priority. Data of higher priority is read first
+    /// and deleted last
+    queues: BTreeMap<u8, Queue>,
     /// current_read_file
     current_read_file: BytesMut,
+    /// expiry of every record in `current_read_file`, in order
+    read_expiries: VecDeque<u64>,
+    /// segment that is being read into `current_read_file` in chunks
+    current_read_segment: Option<ReadSegment>,
+    /// segments, in the order they are read, with records that are yet to be
+    /// acknowledged. Segments on disk are deleted only after all their
+    /// records are committed
+    unacked: VecDeque<Cursor>,
+    /// number of records handed out by `reload` in this session. Records are
+    /// numbered in the order they are handed out, to commit them by
+    sequence: u64,
+    /// persisted read cursor of the oldest unacknowledged segment
+    cursor: File,
+    /// cursor persisted in a previous session, to resume reading from
+    resume: Option<(u8, u64, usize)>,
+    /// bytes discarded while recovering segments of previous sessions
+    discarded: usize,
+    /// bytes dropped during reload as they were past their expiry
+    expired: usize,
+}
+
+/// Backlog files and inmemory write buffer of a single priority
+struct Queue {
+    /// list of backlog file ids. Mutated only be the serialization part of the sender
+    file_ids: Vec<u64>,
+    /// current open file
+    current_write_file: BytesMut,
+    /// id of the next file. Files that are read but not yet acknowledged are
+    /// still on disk, so ids can't be derived from `file_ids`
+    next_id: u64,
+}
+
+impl Queue {
+    fn new(file_ids: Vec<u64>, max_file_size: usize) -> Queue {
+        let next_id = file_ids.last().map_or(0, |id| id + 1);
+        Queue { file_ids, current_write_file: BytesMut::with_capacity(max_file_size * 2), next_id }
+    }
+}
+
+/// Segment on disk that is being read, a chunk at a time
+struct ReadSegment {
+    priority: u8,
+    id: u64,
+    reader: Box<dyn Read + Send>,
+}
+
+impl ReadSegment {
+    fn name(&self) -> String {
+        file_name(self.priority, self.id)
+    }
+}
+
+/// Read and acknowledgement progress of a segment. Segments that are
+/// swapped in from inmemory write buffer don't have an id
+struct Cursor {
+    priority: u8,
+    id: Option<u64>,
+    /// sequence number of the first record handed out from this segment
+    first: u64,
+    /// index of the first record handed out from this segment, records
+    /// before it were acknowledged in a previous session
+    offset: usize,
+    /// records handed out to the reader
+    read: usize,
+    /// records, from the start of the segment, that are all committed
+    acked: usize,
+    /// records committed out of order, ahead of `acked`
+    acks: BTreeSet<usize>,
+    /// expired records that were dropped, as (number of records handed out
+    /// before them, count). Needed to persist position of the cursor
+    expired: VecDeque<(usize, usize)>,
+    /// expired records before the first unacknowledged record
+    expired_acked: usize,
+    /// true once all the records of the segment are read
+    done: bool,
+}
+
+impl Cursor {
+    fn new(priority: u8, id: Option<u64>, first: u64, acked: usize) -> Cursor {
+        Cursor {
+            priority,
+            id,
+            first,
+            offset: acked,
+            read: acked,
+            acked,
+            acks: BTreeSet::new(),
+            expired: VecDeque::new(),
+            expired_acked: 0,
+            done: false,
+        }
+    }
+
+    /// Number of valid records, from the start of the segment, that don't
+    /// have to be read again after a restart
+    fn position(&self) -> usize {
+        self.acked + self.expired_acked
+    }
+
+    /// Index within the segment of record with given sequence number, if it
+    /// was handed out from this segment
+    fn index(&self, sequence: u64) -> Option<usize> {
+        let handed_out = (self.read - self.offset) as u64;
+        if sequence < self.first || sequence - self.first >= handed_out {
+            return None;
+        }
+
+        Some(self.offset + (sequence - self.first) as usize)
+    }
+
+    /// Acknowledges record at given index of the segment
+    fn ack(&mut self, index: usize) {
+        if index < self.acked {
+            return;
+        }
+
+        self.acks.insert(index);
+        while self.acks.remove(&self.acked) {
+            self.acked += 1;
+        }
+
+        while let Some(&(before, count)) = self.expired.front() {
+            if before > self.acked {
+                break;
+            }
+
+            self.expired_acked += count;
+            self.expired.pop_front();
+        }
+    }
+
+    fn is_acked(&self) -> bool {
+        self.done && self.acked >= self.read
+    }
 }
 
 impl Storage {
@@ -29,299 +206,1895 @@ impl Storage {
         max_file_count: usize,
     ) -> io::Result<Storage> {
         let backup_path = backlog_dir.into();
-        let backlog_file_ids = get_file_ids(&backup_path)?;
+        remove_incomplete(&backup_path)?;
+        let mut file_ids = get_file_ids(&backup_path)?;
+        let discarded = recover(&backup_path, &mut file_ids)?;
+        if discarded > 0 {
+            warn!("Discarded {} corrupt bytes while recovering {:?}", discarded, backup_path);
+        }
+
+        let mut disk_usage = 0;
+        for (&priority, ids) in file_ids.iter() {
+            for &id in ids {
+                disk_usage +=
+                    fs::metadata(backup_path.join(file_name(priority, id)))?.len() as usize;
+            }
+        }
+
+        let queues = file_ids
+            .into_iter()
+            .map(|(priority, ids)| (priority, Queue::new(ids, max_file_size)))
+            .collect();
+
+        let legacy = legacy_ids(&backup_path)?;
+        if !legacy.is_empty() {
+            warn!("Found {} unframed segments in {:?} to be migrated", legacy.len(), backup_path);
+        }
+
+        let cursor_path = backup_path.join(CURSOR_FILE);
+        let cursor = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(cursor_path)?;
+        let resume = load_cursor(&cursor)?;
 
         Ok(Storage {
-            backlog_file_ids,
             backup_path,
             max_file_size,
             max_file_count,
-            current_write_file: BytesMut::with_capacity(max_file_size * 2),
-            current_read_file: BytesMut::with_capacity(max_file_size * 2),
+            compression: Compression::None,
+            max_age: None,
+            max_disk_usage: None,
+            min_free_space: None,
+            disk_usage,
+            quota_evictions: 0,
+            flushed_size: 0,
+            flushed_disk_size: 0,
+            queues,
+            current_read_file: BytesMut::with_capacity(READ_CHUNK_SIZE),
+            read_expiries: VecDeque::new(),
+            current_read_segment: None,
+            unacked: VecDeque::new(),
+            sequence: 0,
+            cursor,
+            resume,
+            discarded,
+            expired: 0,
         })
     }
 
-    pub fn writer(&mut self) -> &mut BytesMut {
-        &mut self.current_write_file
+    /// Sets compression of segments that are flushed to disk from here on.
+    /// Segments already on disk are read irrespective of this setting
+    pub fn set_compression(&mut self, compression: Compression) {
+        self.compression = compression;
+    }
+
+    /// Age beyond which every record written to storage is expired. Segments
+    /// last modified longer ago than this are deleted without being read.
+    /// Records carry their own expiry and are dropped irrespective of this
+    pub fn set_max_age(&mut self, max_age: Option<Duration>) {
+        self.max_age = max_age;
+    }
+
+    /// Limits total size of segments on disk. Old segments are deleted to
+    /// make space for new ones once the limit is reached
+    pub fn set_max_disk_usage(&mut self, max_disk_usage: Option<usize>) {
+        self.max_disk_usage = max_disk_usage;
+    }
+
+    /// Minimum free space to leave on the filesystem holding the segments.
+    /// Guards against other software filling up a shared partition
+    pub fn set_min_free_space(&mut self, min_free_space: Option<usize>) {
+        self.min_free_space = min_free_space;
+    }
+
+    /// Number of bytes in segments that are currently on disk
+    pub fn disk_usage(&self) -> usize {
+        self.disk_usage
+    }
+
+    /// Number of segments on disk, both those that are yet to be read and
+    /// those with records that are yet to be acknowledged
+    pub fn file_count(&self) -> usize {
+        let unacked = self.unacked.iter().filter(|c| c.id.is_some()).count();
+        self.queues.values().map(|q| q.file_ids.len()).sum::<usize>() + unacked
+    }
+
+    /// Deletes oldest segment of the lowest priority backlog, whether or not it
+    /// is read. Lets multiple storages share a budget. Returns priority and
+    /// id of the deleted segment, if there was one
+    pub fn delete_oldest(&mut self) -> io::Result<Option<(u8, u64)>> {
+        self.evict(u8::MAX)
+    }
+
+    /// Number of segments deleted or dropped in this session, to stay
+    /// within configured disk usage and free space limits
+    pub fn quota_evictions(&self) -> usize {
+        self.quota_evictions
+    }
+
+    /// Total size of segments flushed to disk in this session, before compression
+    pub fn flushed_size(&self) -> usize {
+        self.flushed_size
+    }
+
+    /// Total size of segments flushed to disk in this session, after compression
+    pub fn flushed_disk_size(&self) -> usize {
+        self.flushed_disk_size
+    }
+
+    /// Number of bytes in segments of previous sessions that were found
+    /// corrupt during recovery and won't be reloaded
+    pub fn discarded(&self) -> usize {
+        self.discarded
+    }
+
+    /// Number of bytes dropped in this session as they expired before
+    /// they could be reloaded
+    pub fn expired(&self) -> usize {
+        self.expired
+    }
+
+    /// Frames a record with its length, checksum and expiry and appends it
+    /// to the inmemory write buffer of given priority. Record is dropped
+    /// during reload once it is older than `max_age`. Records larger than a
+    /// segment are rejected, as they can't be read back
+    pub fn write(
+        &mut self,
+        record: &[u8],
+        priority: u8,
+        max_age: Option<Duration>,
+    ) -> io::Result<()> {
+        let max_file_size = self.max_file_size;
+        if record.len() > max_file_size {
+            let error = format!("Record size {} is larger than segment size", record.len());
+            return Err(io::Error::new(ErrorKind::InvalidInput, error));
+        }
+
+        let queue =
+            self.queues.entry(priority).or_insert_with(|| Queue::new(vec![], max_file_size));
+        let expiry = max_age.map_or(0, |age| now() + age.as_millis() as u64);
+
+        queue.current_write_file.put_u32(record.len() as u32);
+        queue.current_write_file.put_u64(checksum(expiry, record));
+        queue.current_write_file.put_u64(expiry);
+        queue.current_write_file.put_slice(record);
+        Ok(())
+    }
+
+    /// Migrates segments written before records were framed, named
+    /// backup@<id>, into the backlog of priority 0. Records of such segments
+    /// are back to back and `split` takes the next whole record off the front
+    /// of their contents, returning `None` once there isn't one. A segment is
+    /// deleted only after its records are flushed to disk again. Returns
+    /// number of segments migrated
+    pub fn migrate<F>(&mut self, mut split: F) -> io::Result<usize>
+    where
+        F: FnMut(&mut BytesMut) -> Option<BytesMut>,
+    {
+        let ids = legacy_ids(&self.backup_path)?;
+        for &id in ids.iter() {
+            let path = self.backup_path.join(format!("backup@{}", id));
+            let mut buf = BytesMut::from(&fs::read(&path)?[..]);
+            let mut count = 0;
+            while let Some(record) = split(&mut buf) {
+                if let Err(e) = self.write(&record, 0, None) {
+                    warn!("Discarding record of {:?}. Error = {}", path, e);
+                    self.discarded += record.len();
+                    continue;
+                }
+
+                self.flush_on_overflow()?;
+                count += 1;
+            }
+
+            if !buf.is_empty() {
+                warn!("Discarding {} unreadable bytes of {:?}", buf.len(), path);
+                self.discarded += buf.len();
+            }
+
+            self.flush_all()?;
+            fs::remove_file(&path)?;
+            info!("Migrated {} records of {:?}", count, path);
+        }
+
+        Ok(ids.len())
+    }
+
+    pub fn reader(&mut self) -> &mut BytesMut {
+        &mut self.current_read_file
+    }
+
+    /// Expiry, in milliseconds since unix epoch, of the next record read off
+    /// `reader()`. 0 if it never expires. To be taken once for every record
+    pub fn take_expiry(&mut self) -> u64 {
+        self.read_expiries.pop_front().unwrap_or_default()
+    }
+
+    /// Removes a file with provided priority and id
+    fn remove(&mut self, priority: u8, id: u64) -> io::Result<()> {
+        let path = self.backup_path.join(file_name(priority, id));
+        let size = fs::metadata(&path)?.len() as usize;
+        fs::remove_file(path)?;
+        self.disk_usage = self.disk_usage.saturating_sub(size);
+        Ok(())
+    }
+
+    /// Checks if a segment of given size fits within configured disk usage
+    /// and leaves configured free space on the filesystem
+    fn has_space(&self, size: usize) -> io::Result<bool> {
+        if let Some(max_disk_usage) = self.max_disk_usage {
+            if self.disk_usage + size > max_disk_usage {
+                return Ok(false);
+            }
+        }
+
+        if let Some(min_free_space) = self.min_free_space {
+            let available = fs2::available_space(&self.backup_path)? as usize;
+            if available < min_free_space.saturating_add(size) {
+                return Ok(false);
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Deletes the oldest file of the lowest priority backlog, to make space
+    /// for a new file of given priority. Files that are being read, or have
+    /// records yet to be acknowledged, are older than those yet to be read and
+    /// are deleted first. Returns `None` when all the files on disk are of
+    /// higher priority, in which case the new file is dropped
+    fn evict(&mut self, priority: u8) -> io::Result<Option<(u8, u64)>> {
+        let unacked = self.unacked.iter().filter(|c| c.id.is_some()).map(|c| c.priority);
+        let queued = self.queues.iter().filter(|(_, q)| !q.file_ids.is_empty()).map(|(&p, _)| p);
+        let lowest = match unacked.chain(queued).min() {
+            Some(lowest) => lowest,
+            None => return Ok(None),
+        };
+
+        if lowest > priority {
+            return Ok(None);
+        }
+
+        let id = match self.unacked.iter().position(|c| c.id.is_some() && c.priority == lowest) {
+            Some(position) => self.forget(position)?,
+            None => self.queues.get_mut(&lowest).unwrap().file_ids.remove(0),
+        };
+
+        warn!("file limit reached. deleting {}", file_name(lowest, id));
+        self.remove(lowest, id)?;
+        Ok(Some((lowest, id)))
+    }
+
+    /// Stops tracking acknowledgements of an unacknowledged segment that is
+    /// about to be deleted, along with reading it if it is still being read.
+    /// Records of it that are already handed out can't be committed anymore.
+    /// Returns id of the segment
+    fn forget(&mut self, position: usize) -> io::Result<u64> {
+        let cursor = self.unacked.remove(position).unwrap();
+        let id = cursor.id.unwrap();
+        // Segment being read is always the latest unacknowledged segment
+        if position == self.unacked.len() {
+            self.current_read_segment = None;
+        }
+
+        // Cursor is moved on before deleting the segment
+        if position == 0 {
+            self.save_cursor()?;
+            self.retire()?;
+        }
+
+        Ok(id)
+    }
+
+    /// Opens file to flush inmemory write buffer of given priority to disk.
+    /// Also handles retention of previous files on disk. Returns `None` if
+    /// there is no space left for data of this priority
+    fn open_next_write_file(&mut self, priority: u8) -> io::Result<Option<NextFile>> {
+        let mut deleted = None;
+        if self.file_count() >= self.max_file_count {
+            deleted = self.evict(priority)?;
+            if deleted.is_none() {
+                return Ok(None);
+            }
+        }
+
+        // Size before compression is an upper bound of what is written to disk
+        let size = self.queues[&priority].current_write_file.len();
+        while !self.has_space(size)? {
+            self.quota_evictions += 1;
+            match self.evict(priority)? {
+                Some(d) => deleted = Some(d),
+                None => return Ok(None),
+            }
+        }
+
+        let queue = self.queues.get_mut(&priority).unwrap();
+        let next_file_id = queue.next_id;
+        let next_file_path = self.backup_path.join(file_name(priority, next_file_id));
+
+        // Compressed segments are written to a temporary file and renamed once
+        // complete, as a torn compressed segment can't be recovered
+        let open_path = match self.compression {
+            Compression::None => next_file_path.clone(),
+            _ => next_file_path.with_extension("tmp"),
+        };
+
+        let next_file =
+            OpenOptions::new().write(true).create(true).truncate(true).open(&open_path)?;
+        queue.file_ids.push(next_file_id);
+        queue.next_id += 1;
+
+        Ok(Some(NextFile { path: next_file_path, open_path, file: next_file, deleted }))
+    }
+
+    /// Flushes what ever is in write buffer of given priority into a new
+    /// file on the disk. Returns id of the file that was deleted to make
+    /// space, or of the data that was dropped, if the disk is full
+    #[inline]
+    fn flush(&mut self, priority: u8) -> io::Result<Option<u64>> {
+        let mut next_file = match self.open_next_write_file(priority)? {
+            Some(next_file) => next_file,
+            None => {
+                let queue = self.queues.get_mut(&priority).unwrap();
+                let id = queue.next_id;
+                warn!("file limit reached. dropping {}", file_name(priority, id));
+                queue.current_write_file.clear();
+                // Id of dropped data isn't reused, to not mix it up with the next file
+                queue.next_id += 1;
+                return Ok(Some(id));
+            }
+        };
+
+        info!("Flushing data to disk!! {:?}", next_file.path);
+        let compression = self.compression;
+        let queue = self.queues.get_mut(&priority).unwrap();
+        compression.write(&mut next_file.file, &queue.current_write_file[..])?;
+        next_file.file.flush()?;
+        if next_file.open_path != next_file.path {
+            next_file.file.sync_all()?;
+            fs::rename(&next_file.open_path, &next_file.path)?;
+        }
+
+        let disk_size = next_file.file.metadata()?.len() as usize;
+        self.flushed_size += queue.current_write_file.len();
+        self.flushed_disk_size += disk_size;
+        self.disk_usage += disk_size;
+        queue.current_write_file.clear();
+        Ok(next_file.deleted.map(|(_, id)| id))
+    }
+
+    /// Flushes write buffers of all priorities to disk irrespective of their
+    /// size. Call this before shutting down to not lose data that is still in
+    /// memory. Higher priorities are flushed first to give them precedence
+    /// when disk is near full
+    pub fn flush_all(&mut self) -> io::Result<Option<u64>> {
+        let mut deleted = None;
+        let priorities: Vec<u8> = self.queues.keys().rev().copied().collect();
+        for priority in priorities {
+            if self.queues[&priority].current_write_file.is_empty() {
+                continue;
+            }
+
+            if let Some(id) = self.flush(priority)? {
+                deleted = Some(id);
+            }
+        }
+
+        Ok(deleted)
+    }
+
+    /// Checks write buffer sizes and flushes them to disk when the size
+    /// exceeds configured size
+    pub fn flush_on_overflow(&mut self) -> io::Result<Option<u64>> {
+        let mut deleted = None;
+        let priorities: Vec<u8> = self
+            .queues
+            .iter()
+            .filter(|(_, q)| q.current_write_file.len() >= self.max_file_size)
+            .map(|(&priority, _)| priority)
+            .collect();
+
+        for priority in priorities {
+            if let Some(id) = self.flush(priority)? {
+                deleted = Some(id);
+            }
+        }
+
+        Ok(deleted)
+    }
+
+    /// Reloads next buffer even if there is pending data in current buffer
+    pub fn reload(&mut self) -> io::Result<bool> {
+        self.current_read_file.clear();
+        self.read_expiries.clear();
+        let now = now();
+
+        loop {
+            // Continue reading from the segment that is already open. Segment
+            // is deleted only after all of it's records are acknowledged
+            if let Some(segment) = &mut self.current_read_segment {
+                // Segment being read is always the latest unacknowledged segment
+                let cursor = self.unacked.back_mut().unwrap();
+                let buf = &mut self.current_read_file;
+                let expiries = &mut self.read_expiries;
+                let read = cursor.read;
+                let max_size = self.max_file_size;
+                let chunk = read_chunk(&mut segment.reader, buf, expiries, max_size, now, cursor);
+                self.sequence += (cursor.read - read) as u64;
+                match chunk {
+                    Ok((skipped, expired)) => {
+                        if skipped > 0 {
+                            warn!("Skipped {} corrupt bytes in {}", skipped, segment.name());
+                        }
+
+                        self.expired += expired;
+                    }
+                    // Corrupt compressed data, skip rest of the segment
+                    Err(e) if matches!(e.kind(), ErrorKind::InvalidData | ErrorKind::Other) => {
+                        error!("Failed to read {}, skipping rest. Error = {:?}", segment.name(), e);
+                        segment.reader = Box::new(io::empty());
+                    }
+                    Err(e) => return Err(e),
+                };
+
+                if !self.current_read_file.is_empty() {
+                    return Ok(false);
+                }
+
+                cursor.done = true;
+                self.current_read_segment = None;
+                self.retire()?;
+            }
+
+            // Pick highest priority backlog with pending data
+            let next = self
+                .queues
+                .iter_mut()
+                .rev()
+                .find(|(_, q)| !q.file_ids.is_empty() || !q.current_write_file.is_empty());
+
+            let (&priority, queue) = match next {
+                Some(next) => next,
+                // All the data is caught up
+                None => return Ok(true),
+            };
+
+            // Swap read buffer with write buffer to read data in inmemory write
+            // buffer when all the backlog disk files of this priority are done
+            if queue.file_ids.is_empty() {
+                mem::swap(&mut self.current_read_file, &mut queue.current_write_file);
+                let mut cursor = Cursor::new(priority, None, self.sequence, 0);
+                let buf = &mut self.current_read_file;
+                let (_, expired) = unframe(buf, &mut self.read_expiries, now, &mut cursor);
+                self.sequence += cursor.read as u64;
+                self.expired += expired;
+
+                // Move on to next backlog if nothing is left in read buffer after swapping
+                if self.current_read_file.is_empty() {
+                    continue;
+                }
+
+                cursor.done = true;
+                self.unacked.push_back(cursor);
+                return Ok(false);
+            }
+
+            // Len always > 0 because of above if. Doesn't panic
+            let id = queue.file_ids.remove(0);
+            let next_file_path = self.backup_path.join(file_name(priority, id));
+            let file = OpenOptions::new().read(true).open(&next_file_path)?;
+
+            // Every record in a segment was written before it was last modified
+            let metadata = file.metadata()?;
+            let age = metadata.modified()?.elapsed().unwrap_or_default();
+            if matches!(self.max_age, Some(max_age) if age > max_age) {
+                warn!("Deleting expired segment {}", file_name(priority, id));
+                self.expired += metadata.len() as usize;
+                self.remove(priority, id)?;
+                if matches!(self.resume, Some((p, i, _)) if (p, i) == (priority, id)) {
+                    self.resume = None;
+                    self.save_cursor()?;
+                }
+
+                continue;
+            }
+
+            let (_, mut reader) = compression::open(file)?;
+
+            // Resume from the record after the last one acknowledged in previous session
+            let mut acked = 0;
+            if let Some((p, i, count)) = self.resume {
+                if (p, i) == (priority, id) {
+                    acked = skip_records(&mut reader, count, self.max_file_size)?;
+                    info!("Resuming {} after {} acknowledged records", file_name(p, i), acked);
+                    self.resume = None;
+                }
+            }
+
+            self.unacked.push_back(Cursor::new(priority, Some(id), self.sequence, acked));
+            self.current_read_segment = Some(ReadSegment { priority, id, reader });
+        }
+    }
+
+    /// Acknowledges record with given sequence number, i.e the number of
+    /// records read off `reader` before it in this session. Records can be
+    /// committed in any order. A segment is deleted from disk once all of its
+    /// records are committed, and progress of the oldest segment is persisted
+    /// to resume from after a restart
+    pub fn commit(&mut self, record: u64) -> io::Result<()> {
+        let found = self.unacked.iter().enumerate().find_map(|(i, c)| Some((i, c.index(record)?)));
+        // Segment of the record was deleted to make space
+        let (position, index) = match found {
+            Some(found) => found,
+            None => return Ok(()),
+        };
+
+        self.unacked[position].ack(index);
+        if position > 0 {
+            return Ok(());
+        }
+
+        if self.unacked[0].is_acked() {
+            return self.retire();
+        }
+
+        if self.unacked[0].id.is_some() {
+            self.save_cursor()?;
+        }
+
+        Ok(())
+    }
+
+    /// Deletes segments, in read order, that are completely read and acknowledged
+    fn retire(&mut self) -> io::Result<()> {
+        loop {
+            match self.unacked.front() {
+                Some(cursor) if cursor.is_acked() => {}
+                _ => return Ok(()),
+            }
+
+            // Cursor is moved on before deleting the segment, to never
+            // leave behind a cursor that points to a deleted segment
+            let cursor = self.unacked.pop_front().unwrap();
+            self.save_cursor()?;
+            if let Some(id) = cursor.id {
+                self.remove(cursor.priority, id)?;
+            }
+        }
+    }
+
+    /// Persists acknowledgement progress of the oldest unacknowledged segment
+    fn save_cursor(&mut self) -> io::Result<()> {
+        match self.unacked.front() {
+            Some(cursor @ Cursor { id: Some(id), .. }) => {
+                let mut buf = Vec::with_capacity(CURSOR_SIZE);
+                buf.put_u8(cursor.priority);
+                buf.put_u64(*id);
+                buf.put_u64(cursor.position() as u64);
+                self.cursor.write_all_at(&buf, 0)
+            }
+            _ => self.cursor.set_len(0),
+        }
+    }
+
+    /// Loads next chunk of head file of highest priority backlog to current
+    /// inmemory read buffer. The file is deleted once all of its records are
+    /// committed.
+    /// If all the disk data is caught up, swaps write buffers to current
+    /// read buffer if there is pending data in memory write buffers.
+    /// Returns true if all the messages are caught up
+    pub fn reload_on_eof(&mut self) -> io::Result<bool> {
+        // Don't reload if there is data in current read file
+        if self.current_read_file.has_remaining() {
+            return Ok(false);
+        }
+
+        self.reload()
+    }
+}
+
+impl Drop for Storage {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush_all() {
+            error!("Failed to flush write buffer to disk on drop. Error = {:?}", e);
+        }
+    }
+}
+
+/// Frame header of a record
+struct Header {
+    size: usize,
+    checksum: u64,
+    expiry: u64,
+}
+
+impl Header {
+    fn read(mut buf: &[u8]) -> Header {
+        Header { size: buf.get_u32() as usize, checksum: buf.get_u64(), expiry: buf.get_u64() }
+    }
+
+    fn is_valid(&self, record: &[u8]) -> bool {
+        checksum(self.expiry, record) == self.checksum
+    }
+
+    fn is_expired(&self, now: u64) -> bool {
+        self.expiry != 0 && self.expiry <= now
+    }
+}
+
+/// Checksum of a record along with its expiry
+fn checksum(expiry: u64, record: &[u8]) -> u64 {
+    let mut hasher = SeaHasher::new();
+    hasher.write_u64(expiry);
+    hasher.write(record);
+    hasher.finish()
+}
+
+/// Current time in milliseconds since unix epoch
+fn now() -> u64 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    now.as_millis() as u64
+}
+
+/// Strips frame headers off the records in `buf`, in place, leaving behind
+/// only the records. Records failing checksum or past their expiry are
+/// dropped and a torn record at the tail is truncated. Records left behind
+/// are accounted in `cursor` and their expiries appended to `expiries`.
+/// Returns number of bytes dropped as corrupt and as expired
+fn unframe(
+    buf: &mut BytesMut,
+    expiries: &mut VecDeque<u64>,
+    now: u64,
+    cursor: &mut Cursor,
+) -> (usize, usize) {
+    let len = buf.len();
+    let mut read = 0;
+    let mut write = 0;
+    let mut discarded = 0;
+    let mut expired = 0;
+
+    while len - read >= HEADER_SIZE {
+        let header = Header::read(&buf[read..read + HEADER_SIZE]);
+        let start = read + HEADER_SIZE;
+        if len - start < header.size {
+            break;
+        }
+
+        let end = start + header.size;
+        if !header.is_valid(&buf[start..end]) {
+            discarded += HEADER_SIZE + header.size;
+        } else if header.is_expired(now) {
+            expired += HEADER_SIZE + header.size;
+        } else {
+            buf.copy_within(start..end, write);
+            write += header.size;
+            expiries.push_back(header.expiry);
+            cursor.read += 1;
+        }
+
+        read = end;
+    }
+
+    discarded += len - read;
+    buf.truncate(write);
+    (discarded, expired)
+}
+
+/// Reads records of a segment into `buf`, without their frame headers, until
+/// there are atleast `READ_CHUNK_SIZE` bytes in `buf` or the segment ends.
+/// Records failing checksum or past their expiry are dropped. Reading stops
+/// at a record that is torn, and the rest of the segment is dropped as
+/// corrupt at a record bigger than `max_record_size`. Records read,
+/// and expired records skipped between them, are accounted in `cursor`.
+/// Expiries of records read are appended to `expiries`. Returns number of
+/// bytes dropped as corrupt and as expired
+fn read_chunk<R: Read>(
+    reader: &mut R,
+    buf: &mut BytesMut,
+    expiries: &mut VecDeque<u64>,
+    max_record_size: usize,
+    now: u64,
+    cursor: &mut Cursor,
+) -> io::Result<(usize, usize)> {
+    let mut header = [0; HEADER_SIZE];
+    let mut discarded = 0;
+    let mut expired = 0;
+
+    while buf.len() < READ_CHUNK_SIZE {
+        match reader.read_exact(&mut header) {
+            Ok(_) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
+        }
+
+        let header = Header::read(&header);
+        if header.size > max_record_size {
+            warn!(
+                "Record size {} is larger than segment size. Skipping rest of segment",
+                header.size
+            );
+            // Records can't be framed past this, reader is left at the end
+            discarded += HEADER_SIZE + io::copy(reader, &mut io::sink())? as usize;
+            break;
+        }
+
+        let start = buf.len();
+        buf.resize(start + header.size, 0);
+        match reader.read_exact(&mut buf[start..]) {
+            Ok(_) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
+                buf.truncate(start);
+                break;
+            }
+            Err(e) => return Err(e),
+        }
+
+        if !header.is_valid(&buf[start..]) {
+            buf.truncate(start);
+            discarded += HEADER_SIZE + header.size;
+            continue;
+        }
+
+        if header.is_expired(now) {
+            buf.truncate(start);
+            expired += HEADER_SIZE + header.size;
+            match cursor.expired.back_mut() {
+                Some((before, count)) if *before == cursor.read => *count += 1,
+                _ => cursor.expired.push_back((cursor.read, 1)),
+            }
+            continue;
+        }
+
+        expiries.push_back(header.expiry);
+        cursor.read += 1;
     }
 
-    pub fn reader(&mut self) -> &mut BytesMut {
-        &mut self.current_read_file
-    }
+    Ok((discarded, expired))
+}
+
+/// Skips over `count` valid records of a segment, which were acknowledged,
+/// or expired, in a previous session. Returns number of records skipped
+fn skip_records<R: Read>(
+    reader: &mut R,
+    count: usize,
+    max_record_size: usize,
+) -> io::Result<usize> {
+    let mut header = [0; HEADER_SIZE];
+    let mut record = Vec::new();
+    let mut skipped = 0;
+
+    while skipped < count {
+        match reader.read_exact(&mut header) {
+            Ok(_) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
+        }
+
+        let header = Header::read(&header);
+        if header.size > max_record_size {
+            break;
+        }
+
+        record.resize(header.size, 0);
+        match reader.read_exact(&mut record) {
+            Ok(_) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
+        }
+
+        // Corrupt records are never handed out and hence not acknowledged
+        if header.is_valid(&record) {
+            skipped += 1;
+        }
+    }
+
+    Ok(skipped)
+}
+
+/// Reads cursor persisted by a previous session, if any
+fn load_cursor(file: &File) -> io::Result<Option<(u8, u64, usize)>> {
+    let mut buf = [0; CURSOR_SIZE];
+    match file.read_exact_at(&mut buf, 0) {
+        Ok(_) => {}
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+
+    let mut buf = &buf[..];
+    Ok(Some((buf.get_u8(), buf.get_u64(), buf.get_u64() as usize)))
+}
+
+/// Validates records of a segment written in a previous session. A torn
+/// record at the tail, left behind by an interrupted write, is truncated.
+/// Records with a bad checksum are left to be skipped during reload.
+/// Compressed segments are written atomically and aren't validated.
+/// Returns number of bytes discarded
+fn recover_segment(path: &Path) -> io::Result<usize> {
+    let file = OpenOptions::new().read(true).write(true).open(path)?;
+    let len = file.metadata()?.len() as usize;
+    let mut reader = BufReader::new(&file);
+    if Compression::detect(reader.fill_buf()?) != Compression::None {
+        return Ok(0);
+    }
+
+    let mut header = [0; HEADER_SIZE];
+    let mut record = Vec::new();
+    let mut offset = 0;
+    let mut discarded = 0;
+
+    while len - offset >= HEADER_SIZE {
+        reader.read_exact(&mut header)?;
+        let header = Header::read(&header);
+        if len - offset - HEADER_SIZE < header.size {
+            break;
+        }
+
+        record.resize(header.size, 0);
+        reader.read_exact(&mut record)?;
+        if !header.is_valid(&record) {
+            discarded += HEADER_SIZE + header.size;
+        }
+
+        offset += HEADER_SIZE + header.size;
+    }
+
+    if offset < len {
+        warn!("Truncating torn tail of {:?} at {}", path, offset);
+        file.set_len(offset as u64)?;
+        discarded += len - offset;
+    }
+
+    Ok(discarded)
+}
+
+/// Runs recovery on all the segments on disk. Segments left with no valid
+/// data are deleted. Returns total number of bytes discarded
+fn recover(backup_path: &Path, file_ids: &mut BTreeMap<u8, Vec<u64>>) -> io::Result<usize> {
+    let mut discarded = 0;
+
+    for (&priority, ids) in file_ids.iter_mut() {
+        let mut valid = Vec::with_capacity(ids.len());
+        for id in ids.drain(..) {
+            let path = backup_path.join(file_name(priority, id));
+            discarded += recover_segment(&path)?;
+
+            if fs::metadata(&path)?.len() == 0 {
+                warn!("Deleting empty segment {}", file_name(priority, id));
+                fs::remove_file(&path)?;
+                continue;
+            }
+
+            valid.push(id);
+        }
+
+        *ids = valid;
+    }
+
+    Ok(discarded)
+}
+
+/// Deletes temporary files of compressed segments that weren't completely
+/// written before uplink went down
+fn remove_incomplete(path: &Path) -> io::Result<()> {
+    for file in fs::read_dir(path)? {
+        let path = file?.path();
+        if path.is_file() && path.extension() == Some(OsStr::new("tmp")) {
+            warn!("Deleting incomplete segment {:?}", path);
+            fs::remove_file(path)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Name of the file with given id in backlog of given priority
+fn file_name(priority: u8, id: u64) -> String {
+    format!("backup@{}@{}", priority, id)
+}
+
+/// Converts file path to priority and file id
+fn id(path: &Path) -> io::Result<(u8, u64)> {
+    let invalid = || io::Error::new(ErrorKind::InvalidInput, "Not a backup file");
+    let file_name = path.file_name().and_then(|f| f.to_str()).ok_or_else(invalid)?;
+
+    let mut parts = file_name.split('@');
+    if parts.next() != Some("backup") {
+        return Err(invalid());
+    }
+
+    let priority = parts.next().and_then(|p| p.parse().ok()).ok_or_else(invalid)?;
+    let id = parts.next().and_then(|id| id.parse().ok()).ok_or_else(invalid)?;
+    Ok((priority, id))
+}
+
+/// Ids of segments written before records were framed and segments had
+/// priorities, e.g 3 of backup@3, in the order they were written
+fn legacy_ids(path: &Path) -> io::Result<Vec<u64>> {
+    let mut ids = vec![];
+    for file in fs::read_dir(path)? {
+        let path = file?.path();
+        if path.is_dir() {
+            continue;
+        }
+
+        let file_name = match path.file_name().and_then(|f| f.to_str()) {
+            Some(file_name) => file_name,
+            None => continue,
+        };
+
+        if let Some(id) = file_name.strip_prefix("backup@").and_then(|id| id.parse().ok()) {
+            ids.push(id);
+        }
+    }
+
+    ids.sort_unstable();
+    Ok(ids)
+}
+
+/// Gets list of file ids in the disk, for every priority. Id of file
+/// backup@2@10 is 10 in the backlog of priority 2. Storing ids instead
+/// of full paths enables efficient indexing
+fn get_file_ids(path: &Path) -> io::Result<BTreeMap<u8, Vec<u64>>> {
+    let mut file_ids: BTreeMap<u8, Vec<u64>> = BTreeMap::new();
+    let files = fs::read_dir(path)?;
+    for file in files {
+        let path = file?.path();
+
+        // ignore directories
+        if path.is_dir() {
+            continue;
+        }
+
+        match id(&path) {
+            Ok((priority, id)) => file_ids.entry(priority).or_default().push(id),
+            Err(_) => continue,
+        }
+    }
+
+    for ids in file_ids.values_mut() {
+        ids.sort_unstable();
+    }
+
+    Ok(file_ids)
+}
+
+struct NextFile {
+    path: PathBuf,
+    open_path: PathBuf,
+    file: File,
+    deleted: Option<(u8, u64)>,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use mqttbytes::*;
+    use tempdir::TempDir;
+
+    fn write_publish(storage: &mut Storage, publish: &Publish, priority: u8) {
+        let mut buf = BytesMut::new();
+        publish.write(&mut buf).unwrap();
+        storage.write(&buf, priority, None).unwrap();
+    }
+
+    fn init_backup_folders() -> TempDir {
+        let backup = TempDir::new("/tmp/persist").unwrap();
+
+        if !backup.path().is_dir() {
+            panic!("Folder does not exist");
+        }
+
+        backup
+    }
+
+    #[test]
+    fn flush_creates_new_file_after_size_limit() {
+        // 1036 is the size of a publish message with topic = "hello", qos = 1, payload = 1024 bytes
+        let backup = init_backup_folders();
+        let mut storage = Storage::new(backup.path(), 10 * 1036, 10).unwrap();
+
+        // 2 files on disk and a partially filled in memory buffer
+        for _ in 0..101 {
+            let mut publish = Publish::new("hello", QoS::AtLeastOnce, vec![1; 1024]);
+            publish.pkid = 1;
+            write_publish(&mut storage, &publish, 0);
+            storage.flush_on_overflow().unwrap();
+        }
+
+        // 1 message in in memory writer
+        assert_eq!(storage.queues[&0].current_write_file.len(), HEADER_SIZE + 1036);
+
+        // other messages on disk
+        let files = get_file_ids(backup.path()).unwrap();
+        assert_eq!(files[&0], vec![0, 1, 2, 3, 4, 5, 6, 7, 8, 9]);
+    }
+
+    #[test]
+    fn old_file_is_deleted_after_limit() {
+        let backup = init_backup_folders();
+        let mut storage = Storage::new(backup.path(), 10 * 1036, 10).unwrap();
+
+        // 11 files created. 10 on disk
+        for _ in 0..110 {
+            let mut publish = Publish::new("hello", QoS::AtLeastOnce, vec![1; 1024]);
+            publish.pkid = 1;
+            write_publish(&mut storage, &publish, 0);
+            storage.flush_on_overflow().unwrap();
+        }
+
+        let files = get_file_ids(backup.path()).unwrap();
+        assert_eq!(files[&0], vec![1, 2, 3, 4, 5, 6, 7, 8, 9, 10]);
+
+        // 11 files created. 10 on disk
+        for _ in 0..10 {
+            let mut publish = Publish::new("hello", QoS::AtLeastOnce, vec![1; 1024]);
+            publish.pkid = 1;
+            write_publish(&mut storage, &publish, 0);
+            storage.flush_on_overflow().unwrap();
+        }
+
+        assert_eq!(storage.queues[&0].current_write_file.len(), 0);
+        let files = get_file_ids(backup.path()).unwrap();
+        assert_eq!(files[&0], vec![2, 3, 4, 5, 6, 7, 8, 9, 10, 11]);
+    }
+
+    #[test]
+    fn reload_loads_correct_file_into_memory() {
+        let backup = init_backup_folders();
+        let mut storage = Storage::new(backup.path(), 10 * 1036, 10).unwrap();
+
+        // 10 files on disk
+        for i in 0..100 {
+            let mut publish = Publish::new("hello", QoS::AtLeastOnce, vec![i; 1024]);
+            publish.pkid = 1;
+            write_publish(&mut storage, &publish, 0);
+            storage.flush_on_overflow().unwrap();
+        }
+
+        let mut publishes = Vec::new();
+
+        // breaks after 100th iteration due to `reload_on_eof` break
+        for _ in 0..1234 {
+            // Done reading all the pending files
+            if storage.reload_on_eof().unwrap() {
+                break;
+            }
+
+            match read(storage.reader(), 1048).unwrap() {
+                Packet::Publish(publish) => publishes.push(publish),
+                packet => unreachable!("{:?}", packet),
+            }
+        }
+
+        assert_eq!(publishes.len(), 100);
+        for (i, publish) in publishes.iter().enumerate() {
+            assert_eq!(&publish.payload[..], vec![i as u8; 1024].as_slice());
+        }
+    }
+
+    #[test]
+    fn reload_loads_partially_written_write_buffer_correctly() {
+        let backup = init_backup_folders();
+        let mut storage = Storage::new(backup.path(), 10 * 1036, 10).unwrap();
+
+        // 10 files on disk and partially filled current write buffer
+        for i in 0..105 {
+            let mut publish = Publish::new("hello", QoS::AtLeastOnce, vec![i; 1024]);
+            publish.pkid = 1;
+            write_publish(&mut storage, &publish, 0);
+            storage.flush_on_overflow().unwrap();
+        }
+
+        let mut publishes = Vec::new();
+
+        // breaks after 100th iteration due to `reload_on_eof` break
+        for _i in 0..12345 {
+            // Done reading all the pending files
+            if storage.reload_on_eof().unwrap() {
+                break;
+            }
+
+            match read(storage.reader(), 1048).unwrap() {
+                Packet::Publish(publish) => publishes.push(publish),
+                packet => unreachable!("{:?}", packet),
+            }
+        }
+
+        assert_eq!(storage.queues[&0].current_write_file.len(), 0);
+        assert_eq!(publishes.len(), 105);
+        for (i, publish) in publishes.iter().enumerate() {
+            assert_eq!(&publish.payload[..], vec![i as u8; 1024].as_slice());
+        }
+    }
+
+    #[test]
+    fn recovery_truncates_torn_tail() {
+        let backup = init_backup_folders();
+        let mut storage = Storage::new(backup.path(), 10 * 1036, 10).unwrap();
+
+        // 2 files on disk
+        for i in 0..20 {
+            let mut publish = Publish::new("hello", QoS::AtLeastOnce, vec![i; 1024]);
+            publish.pkid = 1;
+            write_publish(&mut storage, &publish, 0);
+            storage.flush_on_overflow().unwrap();
+        }
+
+        // Simulate a power cut half way through writing the last record
+        let path = backup.path().join("backup@0@1");
+        let len = fs::metadata(&path).unwrap().len();
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(len - 500).unwrap();
+
+        let mut storage = Storage::new(backup.path(), 10 * 1036, 10).unwrap();
+        assert_eq!(storage.discarded(), HEADER_SIZE + 1036 - 500);
+        assert_eq!(fs::metadata(&path).unwrap().len(), 9 * (HEADER_SIZE + 1036) as u64);
+
+        let mut publishes = Vec::new();
+        while !storage.reload_on_eof().unwrap() {
+            match read(storage.reader(), 1048).unwrap() {
+                Packet::Publish(publish) => publishes.push(publish),
+                packet => unreachable!("{:?}", packet),
+            }
+        }
+
+        assert_eq!(publishes.len(), 19);
+        for (i, publish) in publishes.iter().enumerate() {
+            assert_eq!(&publish.payload[..], vec![i as u8; 1024].as_slice());
+        }
+    }
+
+    #[test]
+    fn reload_skips_corrupt_records() {
+        let backup = init_backup_folders();
+        let mut storage = Storage::new(backup.path(), 10 * 1036, 10).unwrap();
+
+        // 1 file on disk
+        for i in 0..10 {
+            let mut publish = Publish::new("hello", QoS::AtLeastOnce, vec![i; 1024]);
+            publish.pkid = 1;
+            write_publish(&mut storage, &publish, 0);
+            storage.flush_on_overflow().unwrap();
+        }
+
+        // Flip a byte in the payload of 3rd record
+        let path = backup.path().join("backup@0@0");
+        let mut data = fs::read(&path).unwrap();
+        data[2 * (HEADER_SIZE + 1036) + HEADER_SIZE + 100] ^= 0xFF;
+        fs::write(&path, data).unwrap();
+
+        let mut storage = Storage::new(backup.path(), 10 * 1036, 10).unwrap();
+        assert_eq!(storage.discarded(), HEADER_SIZE + 1036);
+
+        let mut publishes = Vec::new();
+        while !storage.reload_on_eof().unwrap() {
+            match read(storage.reader(), 1048).unwrap() {
+                Packet::Publish(publish) => publishes.push(publish),
+                packet => unreachable!("{:?}", packet),
+            }
+        }
+
+        let payloads: Vec<u8> = publishes.iter().map(|p| p.payload[0]).collect();
+        assert_eq!(payloads, vec![0, 1, 3, 4, 5, 6, 7, 8, 9]);
+    }
+
+    #[test]
+    fn records_larger_than_segment_are_rejected() {
+        let backup = init_backup_folders();
+        let mut storage = Storage::new(backup.path(), 1036, 10).unwrap();
+
+        let error = storage.write(&[0; 1037], 0, None).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidInput);
+        storage.write(&[0; 1036], 0, None).unwrap();
+        storage.flush_all().unwrap();
+
+        assert!(!storage.reload_on_eof().unwrap());
+        assert_eq!(storage.reader().len(), 1036);
+    }
+
+    #[test]
+    fn reload_skips_rest_of_segment_after_oversized_record() {
+        let backup = init_backup_folders();
+        let mut storage = Storage::new(backup.path(), 10 * 1036, 10).unwrap();
+
+        for i in 0..2 {
+            let mut publish = Publish::new("hello", QoS::AtLeastOnce, vec![i; 1024]);
+            publish.pkid = 1;
+            write_publish(&mut storage, &publish, 0);
+
+            // Header of a record larger than a segment, followed by some of its payload
+            if i == 0 {
+                let queue = storage.queues.get_mut(&0).unwrap();
+                queue.current_write_file.put_u32(20 * 1036);
+                queue.current_write_file.put_u64(0);
+                queue.current_write_file.put_u64(0);
+                queue.current_write_file.put_slice(&[0; 100]);
+            }
+        }
+        storage.flush_all().unwrap();
+
+        let mut publishes = Vec::new();
+        while !storage.reload_on_eof().unwrap() {
+            match read(storage.reader(), 1048).unwrap() {
+                Packet::Publish(publish) => publishes.push(publish),
+                packet => unreachable!("{:?}", packet),
+            }
+        }
+
+        assert_eq!(publishes.len(), 1);
+        assert_eq!(&publishes[0].payload[..], vec![0; 1024].as_slice());
+    }
+
+    #[test]
+    fn flush_all_persists_partially_filled_write_buffer() {
+        let backup = init_backup_folders();
+        let mut storage = Storage::new(backup.path(), 10 * 1036, 10).unwrap();
+
+        // 1 file on disk and 5 messages in memory
+        for i in 0..15 {
+            let mut publish = Publish::new("hello", QoS::AtLeastOnce, vec![i; 1024]);
+            publish.pkid = 1;
+            write_publish(&mut storage, &publish, 0);
+            storage.flush_on_overflow().unwrap();
+        }
+
+        storage.flush_all().unwrap();
+        assert_eq!(storage.queues[&0].current_write_file.len(), 0);
+        let files = get_file_ids(backup.path()).unwrap();
+        assert_eq!(files[&0], vec![0, 1]);
+
+        // Nothing to flush
+        storage.flush_all().unwrap();
+        let files = get_file_ids(backup.path()).unwrap();
+        assert_eq!(files[&0], vec![0, 1]);
+    }
+
+    #[test]
+    fn drop_flushes_write_buffer() {
+        let backup = init_backup_folders();
+        let mut storage = Storage::new(backup.path(), 10 * 1036, 10).unwrap();
+
+        for i in 0..5 {
+            let mut publish = Publish::new("hello", QoS::AtLeastOnce, vec![i; 1024]);
+            publish.pkid = 1;
+            write_publish(&mut storage, &publish, 0);
+            storage.flush_on_overflow().unwrap();
+        }
+
+        drop(storage);
+
+        let mut storage = Storage::new(backup.path(), 10 * 1036, 10).unwrap();
+        let mut publishes = Vec::new();
+        while !storage.reload_on_eof().unwrap() {
+            match read(storage.reader(), 1048).unwrap() {
+                Packet::Publish(publish) => publishes.push(publish),
+                packet => unreachable!("{:?}", packet),
+            }
+        }
+
+        assert_eq!(publishes.len(), 5);
+    }
+
+    #[test]
+    fn low_priority_file_is_deleted_first() {
+        let backup = init_backup_folders();
+        let mut storage = Storage::new(backup.path(), 10 * 1036, 10).unwrap();
+
+        // 5 files of high priority and 5 files of low priority
+        for i in 0..100 {
+            let mut publish = Publish::new("hello", QoS::AtLeastOnce, vec![1; 1024]);
+            publish.pkid = 1;
+            write_publish(&mut storage, &publish, if i % 2 == 0 { 1 } else { 0 });
+            storage.flush_on_overflow().unwrap();
+        }
+
+        // 2 more files of high priority push out 2 oldest files of low priority
+        for _ in 0..20 {
+            let mut publish = Publish::new("hello", QoS::AtLeastOnce, vec![1; 1024]);
+            publish.pkid = 1;
+            write_publish(&mut storage, &publish, 1);
+            storage.flush_on_overflow().unwrap();
+        }
+
+        let files = get_file_ids(backup.path()).unwrap();
+        assert_eq!(files[&0], vec![2, 3, 4]);
+        assert_eq!(files[&1], vec![0, 1, 2, 3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn low_priority_data_is_dropped_when_disk_is_full_of_high_priority_data() {
+        let backup = init_backup_folders();
+        let mut storage = Storage::new(backup.path(), 10 * 1036, 10).unwrap();
+
+        // 10 files of high priority
+        for _ in 0..100 {
+            let mut publish = Publish::new("hello", QoS::AtLeastOnce, vec![1; 1024]);
+            publish.pkid = 1;
+            write_publish(&mut storage, &publish, 1);
+            storage.flush_on_overflow().unwrap();
+        }
+
+        let mut publish = Publish::new("hello", QoS::AtLeastOnce, vec![1; 1024]);
+        publish.pkid = 1;
+        for _ in 0..10 {
+            write_publish(&mut storage, &publish, 0);
+        }
+
+        assert_eq!(storage.flush_on_overflow().unwrap(), Some(0));
+        assert_eq!(storage.queues[&0].current_write_file.len(), 0);
+        let files = get_file_ids(backup.path()).unwrap();
+        assert!(!files.contains_key(&0));
+        assert_eq!(files[&1], vec![0, 1, 2, 3, 4, 5, 6, 7, 8, 9]);
+    }
+
+    #[test]
+    fn old_file_is_deleted_when_disk_usage_exceeds_limit() {
+        let backup = init_backup_folders();
+        let mut storage = Storage::new(backup.path(), 10 * 1036, 10).unwrap();
+        storage.set_max_disk_usage(Some(3 * 10 * (HEADER_SIZE + 1036)));
+
+        // 5 files created. Only 3 fit in the budget
+        for _ in 0..50 {
+            let mut publish = Publish::new("hello", QoS::AtLeastOnce, vec![1; 1024]);
+            publish.pkid = 1;
+            write_publish(&mut storage, &publish, 0);
+            storage.flush_on_overflow().unwrap();
+        }
+
+        let files = get_file_ids(backup.path()).unwrap();
+        assert_eq!(files[&0], vec![2, 3, 4]);
+        assert_eq!(storage.disk_usage(), 3 * 10 * (HEADER_SIZE + 1036));
+        assert_eq!(storage.quota_evictions(), 2);
+    }
+
+    #[test]
+    fn unacknowledged_file_is_deleted_after_limit() {
+        let backup = init_backup_folders();
+        let mut storage = Storage::new(backup.path(), 10 * 1036, 3).unwrap();
+
+        // 3 files on disk, all of them read but not acknowledged
+        for i in 0..30 {
+            let mut publish = Publish::new("hello", QoS::AtLeastOnce, vec![i; 1024]);
+            publish.pkid = 1;
+            write_publish(&mut storage, &publish, 0);
+            storage.flush_on_overflow().unwrap();
+        }
+
+        while !storage.reload_on_eof().unwrap() {
+            read(storage.reader(), 1048).unwrap();
+        }
+        assert_eq!(storage.file_count(), 3);
+
+        // New data makes way by deleting the oldest of them
+        for i in 0..10 {
+            let mut publish = Publish::new("hello", QoS::AtLeastOnce, vec![i; 1024]);
+            publish.pkid = 1;
+            write_publish(&mut storage, &publish, 0);
+            storage.flush_on_overflow().unwrap();
+        }
+        assert_eq!(get_file_ids(backup.path()).unwrap()[&0], vec![1, 2, 3]);
+        assert_eq!(storage.file_count(), 3);
+
+        // Acknowledgements of the deleted file are ignored
+        for record in 0..20 {
+            storage.commit(record).unwrap();
+        }
+        assert_eq!(get_file_ids(backup.path()).unwrap()[&0], vec![2, 3]);
 
-    /// Removes a file with provided id
-    fn remove(&self, id: u64) -> io::Result<()> {
-        let path = self.backup_path.join(&format!("backup@{}", id));
-        fs::remove_file(path)?;
-        Ok(())
+        // Progress is persisted against the oldest file left
+        drop(storage);
+        let storage = Storage::new(backup.path(), 10 * 1036, 3).unwrap();
+        assert_eq!(storage.resume, Some((0, 2, 0)));
     }
 
-    /// Initializes read buffer before reading data from the file
-    fn prepare_current_read_buffer(&mut self, file_len: usize) {
-        self.current_read_file.clear();
-        let init = vec![0u8; file_len];
-        self.current_read_file.put_slice(&init);
-    }
+    #[test]
+    fn unacknowledged_file_is_deleted_when_disk_usage_exceeds_limit() {
+        let backup = init_backup_folders();
+        let mut storage = Storage::new(backup.path(), 10 * 1036, 10).unwrap();
+        storage.set_max_disk_usage(Some(2 * 10 * (HEADER_SIZE + 1036)));
 
-    /// Opens file to flush current inmemory write buffer to disk.
-    /// Also handles retention of previous files on disk
-    fn open_next_write_file(&mut self) -> io::Result<NextFile> {
-        let next_file_id = self.backlog_file_ids.last().map_or(0, |id| id + 1);
-        let next_file_path = self.backup_path.join(&format!("backup@{}", next_file_id));
-        let next_file = OpenOptions::new().write(true).create(true).open(&next_file_path)?;
-        self.backlog_file_ids.push(next_file_id);
+        // 2 files fill the budget and are being caught up
+        for i in 0..20 {
+            let mut publish = Publish::new("hello", QoS::AtLeastOnce, vec![i; 1024]);
+            publish.pkid = 1;
+            write_publish(&mut storage, &publish, 0);
+            storage.flush_on_overflow().unwrap();
+        }
 
-        let mut next = NextFile { path: next_file_path, file: next_file, deleted: None };
+        assert!(!storage.reload_on_eof().unwrap());
+        read(storage.reader(), 1048).unwrap();
 
-        let backlog_files_count = self.backlog_file_ids.len();
-        if backlog_files_count > self.max_file_count {
-            // Backlog should always be > 0 given the earliest push. doesn't panic
-            let id = self.backlog_file_ids.remove(0);
-            warn!("file limit reached. deleting backup@{}", id);
-            next.deleted = Some(id);
-            self.remove(id)?;
+        // New data isn't dropped
+        for i in 0..10 {
+            let mut publish = Publish::new("hello", QoS::AtLeastOnce, vec![i; 1024]);
+            publish.pkid = 1;
+            write_publish(&mut storage, &publish, 0);
+            storage.flush_on_overflow().unwrap();
         }
 
-        Ok(next)
+        let files = get_file_ids(backup.path()).unwrap();
+        assert_eq!(files[&0], vec![1, 2]);
+        assert_eq!(storage.quota_evictions(), 1);
     }
 
-    /// Flushes what ever is in current write buffer into a new file on the disk
-    #[inline]
-    fn flush(&mut self) -> io::Result<Option<u64>> {
-        let mut next_file = self.open_next_write_file()?;
-        info!("Flushing data to disk!! {:?}", next_file.path);
-        next_file.file.write_all(&self.current_write_file[..])?;
-        next_file.file.flush()?;
-        self.current_write_file.clear();
-        Ok(next_file.deleted)
-    }
+    #[test]
+    fn oldest_file_of_lowest_priority_is_deleted_on_demand() {
+        let backup = init_backup_folders();
+        let mut storage = Storage::new(backup.path(), 10 * 1036, 10).unwrap();
 
-    /// Checks current write buffer size and flushes it to disk when the size
-    /// exceeds configured size
-    pub fn flush_on_overflow(&mut self) -> io::Result<Option<u64>> {
-        if self.current_write_file.len() >= self.max_file_size {
-            return self.flush();
+        // 2 files of priority 0 and 1 file of priority 1
+        for (priority, count) in [(0, 20), (1, 10)] {
+            for _ in 0..count {
+                let mut publish = Publish::new("hello", QoS::AtLeastOnce, vec![1; 1024]);
+                publish.pkid = 1;
+                write_publish(&mut storage, &publish, priority);
+                storage.flush_on_overflow().unwrap();
+            }
         }
 
-        Ok(None)
+        assert_eq!(storage.file_count(), 3);
+        assert_eq!(storage.delete_oldest().unwrap(), Some((0, 0)));
+        assert_eq!(storage.delete_oldest().unwrap(), Some((0, 1)));
+        assert_eq!(storage.delete_oldest().unwrap(), Some((1, 0)));
+        assert_eq!(storage.delete_oldest().unwrap(), None);
+        assert_eq!(storage.file_count(), 0);
+        assert!(get_file_ids(backup.path()).unwrap().is_empty());
     }
 
-    /// Reloads next buffer even if there is pending data in current buffer
-    pub fn reload(&mut self) -> io::Result<bool> {
-        // Swap read buffer with write buffer to read data in inmemory write
-        // buffer when all the backlog disk files are done
-        if self.backlog_file_ids.is_empty() {
-            mem::swap(&mut self.current_read_file, &mut self.current_write_file);
+    #[test]
+    fn data_is_dropped_when_free_space_is_below_limit() {
+        let backup = init_backup_folders();
+        let mut storage = Storage::new(backup.path(), 10 * 1036, 10).unwrap();
 
-            // If read buffer is 0 after swapping, all the data is caught up
-            return if self.current_read_file.is_empty() { Ok(true) } else { Ok(false) };
+        // 2 files on disk
+        for _ in 0..20 {
+            let mut publish = Publish::new("hello", QoS::AtLeastOnce, vec![1; 1024]);
+            publish.pkid = 1;
+            write_publish(&mut storage, &publish, 0);
+            storage.flush_on_overflow().unwrap();
         }
 
-        // Len always > 0 because of above if. Doesn't panic
-        let id = self.backlog_file_ids.remove(0);
-        let next_file_path = self.backup_path.join("backup@".to_owned() + &id.to_string());
-        let mut file = OpenOptions::new().read(true).open(&next_file_path)?;
-
-        // Load file into memory and delete it
-        let metadata = fs::metadata(&next_file_path)?;
-        self.prepare_current_read_buffer(metadata.len() as usize);
-        file.read_exact(&mut self.current_read_file[..])?;
-        self.remove(id)?;
+        // Filesystem can never have this much free space. All the files
+        // are deleted to make space and then the new file is dropped
+        storage.set_min_free_space(Some(usize::MAX / 2));
+        for _ in 0..10 {
+            let mut publish = Publish::new("hello", QoS::AtLeastOnce, vec![1; 1024]);
+            publish.pkid = 1;
+            write_publish(&mut storage, &publish, 0);
+        }
 
-        Ok(false)
+        assert_eq!(storage.flush_on_overflow().unwrap(), Some(2));
+        assert!(get_file_ids(backup.path()).unwrap().is_empty());
+        assert_eq!(storage.disk_usage(), 0);
+        assert_eq!(storage.quota_evictions(), 3);
     }
 
-    /// Loads head file to current inmemory read buffer. Deletes
-    /// the file after loading. If all the disk data is caught up,
-    /// swaps current write buffer to current read buffer if there
-    /// is pending data in memory write buffer.
-    /// Returns true if all the messages are caught up
-    pub fn reload_on_eof(&mut self) -> io::Result<bool> {
-        // Don't reload if there is data in current read file
-        if self.current_read_file.has_remaining() {
-            return Ok(false);
-        }
+    #[test]
+    fn expired_records_are_dropped_during_reload() {
+        let backup = init_backup_folders();
+        let mut storage = Storage::new(backup.path(), 10 * 1036, 10).unwrap();
 
-        self.reload()
-    }
-}
+        // 2 files on disk, every other record expires soon
+        for i in 0..20 {
+            let mut publish = Publish::new("hello", QoS::AtLeastOnce, vec![i; 1024]);
+            publish.pkid = 1;
+            let mut buf = BytesMut::new();
+            publish.write(&mut buf).unwrap();
+            let max_age = if i % 2 == 0 { Some(Duration::from_millis(1)) } else { None };
+            storage.write(&buf, 0, max_age).unwrap();
+            storage.flush_on_overflow().unwrap();
+        }
 
-/// Converts file path to file id
-fn id(path: &Path) -> io::Result<u64> {
-    if let Some(file_name) = path.file_name() {
-        let file_name = format!("{:?}", file_name);
-        if !file_name.contains("backup@") {
-            return Err(io::Error::new(ErrorKind::InvalidInput, "Not a backup file"));
+        std::thread::sleep(Duration::from_millis(10));
+        let mut publishes = Vec::new();
+        while !storage.reload_on_eof().unwrap() {
+            match read(storage.reader(), 1048).unwrap() {
+                Packet::Publish(publish) => publishes.push(publish),
+                packet => unreachable!("{:?}", packet),
+            }
         }
+
+        let payloads: Vec<u8> = publishes.iter().map(|p| p.payload[0]).collect();
+        assert_eq!(payloads, vec![1, 3, 5, 7, 9, 11, 13, 15, 17, 19]);
+        assert_eq!(storage.expired(), 10 * (HEADER_SIZE + 1036));
     }
 
-    let id: Vec<&str> = path.file_name().unwrap().to_str().unwrap().split('@').collect();
-    let id: u64 = id[1].parse().unwrap();
-    Ok(id)
-}
+    #[test]
+    fn expiry_of_every_record_read_is_handed_out() {
+        let backup = init_backup_folders();
+        let mut storage = Storage::new(backup.path(), 10 * 1036, 10).unwrap();
 
-/// Gets list of file ids in the disk. Id of file backup@10 is 10.
-/// Storing ids instead of full paths enables efficient indexing
-fn get_file_ids(path: &Path) -> io::Result<Vec<u64>> {
-    let mut file_ids = Vec::new();
-    let files = fs::read_dir(path)?;
-    for file in files {
-        let path = file?.path();
+        // 1 file on disk and a partially filled write buffer
+        for i in 0..15 {
+            let mut publish = Publish::new("hello", QoS::AtLeastOnce, vec![i; 1024]);
+            publish.pkid = 1;
+            let mut buf = BytesMut::new();
+            publish.write(&mut buf).unwrap();
+            let max_age = if i % 2 == 0 { Some(Duration::from_secs(60)) } else { None };
+            storage.write(&buf, 0, max_age).unwrap();
+            storage.flush_on_overflow().unwrap();
+        }
 
-        // ignore directories
-        if path.is_dir() {
-            continue;
+        let start = now();
+        let mut expiries = Vec::new();
+        while !storage.reload_on_eof().unwrap() {
+            read(storage.reader(), 1048).unwrap();
+            expiries.push(storage.take_expiry());
         }
 
-        match id(&path) {
-            Ok(id) => file_ids.push(id),
-            Err(_) => continue,
+        assert_eq!(expiries.len(), 15);
+        for (i, expiry) in expiries.into_iter().enumerate() {
+            if i % 2 == 0 {
+                assert!(expiry > start && expiry <= start + 60_000, "{}: {}", i, expiry);
+            } else {
+                assert_eq!(expiry, 0);
+            }
         }
     }
 
-    file_ids.sort_unstable();
-    Ok(file_ids)
-}
+    #[test]
+    fn expired_segments_are_deleted_without_reading() {
+        let backup = init_backup_folders();
+        let mut storage = Storage::new(backup.path(), 10 * 1036, 10).unwrap();
 
-struct NextFile {
-    path: PathBuf,
-    file: File,
-    deleted: Option<u64>,
-}
+        // 2 files on disk and a partially filled write buffer
+        for i in 0..25 {
+            let mut publish = Publish::new("hello", QoS::AtLeastOnce, vec![i; 1024]);
+            publish.pkid = 1;
+            write_publish(&mut storage, &publish, 0);
+            storage.flush_on_overflow().unwrap();
+        }
 
-#[cfg(test)]
-mod test {
-    use super::*;
-    use mqttbytes::*;
-    use tempdir::TempDir;
+        std::thread::sleep(Duration::from_millis(10));
+        storage.set_max_age(Some(Duration::from_millis(5)));
 
-    fn init_backup_folders() -> TempDir {
-        let backup = TempDir::new("/tmp/persist").unwrap();
+        // Only data in memory is left
+        let mut publishes = Vec::new();
+        while !storage.reload_on_eof().unwrap() {
+            match read(storage.reader(), 1048).unwrap() {
+                Packet::Publish(publish) => publishes.push(publish),
+                packet => unreachable!("{:?}", packet),
+            }
+        }
 
-        if !backup.path().is_dir() {
-            panic!("Folder does not exist");
+        assert_eq!(publishes.len(), 5);
+        assert!(get_file_ids(backup.path()).unwrap().is_empty());
+        assert_eq!(storage.expired(), 20 * (HEADER_SIZE + 1036));
+    }
+
+    #[test]
+    fn inspect_lists_segments_and_their_records() {
+        let backup = init_backup_folders();
+        let mut storage = Storage::new(backup.path(), 10 * 1036, 10).unwrap();
+
+        // 1 file of each priority
+        for i in 0..10 {
+            let mut publish = Publish::new("hello", QoS::AtLeastOnce, vec![i; 1024]);
+            publish.pkid = 1;
+            write_publish(&mut storage, &publish, 0);
+            write_publish(&mut storage, &publish, 3);
+            storage.flush_on_overflow().unwrap();
         }
 
-        backup
+        // Flip a byte in the payload of 2nd record of low priority file
+        let path = backup.path().join("backup@0@0");
+        let mut data = fs::read(&path).unwrap();
+        data[HEADER_SIZE + 1036 + HEADER_SIZE + 100] ^= 0xFF;
+        fs::write(&path, data).unwrap();
+
+        let segments = inspect::segments(backup.path()).unwrap();
+        let names: Vec<String> = segments.iter().map(|s| s.name()).collect();
+        assert_eq!(names, vec!["backup@3@0", "backup@0@0"]);
+
+        let records: Vec<inspect::Record> =
+            segments[1].records().unwrap().map(|r| r.unwrap()).collect();
+        let valid: Vec<bool> = records.iter().map(|r| r.valid).collect();
+        assert_eq!(records.len(), 10);
+        assert_eq!(valid.iter().filter(|v| !**v).count(), 1);
+        assert!(!valid[1]);
     }
 
     #[test]
-    fn flush_creates_new_file_after_size_limit() {
-        // 1036 is the size of a publish message with topic = "hello", qos = 1, payload = 1024 bytes
+    fn reload_drains_high_priority_first() {
         let backup = init_backup_folders();
         let mut storage = Storage::new(backup.path(), 10 * 1036, 10).unwrap();
 
-        // 2 files on disk and a partially filled in memory buffer
-        for _ in 0..101 {
-            let mut publish = Publish::new("hello", QoS::AtLeastOnce, vec![1; 1024]);
+        // 2 files of each priority and a partially filled write buffer of each
+        for i in 0..45 {
+            let mut publish = Publish::new("hello", QoS::AtLeastOnce, vec![0; 1024]);
+            publish.pkid = 1;
+            write_publish(&mut storage, &publish, 0);
+            storage.flush_on_overflow().unwrap();
+
+            let mut publish = Publish::new("hello", QoS::AtLeastOnce, vec![i; 1024]);
             publish.pkid = 1;
-            publish.write(storage.writer()).unwrap();
+            write_publish(&mut storage, &publish, 5);
             storage.flush_on_overflow().unwrap();
         }
 
-        // 1 message in in memory writer
-        assert_eq!(storage.writer().len(), 1036);
+        let mut publishes = Vec::new();
+        while !storage.reload_on_eof().unwrap() {
+            match read(storage.reader(), 1048).unwrap() {
+                Packet::Publish(publish) => publishes.push(publish),
+                packet => unreachable!("{:?}", packet),
+            }
+        }
 
-        // other messages on disk
-        let files = get_file_ids(&backup.path()).unwrap();
-        assert_eq!(files, vec![0, 1, 2, 3, 4, 5, 6, 7, 8, 9]);
+        assert_eq!(publishes.len(), 90);
+        let payloads: Vec<u8> = publishes.iter().map(|p| p.payload[0]).collect();
+        let expected: Vec<u8> = (0..45).chain(vec![0; 45]).collect();
+        assert_eq!(payloads, expected);
     }
 
     #[test]
-    fn old_file_is_deleted_after_limit() {
+    fn reload_reads_large_segment_in_chunks() {
+        let backup = init_backup_folders();
+        let mut storage = Storage::new(backup.path(), 3000 * (HEADER_SIZE + 1036), 10).unwrap();
+
+        // 1 file of ~3MB on disk
+        for i in 0..3000 {
+            let mut publish = Publish::new("hello", QoS::AtLeastOnce, vec![(i % 256) as u8; 1024]);
+            publish.pkid = 1;
+            write_publish(&mut storage, &publish, 0);
+            storage.flush_on_overflow().unwrap();
+        }
+
+        let mut publishes = Vec::new();
+        while !storage.reload_on_eof().unwrap() {
+            assert!(storage.reader().len() < READ_CHUNK_SIZE + 1036);
+            match read(storage.reader(), 1048).unwrap() {
+                Packet::Publish(publish) => publishes.push(publish),
+                packet => unreachable!("{:?}", packet),
+            }
+            storage.commit(publishes.len() as u64 - 1).unwrap();
+
+            // Segment is deleted only after it is completely read
+            if publishes.len() < 3000 {
+                assert!(backup.path().join("backup@0@0").exists());
+            }
+        }
+
+        assert!(!backup.path().join("backup@0@0").exists());
+        assert_eq!(publishes.len(), 3000);
+        for (i, publish) in publishes.iter().enumerate() {
+            assert_eq!(&publish.payload[..], vec![(i % 256) as u8; 1024].as_slice());
+        }
+    }
+
+    #[test]
+    fn segment_is_deleted_only_after_all_records_are_committed() {
         let backup = init_backup_folders();
         let mut storage = Storage::new(backup.path(), 10 * 1036, 10).unwrap();
 
-        // 11 files created. 10 on disk
-        for _ in 0..110 {
-            let mut publish = Publish::new("hello", QoS::AtLeastOnce, vec![1; 1024]);
+        // 2 files on disk
+        for i in 0..20 {
+            let mut publish = Publish::new("hello", QoS::AtLeastOnce, vec![i; 1024]);
             publish.pkid = 1;
-            publish.write(storage.writer()).unwrap();
+            write_publish(&mut storage, &publish, 0);
             storage.flush_on_overflow().unwrap();
         }
 
-        let files = get_file_ids(&backup.path()).unwrap();
-        assert_eq!(files, vec![1, 2, 3, 4, 5, 6, 7, 8, 9, 10]);
+        let mut count = 0;
+        while !storage.reload_on_eof().unwrap() {
+            read(storage.reader(), 1048).unwrap();
+            count += 1;
+        }
+
+        // All the records are read, but none are acknowledged
+        assert_eq!(count, 20);
+        assert_eq!(get_file_ids(backup.path()).unwrap()[&0], vec![0, 1]);
 
-        // 11 files created. 10 on disk
+        // New segments don't reuse ids of segments that are still on disk
         for _ in 0..10 {
             let mut publish = Publish::new("hello", QoS::AtLeastOnce, vec![1; 1024]);
             publish.pkid = 1;
-            publish.write(storage.writer()).unwrap();
+            write_publish(&mut storage, &publish, 0);
             storage.flush_on_overflow().unwrap();
         }
+        assert_eq!(get_file_ids(backup.path()).unwrap()[&0], vec![0, 1, 2]);
+
+        for record in 0..10 {
+            storage.commit(record).unwrap();
+        }
+        assert_eq!(get_file_ids(backup.path()).unwrap()[&0], vec![1, 2]);
 
-        assert_eq!(storage.writer().len(), 0);
-        let files = get_file_ids(&backup.path()).unwrap();
-        assert_eq!(files, vec![2, 3, 4, 5, 6, 7, 8, 9, 10, 11]);
+        for record in 10..20 {
+            storage.commit(record).unwrap();
+        }
+        assert_eq!(get_file_ids(backup.path()).unwrap()[&0], vec![2]);
     }
 
     #[test]
-    fn reload_loads_correct_file_into_memory() {
+    fn unacknowledged_records_are_reloaded_after_restart() {
         let backup = init_backup_folders();
         let mut storage = Storage::new(backup.path(), 10 * 1036, 10).unwrap();
 
-        // 10 files on disk
-        for i in 0..100 {
+        // 2 files on disk
+        for i in 0..20 {
             let mut publish = Publish::new("hello", QoS::AtLeastOnce, vec![i; 1024]);
             publish.pkid = 1;
-            publish.write(storage.writer()).unwrap();
+            write_publish(&mut storage, &publish, 0);
             storage.flush_on_overflow().unwrap();
         }
 
-        let mut publishes = Vec::new();
+        // Read everything, but only 5 records are acknowledged before going down
+        while !storage.reload_on_eof().unwrap() {
+            read(storage.reader(), 1048).unwrap();
+        }
 
-        // breaks after 100th iteration due to `reload_on_eof` break
-        for _ in 0..1234 {
-            // Done reading all the pending files
-            if storage.reload_on_eof().unwrap() {
-                break;
-            }
+        for record in 0..5 {
+            storage.commit(record).unwrap();
+        }
+        drop(storage);
 
+        let mut storage = Storage::new(backup.path(), 10 * 1036, 10).unwrap();
+        let mut publishes = Vec::new();
+        while !storage.reload_on_eof().unwrap() {
             match read(storage.reader(), 1048).unwrap() {
                 Packet::Publish(publish) => publishes.push(publish),
                 packet => unreachable!("{:?}", packet),
             }
         }
 
-        assert_eq!(publishes.len(), 100);
-        for (i, publish) in publishes.iter().enumerate() {
-            assert_eq!(&publish.payload[..], vec![i as u8; 1024].as_slice());
+        let payloads: Vec<u8> = publishes.iter().map(|p| p.payload[0]).collect();
+        assert_eq!(payloads, (5..20).collect::<Vec<u8>>());
+    }
+
+    #[test]
+    fn records_are_committed_out_of_order() {
+        let backup = init_backup_folders();
+        let mut storage = Storage::new(backup.path(), 10 * 1036, 10).unwrap();
+
+        // 2 files on disk
+        for i in 0..20 {
+            let mut publish = Publish::new("hello", QoS::AtLeastOnce, vec![i; 1024]);
+            publish.pkid = 1;
+            write_publish(&mut storage, &publish, 0);
+            storage.flush_on_overflow().unwrap();
+        }
+
+        while !storage.reload_on_eof().unwrap() {
+            read(storage.reader(), 1048).unwrap();
+        }
+
+        // Later records of the first segment and the second segment are acknowledged
+        // while the first record is still in flight
+        for record in 1..20 {
+            storage.commit(record).unwrap();
         }
+        assert_eq!(get_file_ids(backup.path()).unwrap()[&0], vec![0, 1]);
+        assert_eq!(storage.file_count(), 2);
+
+        // Repeated acknowledgements don't count towards other records
+        storage.commit(5).unwrap();
+        assert_eq!(get_file_ids(backup.path()).unwrap()[&0], vec![0, 1]);
+
+        storage.commit(0).unwrap();
+        assert!(get_file_ids(backup.path()).unwrap().get(&0).map_or(0, Vec::len) == 0);
+        assert_eq!(storage.file_count(), 0);
     }
 
     #[test]
-    fn reload_loads_partially_written_write_buffer_correctly() {
+    fn id_of_dropped_data_is_not_reused() {
+        let backup = init_backup_folders();
+        let mut storage = Storage::new(backup.path(), 10 * 1036, 1).unwrap();
+
+        for i in 0..10 {
+            let mut publish = Publish::new("hello", QoS::AtLeastOnce, vec![i; 1024]);
+            publish.pkid = 1;
+            write_publish(&mut storage, &publish, 1);
+        }
+        storage.flush_all().unwrap();
+
+        // Disk is full of high priority data
+        for i in 0..10 {
+            let mut publish = Publish::new("hello", QoS::AtLeastOnce, vec![i; 1024]);
+            publish.pkid = 1;
+            write_publish(&mut storage, &publish, 0);
+        }
+        assert_eq!(storage.flush_all().unwrap(), Some(0));
+        assert_eq!(storage.queues[&0].next_id, 1);
+    }
+
+    fn compressed_segments_are_reloaded(compression: Compression) {
         let backup = init_backup_folders();
         let mut storage = Storage::new(backup.path(), 10 * 1036, 10).unwrap();
+        storage.set_compression(compression);
 
         // 10 files on disk and partially filled current write buffer
         for i in 0..105 {
             let mut publish = Publish::new("hello", QoS::AtLeastOnce, vec![i; 1024]);
             publish.pkid = 1;
-            publish.write(storage.writer()).unwrap();
+            write_publish(&mut storage, &publish, 0);
             storage.flush_on_overflow().unwrap();
         }
 
-        let mut publishes = Vec::new();
-
-        // breaks after 100th iteration due to `reload_on_eof` break
-        for _i in 0..12345 {
-            // Done reading all the pending files
-            if storage.reload_on_eof().unwrap() {
-                break;
-            }
+        assert_eq!(storage.flushed_size(), 100 * (HEADER_SIZE + 1036));
+        assert!(storage.flushed_disk_size() < storage.flushed_size() / 10);
 
+        // Compression setting doesn't affect reading
+        let mut storage = Storage::new(backup.path(), 10 * 1036, 10).unwrap();
+        let mut publishes = Vec::new();
+        while !storage.reload_on_eof().unwrap() {
             match read(storage.reader(), 1048).unwrap() {
                 Packet::Publish(publish) => publishes.push(publish),
                 packet => unreachable!("{:?}", packet),
             }
         }
 
-        assert_eq!(storage.current_write_file.len(), 0);
-        assert_eq!(publishes.len(), 105);
+        assert_eq!(publishes.len(), 100);
         for (i, publish) in publishes.iter().enumerate() {
             assert_eq!(&publish.payload[..], vec![i as u8; 1024].as_slice());
         }
     }
+
+    #[test]
+    fn zstd_compressed_segments_are_reloaded() {
+        compressed_segments_are_reloaded(Compression::Zstd);
+    }
+
+    #[test]
+    fn lz4_compressed_segments_are_reloaded() {
+        compressed_segments_are_reloaded(Compression::Lz4);
+    }
+
+    #[test]
+    fn incomplete_compressed_segments_are_deleted() {
+        let backup = init_backup_folders();
+        fs::write(backup.path().join("backup@0@0.tmp"), vec![1; 1024]).unwrap();
+
+        let mut storage = Storage::new(backup.path(), 10 * 1036, 10).unwrap();
+        assert!(!backup.path().join("backup@0@0.tmp").exists());
+        assert!(storage.reload_on_eof().unwrap());
+    }
+
+    #[test]
+    fn unframed_segments_are_migrated() {
+        let backup = init_backup_folders();
+
+        // 2 segments written before records were framed, the last one torn
+        for id in 0..2u8 {
+            let mut segment = BytesMut::new();
+            for i in 0..5 {
+                let mut publish = Publish::new("hello", QoS::AtLeastOnce, vec![id * 5 + i; 1024]);
+                publish.pkid = 1;
+                publish.write(&mut segment).unwrap();
+            }
+
+            fs::write(backup.path().join(format!("backup@{}", id)), &segment).unwrap();
+        }
+        let torn = fs::read(backup.path().join("backup@1")).unwrap();
+        fs::write(backup.path().join("backup@1"), &torn[..torn.len() - 10]).unwrap();
+
+        let mut storage = Storage::new(backup.path(), 10 * 1036, 10).unwrap();
+        let migrated = storage
+            .migrate(|buf| match read(buf, 1048) {
+                Ok(Packet::Publish(publish)) => {
+                    let mut record = BytesMut::new();
+                    publish.write(&mut record).unwrap();
+                    Some(record)
+                }
+                _ => None,
+            })
+            .unwrap();
+
+        assert_eq!(migrated, 2);
+        assert!(!backup.path().join("backup@0").exists());
+        assert!(!backup.path().join("backup@1").exists());
+        assert_eq!(storage.discarded(), 1036 - 10);
+
+        let mut publishes = Vec::new();
+        while !storage.reload_on_eof().unwrap() {
+            match read(storage.reader(), 1048).unwrap() {
+                Packet::Publish(publish) => publishes.push(publish),
+                packet => unreachable!("{:?}", packet),
+            }
+        }
+
+        let payloads: Vec<u8> = publishes.iter().map(|p| p.payload[0]).collect();
+        assert_eq!(payloads, (0..9).collect::<Vec<u8>>());
+    }
 }
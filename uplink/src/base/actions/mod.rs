@@ -14,7 +14,7 @@ pub mod ota;
 mod process;
 pub mod tunshell;
 
-use crate::base::{Buffer, Point, Stream};
+use crate::base::{Point, Stream};
 pub use controller::Controller;
 
 #[derive(Error, Debug)]
@@ -228,17 +228,3 @@ impl Actions {
         }
     }
 }
-
-impl Package for Buffer<ActionResponse> {
-    fn topic(&self) -> Arc<String> {
-        self.topic.clone()
-    }
-
-    fn serialize(&self) -> serde_json::Result<Vec<u8>> {
-        serde_json::to_vec(&self.buffer)
-    }
-
-    fn anomalies(&self) -> Option<(String, usize)> {
-        self.anomalies()
-    }
-}
@@ -193,7 +193,7 @@ impl OtaDownloader {
 
         let mut file_path = ota_path.to_owned();
         let file_name =
-            url.split('/').last().ok_or_else(|| Error::FileNameMissing(url.to_owned()))?;
+            url.split('/').next_back().ok_or_else(|| Error::FileNameMissing(url.to_owned()))?;
         file_path.push(file_name);
         let file_path = file_path.as_path();
         let file = File::create(file_path)?;
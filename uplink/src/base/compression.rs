@@ -0,0 +1,106 @@
+use std::io::{self, Read, Write};
+
+use serde::Deserialize;
+
+/// Algorithm used to compress serialized payloads of a stream before they
+/// are published. Topic of compressed payloads is suffixed with the name of
+/// the algorithm, e.g `/jsonarray/lz4`, so that the backend can decode them
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PayloadCompression {
+    #[default]
+    None,
+    Zstd,
+    Lz4,
+    /// raw deflate stream, without zlib or gzip headers
+    Deflate,
+}
+
+impl PayloadCompression {
+    pub fn name(&self) -> &'static str {
+        match self {
+            PayloadCompression::None => "none",
+            PayloadCompression::Zstd => "zstd",
+            PayloadCompression::Lz4 => "lz4",
+            PayloadCompression::Deflate => "deflate",
+        }
+    }
+
+    /// Topic that payloads of a stream publishing on `topic` are sent to
+    pub fn topic(&self, topic: &str) -> String {
+        match self {
+            PayloadCompression::None => topic.to_owned(),
+            compression => topic.to_owned() + "/" + compression.name(),
+        }
+    }
+
+    pub fn compress(&self, payload: &[u8]) -> io::Result<Vec<u8>> {
+        let compressed = match self {
+            PayloadCompression::None => payload.to_vec(),
+            PayloadCompression::Zstd => zstd::encode_all(payload, 0)?,
+            PayloadCompression::Lz4 => {
+                let mut encoder = lz4_flex::frame::FrameEncoder::new(vec![]);
+                encoder.write_all(payload)?;
+                encoder.finish()?
+            }
+            PayloadCompression::Deflate => {
+                let compression = flate2::Compression::default();
+                let mut encoder = flate2::write::DeflateEncoder::new(vec![], compression);
+                encoder.write_all(payload)?;
+                encoder.finish()?
+            }
+        };
+
+        Ok(compressed)
+    }
+
+    pub fn decompress(&self, compressed: &[u8]) -> io::Result<Vec<u8>> {
+        let mut payload = vec![];
+        match self {
+            PayloadCompression::None => payload.extend_from_slice(compressed),
+            PayloadCompression::Zstd => payload = zstd::decode_all(compressed)?,
+            PayloadCompression::Lz4 => {
+                let mut decoder = lz4_flex::frame::FrameDecoder::new(compressed);
+                decoder.read_to_end(&mut payload)?;
+            }
+            PayloadCompression::Deflate => {
+                let mut decoder = flate2::read::DeflateDecoder::new(compressed);
+                decoder.read_to_end(&mut payload)?;
+            }
+        }
+
+        Ok(payload)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn compressed_payloads_are_decompressed() {
+        let payload = br#"[{"sequence":1,"timestamp":12345,"data":100}]"#.repeat(100);
+        for compression in [
+            PayloadCompression::None,
+            PayloadCompression::Zstd,
+            PayloadCompression::Lz4,
+            PayloadCompression::Deflate,
+        ] {
+            let compressed = compression.compress(&payload).unwrap();
+            if compression != PayloadCompression::None {
+                assert!(compressed.len() < payload.len(), "{:?}", compression);
+            }
+
+            let decompressed = compression.decompress(&compressed).unwrap();
+            assert_eq!(decompressed, payload, "{:?}", compression);
+        }
+    }
+
+    #[test]
+    fn topic_is_suffixed_with_algorithm() {
+        let topic = "/tenants/demo/devices/1/events/can/jsonarray";
+        assert_eq!(PayloadCompression::None.topic(topic), topic);
+        assert_eq!(PayloadCompression::Lz4.topic(topic), format!("{}/lz4", topic));
+        assert_eq!(PayloadCompression::Deflate.topic(topic), format!("{}/deflate", topic));
+    }
+}
@@ -0,0 +1,321 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use flume::Sender;
+use log::error;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use tokio::select;
+use tokio::sync::watch;
+use tokio::time::Duration;
+
+use crate::base::shutdown::Shutdown;
+use crate::base::{Config, Package, Point, Stream};
+
+/// Delays between attempts to reconnect to the broker, growing exponentially
+/// with every failed attempt
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Backoff {
+    /// Seconds to wait before the first attempt
+    pub initial_delay: u64,
+    /// Upper bound of seconds to wait between attempts, before jitter
+    pub max_delay: u64,
+    /// Factor the delay grows by after every failed attempt
+    pub multiplier: f64,
+    /// Upto this fraction of the delay is randomly added to it, so that devices
+    /// which lost the connection together don't all reconnect at once
+    pub jitter: f64,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff { initial_delay: 1, max_delay: 60, multiplier: 2.0, jitter: 0.5 }
+    }
+}
+
+impl Backoff {
+    /// Time to wait after `attempts` failed attempts to connect
+    fn delay(&self, attempts: u32) -> Duration {
+        let exponent = attempts.saturating_sub(1).min(i32::MAX as u32) as i32;
+        let delay = self.initial_delay as f64 * self.multiplier.max(1.0).powi(exponent);
+        let delay = delay.min(self.max_delay as f64);
+        let jitter = self.jitter.clamp(0.0, 1.0) * rand::thread_rng().gen::<f64>();
+
+        Duration::from_secs_f64(delay * (1.0 + jitter))
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ConnectionState {
+    /// Yet to connect for the first time
+    #[default]
+    Connecting,
+    Connected,
+    Disconnected,
+}
+
+/// Status of the connection to the broker, as broadcast to whoever subscribes
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct ConnectionStatus {
+    pub state: ConnectionState,
+    /// Error that the connection, or the last attempt to connect, failed with
+    pub reason: Option<String>,
+    /// Number of failed attempts to connect since the last connection
+    pub attempts: u32,
+    /// Timestamp in milliseconds at which the connection went into this state
+    pub since: u64,
+    /// Broker connected, or being connected, to
+    pub endpoint: String,
+}
+
+/// Tracks the connection of an eventloop to the broker, broadcasting its status
+/// and backing off between attempts to reconnect
+pub struct Connection {
+    backoff: Backoff,
+    status: ConnectionStatus,
+    tx: watch::Sender<ConnectionStatus>,
+}
+
+impl Connection {
+    pub fn new(config: &Config, tx: watch::Sender<ConnectionStatus>) -> Connection {
+        let status = ConnectionStatus { since: timestamp(), ..Default::default() };
+        let connection = Connection { backoff: config.reconnect.clone(), status, tx };
+        connection.broadcast();
+        connection
+    }
+
+    pub fn connected(&mut self) {
+        self.status.state = ConnectionState::Connected;
+        self.status.reason = None;
+        self.status.attempts = 0;
+        self.status.since = timestamp();
+        self.broadcast();
+    }
+
+    /// Broker that the eventloop connects to from now on
+    pub fn set_endpoint(&mut self, endpoint: String) {
+        self.status.endpoint = endpoint;
+        self.broadcast();
+    }
+
+    /// Records a failed connection, or attempt to connect. Returns time to wait
+    /// before attempting to connect again
+    pub fn disconnected(&mut self, reason: String) -> Duration {
+        if self.status.state == ConnectionState::Connected {
+            self.status.since = timestamp();
+        }
+        self.status.state = ConnectionState::Disconnected;
+        self.status.reason = Some(reason);
+        self.status.attempts += 1;
+        self.broadcast();
+
+        self.backoff.delay(self.status.attempts)
+    }
+
+    /// Connection is dropped on purpose, to reconnect with new certificates or to
+    /// another broker. Isn't a failed attempt to connect, nothing to back off from
+    pub fn reconnecting(&mut self, reason: String) {
+        if self.status.state == ConnectionState::Connected {
+            self.status.since = timestamp();
+        }
+        self.status.state = ConnectionState::Disconnected;
+        self.status.reason = Some(reason);
+        self.broadcast();
+    }
+
+    fn broadcast(&self) {
+        // Uplink holds a receiver as long as the eventloop runs
+        let _ = self.tx.send(self.status.clone());
+    }
+}
+
+fn timestamp() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or(Duration::from_secs(0)).as_millis()
+        as u64
+}
+
+/// Connect or disconnect event of the connection to the broker
+#[derive(Debug, Serialize)]
+pub struct ConnectionStat {
+    sequence: u32,
+    timestamp: u64,
+    state: ConnectionState,
+    reason: Option<String>,
+    /// Broker connected, or disconnected, from
+    endpoint: String,
+    /// Number of attempts it took to connect
+    attempts: u32,
+    /// Milliseconds spent in the previous state
+    duration: u64,
+}
+
+impl Point for ConnectionStat {
+    fn sequence(&self) -> u32 {
+        self.sequence
+    }
+
+    fn timestamp(&self) -> u64 {
+        self.timestamp
+    }
+}
+
+/// Publishes connect and disconnect events on the `uplink_connection_stats`
+/// stream, along with the time spent connected or disconnected before them
+pub struct ConnectionStats {
+    status: watch::Receiver<ConnectionStatus>,
+    stream: Stream<ConnectionStat>,
+    sequence: u32,
+    shutdown: Shutdown,
+}
+
+impl ConnectionStats {
+    pub fn new(
+        config: &Config,
+        status: watch::Receiver<ConnectionStatus>,
+        tx: Sender<Box<dyn Package>>,
+        shutdown: Shutdown,
+    ) -> ConnectionStats {
+        let name = "uplink_connection_stats";
+        // Events are rare, every one of them is published right away
+        let mut stream =
+            Stream::dynamic_with_size(name, &config.project_id, &config.device_id, 1, tx);
+        if let Some(stream_config) = config.streams.get(name) {
+            if let Err(e) = stream.configure(stream_config) {
+                error!("Failed to configure {} stream. Error = {}", name, e);
+            }
+        }
+
+        ConnectionStats { status, stream, sequence: 0, shutdown }
+    }
+
+    pub async fn start(mut self) {
+        let mut last = self.status.borrow().clone();
+        loop {
+            select! {
+                changed = self.status.changed() => {
+                    // Eventloop is gone
+                    if changed.is_err() {
+                        return;
+                    }
+
+                    let status = self.status.borrow().clone();
+                    // Failed attempts to reconnect aren't events of their own
+                    if status.state == last.state {
+                        last = status;
+                        continue;
+                    }
+
+                    self.sequence += 1;
+                    let stat = ConnectionStat {
+                        sequence: self.sequence,
+                        timestamp: status.since,
+                        state: status.state,
+                        reason: status.reason.clone(),
+                        endpoint: status.endpoint.clone(),
+                        attempts: last.attempts,
+                        duration: status.since.saturating_sub(last.since),
+                    };
+                    last = status;
+
+                    if let Err(e) = self.stream.fill(stat).await {
+                        error!("Failed to send connection stats. Error = {:?}", e);
+                    }
+                }
+                _ = self.shutdown.recv() => {
+                    if let Err(e) = self.stream.flush().await {
+                        error!("Failed to flush connection stats. Error = {:?}", e);
+                    }
+                    return;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn backoff(jitter: f64) -> Backoff {
+        Backoff { initial_delay: 1, max_delay: 10, multiplier: 2.0, jitter }
+    }
+
+    #[test]
+    fn delay_grows_exponentially_upto_max_delay() {
+        let backoff = backoff(0.0);
+        let delays: Vec<_> = [0, 1, 2, 3, 4, 5, 100, u32::MAX]
+            .iter()
+            .map(|attempts| backoff.delay(*attempts).as_secs())
+            .collect();
+        assert_eq!(delays, [1, 1, 2, 4, 8, 10, 10, 10]);
+
+        // Delays never shrink
+        let backoff = Backoff { multiplier: 0.5, ..backoff };
+        assert_eq!(backoff.delay(5), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn jitter_lengthens_delay_by_upto_its_fraction() {
+        let backoff = backoff(0.5);
+        for _ in 0..100 {
+            let delay = backoff.delay(3);
+            assert!(delay >= Duration::from_secs(4) && delay <= Duration::from_secs(6));
+        }
+
+        // Jitter never takes the delay below what it would be without it
+        let backoff = Backoff { jitter: 2.0, ..backoff };
+        for _ in 0..100 {
+            let delay = backoff.delay(1);
+            assert!(delay >= Duration::from_secs(1) && delay <= Duration::from_secs(2));
+            let delay = backoff.delay(100);
+            assert!(delay >= Duration::from_secs(10) && delay <= Duration::from_secs(20));
+        }
+    }
+
+    #[test]
+    fn failed_attempts_are_counted_till_connected() {
+        let config = Config { reconnect: backoff(0.0), ..Default::default() };
+        let (tx, rx) = watch::channel(ConnectionStatus::default());
+        let mut connection = Connection::new(&config, tx);
+        assert_eq!(rx.borrow().state, ConnectionState::Connecting);
+
+        connection.set_endpoint("localhost:1883".to_owned());
+        assert_eq!(connection.disconnected("refused".to_owned()), Duration::from_secs(1));
+        let since = rx.borrow().since;
+        assert_eq!(connection.disconnected("refused".to_owned()), Duration::from_secs(2));
+
+        let status = rx.borrow().clone();
+        assert_eq!((status.state, status.attempts), (ConnectionState::Disconnected, 2));
+        assert_eq!(
+            (status.reason.as_deref(), status.endpoint.as_str()),
+            (Some("refused"), "localhost:1883")
+        );
+        // Failed attempts to reconnect don't change when the connection was lost
+        assert_eq!(status.since, since);
+
+        connection.connected();
+        let status = rx.borrow().clone();
+        assert_eq!(
+            (status.state, status.attempts, status.reason),
+            (ConnectionState::Connected, 0, None)
+        );
+        assert_eq!(connection.disconnected("reset".to_owned()), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn deliberate_reconnects_are_not_failed_attempts() {
+        let config = Config { reconnect: backoff(0.0), ..Default::default() };
+        let (tx, rx) = watch::channel(ConnectionStatus::default());
+        let mut connection = Connection::new(&config, tx);
+        connection.connected();
+
+        connection.reconnecting("Switching broker".to_owned());
+        let status = rx.borrow().clone();
+        assert_eq!((status.state, status.attempts), (ConnectionState::Disconnected, 0));
+        assert_eq!(status.reason.as_deref(), Some("Switching broker"));
+
+        // Backoff starts afresh if reconnecting fails
+        assert_eq!(connection.disconnected("refused".to_owned()), Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,233 @@
+use std::cmp::Reverse;
+use std::fmt;
+
+use serde::Deserialize;
+use tokio::time::{Duration, Instant};
+
+use crate::base::{Authentication, Config};
+
+/// Broker uplink can connect to, one of many in regional deployments
+#[derive(Debug, Clone, Deserialize)]
+pub struct Endpoint {
+    pub host: String,
+    pub port: u16,
+    /// TLS settings of this broker. Defaults to `authentication` of uplink
+    pub authentication: Option<Authentication>,
+    /// Brokers with a higher priority are preferred. Defaults to 0
+    #[serde(default)]
+    pub priority: u8,
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.host, self.port)
+    }
+}
+
+/// When to give up on a broker for the next one, and to go back to the
+/// preferred one
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Failover {
+    /// Number of failed attempts to connect after which the next broker is tried
+    pub attempts: u32,
+    /// Seconds after which the preferred broker is tried again, while connected
+    /// to another one
+    pub retry_preferred: u64,
+}
+
+impl Default for Failover {
+    fn default() -> Self {
+        Failover { attempts: 3, retry_preferred: 300 }
+    }
+}
+
+impl Config {
+    /// Brokers to connect to, in order of preference. Just `broker` and `port`
+    /// if `brokers` isn't configured
+    pub fn endpoints(&self) -> Vec<Endpoint> {
+        if self.brokers.is_empty() {
+            return vec![Endpoint {
+                host: self.broker.clone(),
+                port: self.port,
+                authentication: self.authentication.clone(),
+                priority: 0,
+            }];
+        }
+
+        let mut endpoints = self.brokers.clone();
+        for endpoint in endpoints.iter_mut() {
+            if endpoint.authentication.is_none() {
+                endpoint.authentication = self.authentication.clone();
+            }
+        }
+        // Brokers of the same priority are tried in the order they are listed
+        endpoints.sort_by_key(|endpoint| Reverse(endpoint.priority));
+        endpoints
+    }
+}
+
+/// Tracks the broker that the eventloop connects to, rotating through brokers
+/// as attempts to connect to them fail
+pub struct Endpoints {
+    endpoints: Vec<Endpoint>,
+    failover: Failover,
+    /// index of the broker currently connected, or being connected, to
+    active: usize,
+    /// failed attempts to connect to the active broker
+    failures: u32,
+    /// instant at which the preferred broker is tried again
+    retry_at: Option<Instant>,
+}
+
+impl Endpoints {
+    pub fn new(config: &Config) -> Endpoints {
+        Endpoints {
+            endpoints: config.endpoints(),
+            failover: config.failover.clone(),
+            active: 0,
+            failures: 0,
+            retry_at: None,
+        }
+    }
+
+    pub fn all(&self) -> &[Endpoint] {
+        &self.endpoints
+    }
+
+    pub fn active(&self) -> &Endpoint {
+        &self.endpoints[self.active]
+    }
+
+    /// Connected to the active broker. Preferred broker is retried after a while,
+    /// if this isn't it
+    pub fn connected(&mut self) {
+        self.failures = 0;
+        self.retry_at = match self.active {
+            0 => None,
+            _ => Some(Instant::now() + Duration::from_secs(self.failover.retry_preferred)),
+        };
+    }
+
+    /// Attempt to connect to the active broker failed. True if the next broker
+    /// is to be tried instead
+    pub fn failed(&mut self) -> bool {
+        self.failures += 1;
+        if self.failures < self.failover.attempts.max(1) || self.endpoints.len() == 1 {
+            return false;
+        }
+
+        self.activate((self.active + 1) % self.endpoints.len());
+        true
+    }
+
+    /// True if the preferred broker is to be tried again, after being connected
+    /// to another one for a while
+    pub fn retry_preferred(&mut self) -> bool {
+        match self.retry_at {
+            Some(retry_at) if retry_at <= Instant::now() => {
+                self.activate(0);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn activate(&mut self, index: usize) {
+        self.active = index;
+        self.failures = 0;
+        self.retry_at = None;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::*;
+
+    fn config(failover: serde_json::Value) -> Config {
+        let brokers = json!([
+            { "host": "backup", "port": 1883 },
+            { "host": "primary", "port": 1883, "priority": 1 },
+            { "host": "fallback", "port": 1883 },
+        ]);
+        let authentication = json!({
+            "ca_certificate": "ca.pem",
+            "device_certificate": "device.pem",
+            "device_private_key": "device.key",
+        });
+
+        Config {
+            brokers: serde_json::from_value(brokers).unwrap(),
+            authentication: Some(serde_json::from_value(authentication).unwrap()),
+            failover: serde_json::from_value(failover).unwrap(),
+            ..Default::default()
+        }
+    }
+
+    fn hosts(endpoints: &Endpoints) -> Vec<&str> {
+        endpoints.all().iter().map(|endpoint| endpoint.host.as_str()).collect()
+    }
+
+    #[test]
+    fn brokers_are_ordered_by_priority() {
+        let endpoints = Endpoints::new(&config(json!({})));
+        // Brokers of the same priority stay in the order they are listed
+        assert_eq!(hosts(&endpoints), ["primary", "backup", "fallback"]);
+        assert_eq!(endpoints.active().to_string(), "primary:1883");
+        assert!(endpoints.all().iter().all(|endpoint| endpoint.authentication.is_some()));
+
+        let config = Config { broker: "localhost".to_owned(), port: 1883, ..Default::default() };
+        assert_eq!(hosts(&Endpoints::new(&config)), ["localhost"]);
+    }
+
+    #[test]
+    fn next_broker_is_tried_after_failed_attempts() {
+        let mut endpoints = Endpoints::new(&config(json!({ "attempts": 2 })));
+        assert!(!endpoints.failed());
+        assert!(endpoints.failed());
+        assert_eq!(endpoints.active().host, "backup");
+
+        // Connecting resets the count
+        assert!(!endpoints.failed());
+        endpoints.connected();
+        assert!(!endpoints.failed());
+        assert!(endpoints.failed());
+        assert_eq!(endpoints.active().host, "fallback");
+
+        assert!(!endpoints.failed());
+        assert!(endpoints.failed());
+        assert_eq!(endpoints.active().host, "primary");
+    }
+
+    #[test]
+    fn single_broker_is_never_failed_over() {
+        let config = Config { broker: "localhost".to_owned(), port: 1883, ..Default::default() };
+        let mut endpoints = Endpoints::new(&config);
+        for _ in 0..10 {
+            assert!(!endpoints.failed());
+        }
+    }
+
+    #[test]
+    fn preferred_broker_is_retried_while_connected_to_another() {
+        let config = config(json!({ "attempts": 1, "retry_preferred": 0 }));
+        let mut endpoints = Endpoints::new(&config);
+        endpoints.connected();
+        assert!(!endpoints.retry_preferred());
+
+        assert!(endpoints.failed());
+        assert!(!endpoints.retry_preferred());
+        endpoints.connected();
+        assert!(endpoints.retry_preferred());
+        assert_eq!(endpoints.active().host, "primary");
+        assert!(!endpoints.retry_preferred());
+
+        let config = Config { failover: Failover { attempts: 1, retry_preferred: 300 }, ..config };
+        let mut endpoints = Endpoints::new(&config);
+        assert!(endpoints.failed());
+        endpoints.connected();
+        assert!(!endpoints.retry_preferred());
+    }
+}
@@ -0,0 +1,401 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+
+use prost::Message;
+use prost_reflect::{DescriptorPool, DynamicMessage, MessageDescriptor, SerializeOptions};
+use prost_types::value::Kind;
+use prost_types::{ListValue, Struct, Value};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::base::StreamConfig;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Io error {0}")]
+    Io(#[from] io::Error),
+    #[error("Json error {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("MessagePack error {0}")]
+    MessagePack(#[from] rmp_serde::encode::Error),
+    #[error("MessagePack decode error {0}")]
+    MessagePackDecode(#[from] rmp_serde::decode::Error),
+    #[error("Cbor error {0}")]
+    Cbor(#[from] serde_cbor::Error),
+    #[error("Protobuf descriptor error {0}")]
+    Descriptor(#[from] prost_reflect::DescriptorError),
+    #[error("Protobuf encode error {0}")]
+    Encode(#[from] prost::EncodeError),
+    #[error("Protobuf decode error {0}")]
+    Decode(#[from] prost::DecodeError),
+    #[error("Protobuf message {0} not found in descriptor")]
+    MissingMessage(String),
+    #[error("Protobuf descriptor of message {0} not configured")]
+    MissingDescriptor(String),
+}
+
+/// Format in which a batch of data points of a stream is serialized
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    /// JSON array of points
+    #[default]
+    Json,
+    /// MessagePack array of points
+    #[serde(rename = "msgpack")]
+    MessagePack,
+    /// CBOR array of points
+    Cbor,
+    /// Points as a `google.protobuf.ListValue` of `Struct`s, or as length
+    /// delimited messages of a compiled descriptor when `message` is given
+    Protobuf,
+}
+
+impl Format {
+    /// Media type of payloads in this format
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Format::Json => "application/json",
+            Format::MessagePack => "application/msgpack",
+            Format::Cbor => "application/cbor",
+            Format::Protobuf => "application/x-protobuf",
+        }
+    }
+
+    /// Last segment of topics of payloads in this format, for the backend to
+    /// know how to decode them
+    pub fn suffix(&self) -> &'static str {
+        match self {
+            Format::Json => "jsonarray",
+            Format::MessagePack => "msgpack",
+            Format::Cbor => "cbor",
+            Format::Protobuf => "protobuf",
+        }
+    }
+
+    /// Topic that payloads in this format of a stream configured to publish on
+    /// `topic` are sent to. Suffix of JSON payloads, `/jsonarray`, is replaced
+    /// with the suffix of this format
+    pub fn topic(&self, topic: &str) -> String {
+        let json = Format::Json.suffix();
+        match topic.strip_suffix(json).filter(|topic| topic.ends_with('/')) {
+            Some(topic) => topic.to_owned() + self.suffix(),
+            None => topic.to_owned(),
+        }
+    }
+}
+
+/// Serializes batches of points of a stream in the configured format
+#[derive(Debug, Clone, Default)]
+pub enum Encoding {
+    #[default]
+    Json,
+    MessagePack,
+    Cbor,
+    ProtobufStruct,
+    Protobuf(MessageDescriptor),
+}
+
+impl Encoding {
+    /// Encoding of a configured stream. Loads protobuf descriptors from disk
+    pub fn new(config: &StreamConfig) -> Result<Encoding, Error> {
+        let encoding = match config.format {
+            Format::Json => Encoding::Json,
+            Format::MessagePack => Encoding::MessagePack,
+            Format::Cbor => Encoding::Cbor,
+            Format::Protobuf => match &config.message {
+                Some(message) => {
+                    let path = match &config.descriptor {
+                        Some(path) => path,
+                        None => return Err(Error::MissingDescriptor(message.to_owned())),
+                    };
+
+                    let pool = DescriptorPool::decode(&fs::read(path)?[..])?;
+                    match pool.get_message_by_name(message) {
+                        Some(descriptor) => Encoding::Protobuf(descriptor),
+                        None => return Err(Error::MissingMessage(message.to_owned())),
+                    }
+                }
+                None => Encoding::ProtobufStruct,
+            },
+        };
+
+        Ok(encoding)
+    }
+
+    pub fn serialize<T: Serialize>(&self, points: &[T]) -> Result<Vec<u8>, Error> {
+        let payload = match self {
+            Encoding::Json => serde_json::to_vec(points)?,
+            Encoding::MessagePack => rmp_serde::to_vec_named(points)?,
+            Encoding::Cbor => serde_cbor::to_vec(&points)?,
+            Encoding::ProtobufStruct => {
+                let values = match serde_json::to_value(points)? {
+                    serde_json::Value::Array(values) => values,
+                    value => vec![value],
+                };
+
+                let values = values.into_iter().map(protobuf_value).collect();
+                ListValue { values }.encode_to_vec()
+            }
+            Encoding::Protobuf(descriptor) => {
+                let mut payload = vec![];
+                for point in points {
+                    let point = serde_json::to_value(point)?;
+                    let message = DynamicMessage::deserialize(descriptor.clone(), point)?;
+                    message.encode_length_delimited(&mut payload)?;
+                }
+
+                payload
+            }
+        };
+
+        Ok(payload)
+    }
+
+    /// Deserializes a batch of points serialized by `serialize`
+    pub fn deserialize<T: DeserializeOwned>(&self, payload: &[u8]) -> Result<Vec<T>, Error> {
+        let points = match self {
+            Encoding::Json => serde_json::from_slice(payload)?,
+            Encoding::MessagePack => rmp_serde::from_slice(payload)?,
+            Encoding::Cbor => serde_cbor::from_slice(payload)?,
+            Encoding::ProtobufStruct => {
+                let values = ListValue::decode(payload)?.values;
+                serde_json::from_value(values.into_iter().map(json_value).collect())?
+            }
+            Encoding::Protobuf(descriptor) => {
+                // Fields as they are named in the descriptor, with every one of them present
+                let options = SerializeOptions::new()
+                    .stringify_64_bit_integers(false)
+                    .use_proto_field_name(true)
+                    .skip_default_fields(false);
+                let mut points = vec![];
+                let mut buf = payload;
+                while !buf.is_empty() {
+                    let mut message = DynamicMessage::new(descriptor.clone());
+                    message.merge_length_delimited(&mut buf)?;
+                    let point =
+                        message.serialize_with_options(serde_json::value::Serializer, &options)?;
+                    points.push(serde_json::from_value(point)?);
+                }
+
+                points
+            }
+        };
+
+        Ok(points)
+    }
+}
+
+/// Converts JSON into its equivalent protobuf `Value`
+fn protobuf_value(value: serde_json::Value) -> Value {
+    let kind = match value {
+        serde_json::Value::Null => Kind::NullValue(0),
+        serde_json::Value::Bool(b) => Kind::BoolValue(b),
+        serde_json::Value::Number(n) => Kind::NumberValue(n.as_f64().unwrap_or_default()),
+        serde_json::Value::String(s) => Kind::StringValue(s),
+        serde_json::Value::Array(values) => {
+            Kind::ListValue(ListValue { values: values.into_iter().map(protobuf_value).collect() })
+        }
+        serde_json::Value::Object(map) => {
+            let fields: BTreeMap<_, _> =
+                map.into_iter().map(|(k, v)| (k, protobuf_value(v))).collect();
+            Kind::StructValue(Struct { fields })
+        }
+    };
+
+    Value { kind: Some(kind) }
+}
+
+/// Converts a protobuf `Value` back into JSON. Whole numbers are integers, as
+/// they were before being converted to protobuf
+fn json_value(value: Value) -> serde_json::Value {
+    match value.kind {
+        None | Some(Kind::NullValue(_)) => serde_json::Value::Null,
+        Some(Kind::BoolValue(b)) => b.into(),
+        Some(Kind::NumberValue(n)) if n.fract() == 0.0 && n.abs() < (1u64 << 53) as f64 => {
+            (n as i64).into()
+        }
+        Some(Kind::NumberValue(n)) => n.into(),
+        Some(Kind::StringValue(s)) => s.into(),
+        Some(Kind::ListValue(list)) => list.values.into_iter().map(json_value).collect(),
+        Some(Kind::StructValue(point)) => {
+            point.fields.into_iter().map(|(k, v)| (k, json_value(v))).collect()
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use prost_types::field_descriptor_proto::Type;
+    use prost_types::{
+        DescriptorProto, FieldDescriptorProto, FileDescriptorProto, FileDescriptorSet,
+    };
+    use serde_json::json;
+    use tempdir::TempDir;
+
+    use super::*;
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    struct Point {
+        sequence: u32,
+        timestamp: u64,
+        value: f64,
+    }
+
+    fn points() -> Vec<Point> {
+        (1..=3)
+            .map(|i| Point { sequence: i, timestamp: 1000 + i as u64, value: i as f64 })
+            .collect()
+    }
+
+    fn config(config: serde_json::Value) -> StreamConfig {
+        let mut value = json!({ "topic": "/test/jsonarray", "buf_size": 10 });
+        value.as_object_mut().unwrap().extend(config.as_object().unwrap().clone());
+        serde_json::from_value(value).unwrap()
+    }
+
+    /// Writes a descriptor set with message `test.Point` and returns its path
+    fn descriptor(dir: &TempDir) -> String {
+        let field = |name: &str, number, r#type: Type| FieldDescriptorProto {
+            name: Some(name.to_owned()),
+            number: Some(number),
+            r#type: Some(r#type as i32),
+            json_name: Some(name.to_owned()),
+            ..Default::default()
+        };
+
+        let message = DescriptorProto {
+            name: Some("Point".to_owned()),
+            field: vec![
+                field("sequence", 1, Type::Uint32),
+                field("timestamp", 2, Type::Uint64),
+                field("value", 3, Type::Double),
+            ],
+            ..Default::default()
+        };
+
+        let file = FileDescriptorProto {
+            name: Some("test.proto".to_owned()),
+            package: Some("test".to_owned()),
+            message_type: vec![message],
+            syntax: Some("proto3".to_owned()),
+            ..Default::default()
+        };
+
+        let path = dir.path().join("test.desc");
+        fs::write(&path, FileDescriptorSet { file: vec![file] }.encode_to_vec()).unwrap();
+        path.to_str().unwrap().to_owned()
+    }
+
+    #[test]
+    fn points_are_serialized_in_configured_format() {
+        let points = points();
+
+        let encoding = Encoding::new(&config(json!({}))).unwrap();
+        let payload = encoding.serialize(&points).unwrap();
+        assert_eq!(serde_json::from_slice::<Vec<Point>>(&payload).unwrap(), points);
+
+        let encoding = Encoding::new(&config(json!({ "format": "msgpack" }))).unwrap();
+        let payload = encoding.serialize(&points).unwrap();
+        assert_eq!(rmp_serde::from_slice::<Vec<Point>>(&payload).unwrap(), points);
+
+        let encoding = Encoding::new(&config(json!({ "format": "cbor" }))).unwrap();
+        let payload = encoding.serialize(&points).unwrap();
+        assert_eq!(serde_cbor::from_slice::<Vec<Point>>(&payload).unwrap(), points);
+    }
+
+    #[test]
+    fn points_are_serialized_as_protobuf_list_of_structs() {
+        let encoding = Encoding::new(&config(json!({ "format": "protobuf" }))).unwrap();
+        let payload = encoding.serialize(&points()).unwrap();
+
+        let list = ListValue::decode(&payload[..]).unwrap();
+        assert_eq!(list.values.len(), 3);
+        let fields = match &list.values[1].kind {
+            Some(Kind::StructValue(point)) => &point.fields,
+            kind => panic!("unexpected value {:?}", kind),
+        };
+        assert_eq!(fields["sequence"].kind, Some(Kind::NumberValue(2.0)));
+        assert_eq!(fields["timestamp"].kind, Some(Kind::NumberValue(1002.0)));
+    }
+
+    #[test]
+    fn points_are_serialized_as_length_delimited_messages() {
+        let dir = TempDir::new("format").unwrap();
+        let descriptor = descriptor(&dir);
+        let config = config(json!({
+            "format": "protobuf",
+            "descriptor": descriptor,
+            "message": "test.Point"
+        }));
+        let encoding = Encoding::new(&config).unwrap();
+        let payload = encoding.serialize(&points()).unwrap();
+
+        let message = match &encoding {
+            Encoding::Protobuf(message) => message.clone(),
+            encoding => panic!("unexpected encoding {:?}", encoding),
+        };
+
+        let mut buf = &payload[..];
+        let mut decoded = vec![];
+        while !buf.is_empty() {
+            let len = prost::decode_length_delimiter(&mut buf).unwrap();
+            let point = DynamicMessage::decode(message.clone(), &buf[..len]).unwrap();
+            buf = &buf[len..];
+
+            let field = |name| point.get_field_by_name(name).unwrap().into_owned();
+            decoded.push(Point {
+                sequence: field("sequence").as_u32().unwrap(),
+                timestamp: field("timestamp").as_u64().unwrap(),
+                value: field("value").as_f64().unwrap(),
+            });
+        }
+        assert_eq!(decoded, points());
+    }
+
+    #[test]
+    fn points_are_deserialized_from_every_format() {
+        let dir = TempDir::new("format").unwrap();
+        let descriptor = descriptor(&dir);
+        let formats = [
+            json!({}),
+            json!({ "format": "msgpack" }),
+            json!({ "format": "cbor" }),
+            json!({ "format": "protobuf" }),
+            json!({ "format": "protobuf", "descriptor": descriptor, "message": "test.Point" }),
+        ];
+
+        for format in formats.iter() {
+            let encoding = Encoding::new(&config(format.clone())).unwrap();
+            let payload = encoding.serialize(&points()).unwrap();
+            let decoded: Vec<Point> = encoding.deserialize(&payload).unwrap();
+            assert_eq!(decoded, points(), "{:?}", format);
+        }
+    }
+
+    #[test]
+    fn missing_message_is_an_error() {
+        let dir = TempDir::new("format").unwrap();
+        let descriptor = descriptor(&dir);
+        let missing = config(json!({
+            "format": "protobuf",
+            "descriptor": descriptor,
+            "message": "test.Missing"
+        }));
+        assert!(matches!(Encoding::new(&missing), Err(Error::MissingMessage(_))));
+
+        let undescribed = config(json!({ "format": "protobuf", "message": "test.Point" }));
+        assert!(matches!(Encoding::new(&undescribed), Err(Error::MissingDescriptor(_))));
+    }
+
+    #[test]
+    fn topic_is_suffixed_with_format() {
+        let topic = "/tenants/demo/devices/1/events/can/jsonarray";
+        assert_eq!(Format::Json.topic(topic), topic);
+        assert_eq!(Format::MessagePack.topic(topic), "/tenants/demo/devices/1/events/can/msgpack");
+        assert_eq!(Format::Protobuf.topic(topic), "/tenants/demo/devices/1/events/can/protobuf");
+        assert_eq!(Format::Cbor.topic("/can/data"), "/can/data");
+        assert_eq!(Format::Cbor.topic("/can/notjsonarray"), "/can/notjsonarray");
+    }
+}
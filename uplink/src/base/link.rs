@@ -0,0 +1,123 @@
+use std::fs;
+
+use log::{error, info};
+use serde::Deserialize;
+use sysinfo::{NetworksExt, System, SystemExt};
+
+use crate::base::Config;
+
+/// Links over which data of a stream is uploaded
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LinkPolicy {
+    /// Uploaded over whichever link is active
+    #[default]
+    Any,
+    /// Held in persistence while on a link that isn't preferred, e.g metered
+    /// LTE, and uploaded once a preferred one is active
+    Bulk,
+}
+
+/// Tracks the interface through which the default route goes, among network
+/// interfaces on the system
+pub struct Links {
+    /// interfaces that bulk data is uploaded over
+    preferred: Vec<String>,
+    sys: System,
+    /// interface of the default route when last checked
+    active: Option<String>,
+}
+
+impl Links {
+    pub fn new(config: &Config) -> Links {
+        Links { preferred: config.preferred_interfaces.clone(), sys: System::new(), active: None }
+    }
+
+    /// Checks if the default route goes through a preferred interface. All
+    /// interfaces are preferred if none are configured
+    pub fn on_preferred(&mut self) -> bool {
+        if self.preferred.is_empty() {
+            return true;
+        }
+
+        let active = self.default_interface();
+        if active != self.active {
+            info!("Default route is through {:?}", active);
+            self.active = active;
+        }
+
+        matches!(&self.active, Some(interface) if self.preferred.contains(interface))
+    }
+
+    /// Interface of the default route with the lowest metric
+    fn default_interface(&mut self) -> Option<String> {
+        let routes = match fs::read_to_string("/proc/net/route") {
+            Ok(routes) => routes,
+            Err(e) => {
+                error!("Failed to read routing table. Error = {:?}", e);
+                return None;
+            }
+        };
+
+        self.sys.refresh_networks_list();
+        let networks = self.sys.networks();
+        let is_present = |interface: &str| networks.iter().any(|(name, _)| name == interface);
+
+        default_route(&routes, is_present).map(ToOwned::to_owned)
+    }
+}
+
+/// Interface of the default route with the lowest metric, among those that are
+/// up and present, in a routing table as laid out in `/proc/net/route`
+fn default_route(routes: &str, is_present: impl Fn(&str) -> bool) -> Option<&str> {
+    // Iface Destination Gateway Flags RefCnt Use Metric Mask MTU Window IRTT
+    routes
+        .lines()
+        .skip(1)
+        .filter_map(|route| {
+            let fields: Vec<&str> = route.split_whitespace().collect();
+            let interface = *fields.first()?;
+            let flags = u16::from_str_radix(fields.get(3)?, 16).ok()?;
+            let metric: u32 = fields.get(6)?.parse().ok()?;
+            let is_default = fields[1] == "00000000" && *fields.get(7)? == "00000000";
+            // RTF_UP
+            let is_up = flags & 0x1 != 0;
+
+            if is_default && is_up && is_present(interface) {
+                Some((metric, interface))
+            } else {
+                None
+            }
+        })
+        .min()
+        .map(|(_, interface)| interface)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const ROUTES: &str = "\
+Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\t\tMTU\tWindow\tIRTT
+wwan0\t00000000\t0100A8C0\t0003\t0\t0\t700\t00000000\t0\t0\t0
+wlan0\t00000000\t0101A8C0\t0003\t0\t0\t600\t00000000\t0\t0\t0
+eth0\t00000000\t0102A8C0\t0002\t0\t0\t100\t00000000\t0\t0\t0
+eth1\t0002A8C0\t00000000\t0001\t0\t0\t0\t00FFFFFF\t0\t0\t0
+";
+
+    #[test]
+    fn default_route_with_lowest_metric_is_picked() {
+        // eth0 is down and eth1 isn't a default route
+        assert_eq!(default_route(ROUTES, |_| true), Some("wlan0"));
+        assert_eq!(default_route(ROUTES, |interface| interface != "wlan0"), Some("wwan0"));
+    }
+
+    #[test]
+    fn missing_or_malformed_routes_are_ignored() {
+        assert_eq!(default_route(ROUTES, |_| false), None);
+        assert_eq!(default_route("", |_| true), None);
+
+        let routes = "Iface Destination\nwlan0 00000000 0101A8C0 zz 0 0 600 00000000\nwwan0";
+        assert_eq!(default_route(routes, |_| true), None);
+    }
+}
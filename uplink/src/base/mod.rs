@@ -2,14 +2,37 @@ use std::collections::HashMap;
 use std::fmt::Debug;
 use std::mem;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+use compression::PayloadCompression;
+use connection::Backoff;
+use disk::Compression;
+use failover::{Endpoint, Failover};
 use flume::{SendError, Sender};
+use format::{Encoding, Format};
+use link::LinkPolicy;
 use log::warn;
-use serde::Deserialize;
+use mqtt::Protocol;
+use presence::PresenceConfig;
+use rumqttc::QoS;
+use serde::{de, Deserialize, Deserializer, Serialize};
+use sink::SinkConfig;
 
 pub mod actions;
+pub mod compression;
+pub mod connection;
+pub mod failover;
+pub mod format;
+pub mod link;
 pub mod mqtt;
+pub mod mqttv5;
+pub mod presence;
 pub mod serializer;
+pub mod shutdown;
+pub mod sink;
+pub mod storages;
+pub mod throttle;
+pub mod tls;
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
@@ -21,6 +44,51 @@ pub enum Error {
 pub struct StreamConfig {
     pub topic: String,
     pub buf_size: usize,
+    /// Persisted data of higher priority streams is sent first and deleted last
+    #[serde(default)]
+    pub priority: u8,
+    /// Persisted data of this stream older than this many seconds is dropped.
+    /// Defaults to `max_age` of persistence
+    pub max_age: Option<u64>,
+    /// Number of persisted publishes of this stream sent in its turn during
+    /// catchup, relative to other streams. Defaults to 1
+    pub weight: Option<usize>,
+    /// Compression of serialized payloads of this stream
+    #[serde(default)]
+    pub compression: PayloadCompression,
+    /// Format data of this stream is serialized in
+    #[serde(default)]
+    pub format: Format,
+    /// Path to a protobuf `FileDescriptorSet` that has `message`
+    pub descriptor: Option<String>,
+    /// Protobuf message every data point of this stream is serialized as
+    pub message: Option<String>,
+    /// Partially filled buffer of this stream is sent after this many seconds
+    pub flush_period: Option<u64>,
+    /// Maximum number of bytes of this stream published per second
+    pub max_bandwidth: Option<usize>,
+    /// Links over which data of this stream is uploaded
+    #[serde(default)]
+    pub link: LinkPolicy,
+    /// QoS of publishes of this stream, 0, 1 or 2. Defaults to 1
+    #[serde(default = "default_qos", deserialize_with = "deserialize_qos")]
+    pub qos: QoS,
+    /// Publishes of this stream are retained by the broker
+    #[serde(default)]
+    pub retain: bool,
+}
+
+fn default_qos() -> QoS {
+    QoS::AtLeastOnce
+}
+
+fn deserialize_qos<'de, D: Deserializer<'de>>(deserializer: D) -> Result<QoS, D::Error> {
+    match u8::deserialize(deserializer)? {
+        0 => Ok(QoS::AtMostOnce),
+        1 => Ok(QoS::AtLeastOnce),
+        2 => Ok(QoS::ExactlyOnce),
+        qos => Err(de::Error::custom(format!("invalid qos {}, expected 0, 1 or 2", qos))),
+    }
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -28,13 +96,24 @@ pub struct Persistence {
     pub path: String,
     pub max_file_size: usize,
     pub max_file_count: usize,
+    #[serde(default)]
+    pub compression: Compression,
+    pub max_disk_usage: Option<usize>,
+    pub min_free_space: Option<usize>,
+    /// Persisted data older than this many seconds is dropped
+    pub max_age: Option<u64>,
 }
 
+/// Certificates and private key of the device, each either inline as PEM or
+/// the path of a file holding it
 #[derive(Debug, Clone, Deserialize)]
 pub struct Authentication {
     ca_certificate: String,
     device_certificate: String,
+    /// RSA, EC or PKCS#8 private key
     device_private_key: String,
+    /// Protocols offered to the broker over ALPN, e.g `["mqtt"]`
+    alpn: Option<Vec<String>>,
 }
 
 #[derive(Debug, Clone, Deserialize, Default)]
@@ -58,7 +137,22 @@ pub struct Config {
     pub broker: String,
     pub port: u16,
     pub authentication: Option<Authentication>,
+    /// Brokers to fail over between, instead of `broker` and `port`
+    #[serde(default)]
+    pub brokers: Vec<Endpoint>,
+    /// When to fail over to the next of `brokers`
+    #[serde(default)]
+    pub failover: Failover,
+    /// Version of MQTT spoken with the broker
+    #[serde(default)]
+    pub protocol: Protocol,
+    /// Backoff between attempts to reconnect to the broker
+    #[serde(default)]
+    pub reconnect: Backoff,
     pub bridge_port: u16,
+    /// Tell apps connected to the bridge about the connection to the broker
+    #[serde(default)]
+    pub bridge_connection_status: bool,
     pub max_packet_size: usize,
     pub max_inflight: u16,
     pub actions: Vec<String>,
@@ -66,6 +160,23 @@ pub struct Config {
     pub streams: HashMap<String, StreamConfig>,
     pub ota: Ota,
     pub stats: Stats,
+    /// Seconds to wait for uplink to stop on shutdown. Defaults to 10
+    pub shutdown_timeout: Option<u64>,
+    /// Maximum number of bytes published per second, across all streams
+    pub max_bandwidth: Option<usize>,
+    /// Network interfaces over which data of bulk streams is uploaded
+    #[serde(default)]
+    pub preferred_interfaces: Vec<String>,
+    /// Local destination that publishes are written to instead of the broker,
+    /// e.g to bench test uplink
+    pub sink: Option<SinkConfig>,
+    /// Birth, last will and graceful disconnect messages
+    #[serde(default)]
+    pub presence: PresenceConfig,
+    /// Hash of the configuration, excluding credentials, announced in presence
+    /// messages. Set by whoever loads the configuration
+    #[serde(skip)]
+    pub config_hash: String,
 }
 
 pub trait Point: Send + Debug {
@@ -75,10 +186,14 @@ pub trait Point: Send + Debug {
 
 pub trait Package: Send + Debug {
     fn topic(&self) -> Arc<String>;
-    // TODO: Implement a generic Return type that can wrap
-    // around custom serialization error types.
-    fn serialize(&self) -> serde_json::Result<Vec<u8>>;
+    /// Name of the stream the package belongs to
+    fn stream(&self) -> Arc<String>;
+    fn serialize(&self) -> Result<Vec<u8>, format::Error>;
     fn anomalies(&self) -> Option<(String, usize)>;
+    /// Number of data points in the package
+    fn point_count(&self) -> usize;
+    fn qos(&self) -> QoS;
+    fn retain(&self) -> bool;
 }
 
 /// Signal to modify the behaviour of collector
@@ -98,6 +213,13 @@ pub struct Stream<T> {
     max_buffer_size: usize,
     buffer: Buffer<T>,
     tx: Sender<Box<dyn Package>>,
+    encoding: Encoding,
+    qos: QoS,
+    retain: bool,
+    /// time after which a partially filled buffer is flushed
+    flush_period: Option<Duration>,
+    /// instant at which the current buffer is due to be flushed
+    deadline: Option<Instant>,
 }
 
 impl<T> Stream<T>
@@ -114,8 +236,53 @@ where
         let name = Arc::new(stream.into());
         let topic = Arc::new(topic.into());
         let buffer = Buffer::new(name.clone(), topic.clone());
+        let encoding = Encoding::default();
+
+        Stream {
+            name,
+            topic,
+            last_sequence: 0,
+            last_timestamp: 0,
+            max_buffer_size,
+            buffer,
+            tx,
+            encoding,
+            qos: QoS::AtLeastOnce,
+            retain: false,
+            flush_period: None,
+            deadline: None,
+        }
+    }
 
-        Stream { name, topic, last_sequence: 0, last_timestamp: 0, max_buffer_size, buffer, tx }
+    /// Creates a stream that is serialized and flushed as configured
+    pub fn with_config<S: Into<String>>(
+        stream: S,
+        config: &StreamConfig,
+        tx: Sender<Box<dyn Package>>,
+    ) -> Result<Stream<T>, format::Error> {
+        let mut stream = Stream::new(stream.into(), config.topic.clone(), config.buf_size, tx);
+        stream.configure(config)?;
+        Ok(stream)
+    }
+
+    /// Serializes, flushes and publishes buffers of this stream as configured,
+    /// on the configured topic
+    pub fn configure(&mut self, config: &StreamConfig) -> Result<(), format::Error> {
+        self.set_encoding(Encoding::new(config)?);
+        self.topic = Arc::new(config.topic.clone());
+        self.buffer.topic = self.topic.clone();
+        self.flush_period = config.flush_period.map(Duration::from_secs);
+        self.qos = config.qos;
+        self.retain = config.retain;
+        self.buffer.qos = config.qos;
+        self.buffer.retain = config.retain;
+        Ok(())
+    }
+
+    /// Sets the format in which buffers of this stream are serialized
+    pub fn set_encoding(&mut self, encoding: Encoding) {
+        self.buffer.encoding = encoding.clone();
+        self.encoding = encoding;
     }
 
     pub fn dynamic_with_size<S: Into<String>>(
@@ -135,7 +302,8 @@ where
             + &device_id
             + "/events/"
             + &stream
-            + "/jsonarray";
+            + "/"
+            + Format::default().suffix();
 
         Stream::new(stream, topic, max_buffer_size, tx)
     }
@@ -153,7 +321,10 @@ where
         let current_sequence = data.sequence();
         let current_timestamp = data.timestamp();
 
-        // Fill buffer with data
+        // Fill buffer with data. Flush period starts with the first point
+        if self.buffer.buffer.is_empty() {
+            self.deadline = self.flush_period.map(|period| Instant::now() + period);
+        }
         self.buffer.buffer.push(data);
 
         // Anomaly detection
@@ -172,15 +343,29 @@ where
 
         // Send buffer to serializer
         if self.buffer.buffer.len() >= self.max_buffer_size {
-            let name = self.name.clone();
-            let topic = self.topic.clone();
-            let buffer = mem::replace(&mut self.buffer, Buffer::new(name, topic));
-            Ok(Some(buffer))
+            Ok(Some(self.take_buffer()))
         } else {
             Ok(None)
         }
     }
 
+    fn take_buffer(&mut self) -> Buffer<T> {
+        let name = self.name.clone();
+        let topic = self.topic.clone();
+        let mut buffer = Buffer::new(name, topic);
+        buffer.encoding = self.encoding.clone();
+        buffer.qos = self.qos;
+        buffer.retain = self.retain;
+        self.deadline = None;
+        mem::replace(&mut self.buffer, buffer)
+    }
+
+    /// Instant at which the partially filled buffer is due to be flushed, if
+    /// the stream has a flush period and data in its buffer
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
     /// Fill buffer with data and trigger async channel send on max_buf_size
     pub async fn fill(&mut self, data: T) -> Result<(), Error> {
         if let Some(buf) = self.add(data)? {
@@ -198,6 +383,26 @@ where
 
         Ok(())
     }
+
+    /// Trigger async channel send of partially filled buffer, if not empty
+    pub async fn flush(&mut self) -> Result<(), Error> {
+        if !self.buffer.buffer.is_empty() {
+            let buf = self.take_buffer();
+            self.tx.send_async(Box::new(buf)).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Trigger sync channel send of partially filled buffer, if not empty
+    pub fn flush_sync(&mut self) -> Result<(), Error> {
+        if !self.buffer.buffer.is_empty() {
+            let buf = self.take_buffer();
+            self.tx.send(Box::new(buf))?;
+        }
+
+        Ok(())
+    }
 }
 
 /// Buffer is an abstraction of a collection that serializer receives.
@@ -211,6 +416,11 @@ pub struct Buffer<T> {
     pub buffer: Vec<T>,
     pub anomalies: String,
     pub anomaly_count: usize,
+    /// format the buffer is serialized in
+    pub encoding: Encoding,
+    /// QoS and retain flag the buffer is published with
+    pub qos: QoS,
+    pub retain: bool,
 }
 
 impl<T> Buffer<T> {
@@ -221,6 +431,9 @@ impl<T> Buffer<T> {
             buffer: vec![],
             anomalies: String::with_capacity(100),
             anomaly_count: 0,
+            encoding: Encoding::default(),
+            qos: QoS::AtLeastOnce,
+            retain: false,
         }
     }
 
@@ -257,16 +470,59 @@ impl<T> Buffer<T> {
     }
 }
 
+impl<T> Package for Buffer<T>
+where
+    T: Point + Serialize,
+{
+    fn topic(&self) -> Arc<String> {
+        self.topic.clone()
+    }
+
+    fn stream(&self) -> Arc<String> {
+        self.stream.clone()
+    }
+
+    fn serialize(&self) -> Result<Vec<u8>, format::Error> {
+        self.encoding.serialize(&self.buffer)
+    }
+
+    fn anomalies(&self) -> Option<(String, usize)> {
+        self.anomalies()
+    }
+
+    fn point_count(&self) -> usize {
+        self.buffer.len()
+    }
+
+    fn qos(&self) -> QoS {
+        self.qos
+    }
+
+    fn retain(&self) -> bool {
+        self.retain
+    }
+}
+
 impl<T> Clone for Stream<T> {
     fn clone(&self) -> Self {
+        let mut buffer = Buffer::new(self.buffer.stream.clone(), self.buffer.topic.clone());
+        buffer.encoding = self.encoding.clone();
+        buffer.qos = self.qos;
+        buffer.retain = self.retain;
+
         Stream {
             name: self.name.clone(),
             topic: self.topic.clone(),
             last_sequence: 0,
             last_timestamp: 0,
             max_buffer_size: self.max_buffer_size,
-            buffer: Buffer::new(self.buffer.stream.clone(), self.buffer.topic.clone()),
+            buffer,
             tx: self.tx.clone(),
+            encoding: self.encoding.clone(),
+            qos: self.qos,
+            retain: self.retain,
+            flush_period: self.flush_period,
+            deadline: None,
         }
     }
 }
@@ -1,5 +1,6 @@
 use flume::{Sender, TrySendError};
 use log::{debug, error, info};
+use serde::Deserialize;
 use thiserror::Error;
 use tokio::task;
 use tokio::time::Duration;
@@ -7,12 +8,18 @@ use tokio::time::Duration;
 use std::fs::File;
 use std::io::Read;
 use std::path::Path;
+use std::{iter, mem};
 
 use crate::base::actions::Action;
-use crate::base::Config;
+use crate::base::connection::Connection;
+use crate::base::failover::{Endpoint, Endpoints};
+use crate::base::format;
+use crate::base::presence::{Birth, Presence};
+use crate::base::tls::{self, KeyKind, Watcher};
+use crate::base::{Authentication, Config};
 use rumqttc::{
-    AsyncClient, Event, EventLoop, Incoming, Key, MqttOptions, Publish, QoS, TlsConfiguration,
-    Transport,
+    AsyncClient, ConnectionError, Event, EventLoop, Incoming, Key, LastWill, MqttOptions, Outgoing,
+    Publish, QoS, Request, TlsConfiguration, Transport,
 };
 use std::sync::Arc;
 
@@ -22,6 +29,30 @@ pub enum Error {
     Serde(#[from] serde_json::Error),
     #[error("Serde error {0}")]
     ActionForward(#[from] TrySendError<Action>),
+    #[error("Tls error {0}")]
+    Tls(#[from] tls::Error),
+    #[error("Format error {0}")]
+    Format(#[from] format::Error),
+}
+
+/// Version of the MQTT protocol spoken with the broker
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    /// MQTT 3.1.1
+    #[default]
+    V4,
+    /// MQTT 5, with topic aliases, message expiry and properties on publishes
+    V5,
+}
+
+/// Progress of publishes through the eventloop, forwarded to serializer
+#[derive(Debug, Clone, Copy)]
+pub enum Ack {
+    /// Publish with given pkid is written to the network
+    Outgoing(u16),
+    /// Publish with given pkid is acknowledged, or completed with QoS 2, by the broker
+    Acked(u16),
 }
 
 /// Interface implementing MQTT protocol to communicate with broker
@@ -32,18 +63,64 @@ pub struct Mqtt {
     eventloop: EventLoop,
     /// Handles to channels between threads
     native_actions_tx: Sender<Action>,
+    /// Forwards publish acknowledgements to serializer
+    acks_tx: Sender<Ack>,
     /// Currently subscribed topic
     actions_subscription: String,
+    config: Arc<Config>,
+    /// Brokers to fail over between, along with their certificates and keys
+    endpoints: Endpoints,
+    /// Watches certificate files, to reconnect with them once they change
+    watcher: Option<Watcher>,
+    /// Eventloop is connected to the broker
+    connected: bool,
+    /// Reason the connection is being dropped, to reconnect with new certificates
+    /// or to another broker
+    reconnecting: Option<String>,
+    /// Broadcasts status of the connection and backs off between reconnects
+    connection: Connection,
+    /// Birth and last will messages, if enabled
+    presence: Option<Presence>,
+    birth: Birth,
 }
 
 impl Mqtt {
-    pub fn new(config: Arc<Config>, actions_tx: Sender<Action>) -> Mqtt {
+    pub fn new(
+        config: Arc<Config>,
+        actions_tx: Sender<Action>,
+        acks_tx: Sender<Ack>,
+        mut connection: Connection,
+    ) -> Result<Mqtt, Error> {
+        // Certificates of every broker are checked upfront, not when failing over
+        let endpoints = Endpoints::new(&config);
+        for endpoint in endpoints.all() {
+            mqttoptions(&config, endpoint)?;
+        }
+
         // create a new eventloop and reuse it during every reconnection
-        let options = mqttoptions(&config);
+        let endpoint = endpoints.active();
+        let options = mqttoptions(&config, endpoint)?;
         let (client, eventloop) = AsyncClient::new(options, 10);
         let actions_subscription =
             format!("/tenants/{}/devices/{}/actions", config.project_id, config.device_id);
-        Mqtt { client, eventloop, native_actions_tx: actions_tx, actions_subscription }
+        let watcher = endpoint.authentication.as_ref().and_then(Watcher::new);
+        connection.set_endpoint(endpoint.to_string());
+
+        Ok(Mqtt {
+            client,
+            eventloop,
+            native_actions_tx: actions_tx,
+            acks_tx,
+            actions_subscription,
+            presence: Presence::new(&config),
+            config,
+            endpoints,
+            watcher,
+            connected: false,
+            reconnecting: None,
+            connection,
+            birth: Birth::default(),
+        })
     }
 
     /// Returns a client handle to MQTT interface
@@ -51,11 +128,25 @@ impl Mqtt {
         self.client.clone()
     }
 
-    /// Poll eventloop to receive packets from broker
+    /// Poll eventloop to receive packets from broker, till the client
+    /// disconnects
     pub async fn start(mut self) {
         loop {
+            if self.watcher.as_mut().is_some_and(Watcher::changed) {
+                self.reload();
+            }
+
+            if self.endpoints.retry_preferred() {
+                self.switch();
+            }
+
             match self.eventloop.poll().await {
                 Ok(Event::Incoming(Incoming::ConnAck(_))) => {
+                    self.connected = true;
+                    self.endpoints.connected();
+                    self.connection.connected();
+                    self.announce();
+
                     let subscription = self.actions_subscription.clone();
                     let client = self.client();
 
@@ -73,17 +164,134 @@ impl Mqtt {
                         error!("Incoming publish handle failed. Error = {:?}", e);
                     }
                 }
+                Ok(Event::Incoming(Incoming::PubAck(ack))) if self.birth.acked(ack.pkid) => {
+                    debug!("Birth message acknowledged");
+                }
+                Ok(Event::Incoming(Incoming::PubComp(comp))) if self.birth.acked(comp.pkid) => {
+                    debug!("Birth message completed");
+                }
+                Ok(Event::Incoming(Incoming::PubAck(ack))) => {
+                    debug!("Incoming = {:?}", ack);
+                    // Serializer is gone only when uplink is shutting down
+                    let _ = self.acks_tx.send_async(Ack::Acked(ack.pkid)).await;
+                }
+                // Publishes with QoS 2 are done once the broker completes them
+                Ok(Event::Incoming(Incoming::PubComp(comp))) => {
+                    debug!("Incoming = {:?}", comp);
+                    let _ = self.acks_tx.send_async(Ack::Acked(comp.pkid)).await;
+                }
+                Ok(Event::Outgoing(Outgoing::Publish(pkid))) if self.birth.outgoing(pkid) => {
+                    info!("Published birth message");
+                }
+                Ok(Event::Outgoing(Outgoing::Publish(pkid))) => {
+                    debug!("Outgoing = Publish({})", pkid);
+                    let _ = self.acks_tx.send_async(Ack::Outgoing(pkid)).await;
+                }
+                Ok(Event::Outgoing(Outgoing::Disconnect)) => {
+                    info!("Disconnected from broker");
+                    return;
+                }
                 Ok(Event::Incoming(i)) => info!("Incoming = {:?}", i),
                 Ok(Event::Outgoing(o)) => debug!("Outgoing = {:?}", o),
+                // Connection was dropped on purpose, eventloop reconnects right away
+                Err(ConnectionError::Cancel) if self.reconnecting.is_some() => {
+                    self.connected = false;
+                    self.birth.reset();
+                    let reason = self.reconnecting.take().unwrap_or_default();
+                    info!("Reconnecting. Reason = {}", reason);
+                    self.connection.reconnecting(reason);
+                }
                 Err(e) => {
+                    self.connected = false;
+                    self.birth.reset();
                     error!("Connection error = {:?}", e.to_string());
-                    tokio::time::sleep(Duration::from_secs(1)).await;
+                    let delay = self.connection.disconnected(e.to_string());
+                    if self.endpoints.failed() {
+                        self.switch();
+                    }
+                    tokio::time::sleep(delay).await;
                     continue;
                 }
             }
         }
     }
 
+    /// Queues the birth message ahead of publishes of the previous connection, that
+    /// are sent again, and of those handed over by serializer. Birth messages of
+    /// previous connections that are yet to be acknowledged are dropped
+    fn announce(&mut self) {
+        let presence = match &self.presence {
+            Some(presence) => presence,
+            None => return,
+        };
+
+        let mut birth = Publish::new(&presence.topic, presence.qos, presence.birth());
+        birth.retain = presence.retain;
+
+        let pending = mem::replace(&mut self.eventloop.pending, Vec::new().into_iter());
+        let pending: Vec<_> = iter::once(Request::Publish(birth))
+            .chain(pending.filter(|request| {
+                !matches!(request, Request::Publish(publish) if publish.topic == presence.topic)
+            }))
+            .collect();
+        self.eventloop.pending = pending.into_iter();
+        self.birth.queued();
+    }
+
+    /// Rebuilds TLS config from certificate files that changed. Eventloop uses it
+    /// for the next connection, forced by disconnecting if currently connected
+    fn reload(&mut self) {
+        let authentication = match &self.endpoints.active().authentication {
+            Some(authentication) => authentication,
+            None => return,
+        };
+
+        let transport = match transport(authentication) {
+            Ok(transport) => transport,
+            Err(e) => {
+                error!("Failed to reload certificates. Error = {:?}", e);
+                return;
+            }
+        };
+
+        info!("Certificates changed, reconnecting");
+        self.eventloop.options.set_transport(transport);
+        self.reconnect("Reconnecting with new certificates");
+    }
+
+    /// Points eventloop to the active broker, reconnecting if currently connected
+    fn switch(&mut self) {
+        let endpoint = self.endpoints.active();
+        let options = match mqttoptions(&self.config, endpoint) {
+            Ok(options) => options,
+            Err(e) => {
+                error!("Failed to switch to broker {}. Error = {:?}", endpoint, e);
+                return;
+            }
+        };
+
+        info!("Switching to broker {}", endpoint);
+        self.watcher = endpoint.authentication.as_ref().and_then(Watcher::new);
+        self.connection.set_endpoint(endpoint.to_string());
+        self.eventloop.options = options;
+        self.reconnect("Switching broker");
+    }
+
+    /// Forces the eventloop to reconnect, if currently connected, by dropping the
+    /// connection. Nothing is sent to the broker, which would otherwise drop the
+    /// last will and only get to it after publishes that are yet to go out
+    fn reconnect(&mut self, reason: &str) {
+        if self.connected && self.reconnecting.is_none() {
+            self.reconnecting = Some(reason.to_owned());
+            let client = self.client();
+            task::spawn(async move {
+                if let Err(e) = client.cancel().await {
+                    error!("Failed to drop connection to reconnect. Error = {:?}", e);
+                }
+            });
+        }
+    }
+
     fn handle_incoming_publish(&mut self, publish: Publish) -> Result<(), Error> {
         if publish.topic != self.actions_subscription {
             error!("Unsolicited publish on {}", publish.topic);
@@ -98,27 +306,38 @@ impl Mqtt {
     }
 }
 
-fn mqttoptions(config: &Config) -> MqttOptions {
+fn mqttoptions(config: &Config, endpoint: &Endpoint) -> Result<MqttOptions, Error> {
     // let (rsa_private, ca) = get_certs(&config.key.unwrap(), &config.ca.unwrap());
-    let mut mqttoptions = MqttOptions::new(&config.device_id, &config.broker, config.port);
+    let mut mqttoptions = MqttOptions::new(&config.device_id, &endpoint.host, endpoint.port);
     mqttoptions.set_max_packet_size(config.max_packet_size, config.max_packet_size);
     mqttoptions.set_keep_alive(Duration::from_secs(60));
     mqttoptions.set_inflight(config.max_inflight);
 
-    if let Some(auth) = config.authentication.clone() {
-        let ca = auth.ca_certificate.into_bytes();
-        let device_certificate = auth.device_certificate.into_bytes();
-        let device_private_key = auth.device_private_key.into_bytes();
-        let transport = Transport::Tls(TlsConfiguration::Simple {
-            ca,
-            alpn: None,
-            client_auth: Some((device_certificate, Key::RSA(device_private_key))),
-        });
-
-        mqttoptions.set_transport(transport);
+    if let Some(auth) = &endpoint.authentication {
+        mqttoptions.set_transport(transport(auth)?);
     }
 
-    mqttoptions
+    if let Some(presence) = Presence::new(config) {
+        let will = LastWill::new(&presence.topic, presence.will(), presence.qos, presence.retain);
+        mqttoptions.set_last_will(will);
+    }
+
+    Ok(mqttoptions)
+}
+
+fn transport(auth: &Authentication) -> Result<Transport, tls::Error> {
+    let credentials = auth.load()?;
+    let key = match credentials.key_kind {
+        KeyKind::Rsa => Key::RSA(credentials.key.clone()),
+        // Read as PKCS#8, which is what EC keys are converted to
+        KeyKind::Ec | KeyKind::Pkcs8 => Key::ECC(credentials.pkcs8_key()?),
+    };
+
+    Ok(Transport::Tls(TlsConfiguration::Simple {
+        ca: credentials.ca,
+        alpn: credentials.alpn,
+        client_auth: Some((credentials.certificate, key)),
+    }))
 }
 
 fn _get_certs(key_path: &Path, ca_path: &Path) -> (Vec<u8>, Vec<u8>) {
@@ -0,0 +1,871 @@
+use bytes::Bytes;
+use flume::Sender;
+use futures_util::future::BoxFuture;
+use futures_util::FutureExt;
+use log::{debug, error, info};
+use rumqttc::{Publish, QoS};
+use rumqttc_v5::v5::mqttbytes::v5::{LastWill, Packet, Publish as PublishV5, PublishProperties};
+use rumqttc_v5::v5::mqttbytes::QoS as QoSV5;
+use rumqttc_v5::v5::{AsyncClient, ClientError, Event, EventLoop, MqttOptions, Request};
+use rumqttc_v5::{Outgoing, TlsConfiguration, Transport};
+use serde::Deserialize;
+use tokio::task;
+use tokio::time::Duration;
+
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::base::actions::Action;
+use crate::base::compression::PayloadCompression;
+use crate::base::connection::Connection;
+use crate::base::failover::{Endpoint, Endpoints};
+use crate::base::format::{self, Encoding};
+use crate::base::mqtt::{Ack, Error};
+use crate::base::presence::{Birth, Presence};
+use crate::base::sink::{self, Sink};
+use crate::base::tls::{self, Watcher};
+use crate::base::{Authentication, Config};
+
+/// Number of actions whose responses are routed to the topic they asked for.
+/// Routes of the oldest actions are dropped beyond this, in case they are never
+/// done being responded to
+const MAX_RESPONSE_ROUTES: usize = 100;
+
+/// Properties of publishes on a topic, as configured for its stream
+#[derive(Debug, Clone)]
+struct TopicProperties {
+    /// seconds after which the broker drops a publish it couldn't deliver yet
+    expiry: Option<u32>,
+    /// content-type and compression of payloads
+    user_properties: Vec<(String, String)>,
+}
+
+impl TopicProperties {
+    fn new(max_age: Option<u64>, content_type: &str, compression: &str) -> TopicProperties {
+        TopicProperties {
+            expiry: max_age.map(|max_age| max_age.min(u32::MAX as u64) as u32),
+            user_properties: vec![
+                ("content-type".to_owned(), content_type.to_owned()),
+                ("compression".to_owned(), compression.to_owned()),
+            ],
+        }
+    }
+}
+
+/// Properties of publishes of every configured stream, by topic
+#[derive(Debug)]
+struct Properties {
+    topics: HashMap<String, TopicProperties>,
+    /// properties of streams that aren't configured
+    default: TopicProperties,
+    /// stream that responses to actions are published on
+    action_status: Option<ActionStatus>,
+}
+
+impl Properties {
+    fn new(config: &Config) -> Result<Properties, format::Error> {
+        let max_age = config.persistence.as_ref().and_then(|p| p.max_age);
+        let topics = config
+            .streams
+            .values()
+            .map(|stream| {
+                let topic = stream.compression.topic(&stream.topic);
+                let properties = TopicProperties::new(
+                    stream.max_age.or(max_age),
+                    stream.format.content_type(),
+                    stream.compression.name(),
+                );
+                (topic, properties)
+            })
+            .collect();
+
+        let default = TopicProperties::new(max_age, "application/json", "none");
+        let action_status = match config.streams.get("action_status") {
+            Some(stream) => Some(ActionStatus {
+                topic: stream.compression.topic(&stream.topic),
+                compression: stream.compression,
+                encoding: Encoding::new(stream)?,
+            }),
+            None => None,
+        };
+
+        Ok(Properties { topics, default, action_status })
+    }
+
+    fn of(&self, topic: &str) -> &TopicProperties {
+        self.topics.get(topic).unwrap_or(&self.default)
+    }
+}
+
+/// Stream of responses to actions, with how its payloads are encoded
+#[derive(Debug)]
+struct ActionStatus {
+    topic: String,
+    compression: PayloadCompression,
+    encoding: Encoding,
+}
+
+impl ActionStatus {
+    /// Responses in a payload of the stream, if it is one
+    fn responses(&self, topic: &str, payload: &[u8]) -> Option<Vec<Response>> {
+        if topic != self.topic {
+            return None;
+        }
+
+        let payload = self.compression.decompress(payload).ok()?;
+        self.encoding.deserialize(&payload).ok()
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Response {
+    id: String,
+    state: String,
+}
+
+/// What the session learns once a publish is handed over to the eventloop
+#[derive(Debug, Default)]
+struct Sent {
+    /// alias that the broker learns the topic of
+    announces: Option<u16>,
+    /// action that is done being responded to
+    finishes: Option<String>,
+}
+
+/// State of the connection shared by the eventloop and clients
+#[derive(Debug, Default)]
+struct Session {
+    /// maximum topic alias the broker accepts, as of the last connection
+    alias_max: u16,
+    /// aliases assigned to topics, from 1 upwards
+    aliases: HashMap<String, u16>,
+    /// aliases that the broker knows the topics of, in the current connection
+    announced: HashSet<u16>,
+    /// response topic and correlation data of actions that asked for them, by
+    /// id, along with the order in which the actions came in
+    responses: HashMap<String, (String, Option<Bytes>, u64)>,
+    /// number of actions that asked for a response topic so far
+    actions: u64,
+}
+
+impl Session {
+    /// Alias of `topic`, if it has one or there are aliases left to assign,
+    /// and if the broker already knows the topic it stands for
+    fn alias(&mut self, topic: &str) -> Option<(u16, bool)> {
+        let alias = match self.aliases.get(topic) {
+            Some(alias) => *alias,
+            None if (self.aliases.len() as u16) < self.alias_max => {
+                let alias = self.aliases.len() as u16 + 1;
+                self.aliases.insert(topic.to_owned(), alias);
+                alias
+            }
+            None => return None,
+        };
+
+        Some((alias, self.announced.contains(&alias)))
+    }
+
+    /// Routes responses to action `id` to `topic`. Route of the oldest action
+    /// is dropped if there are too many of them
+    fn respond_to(&mut self, id: String, topic: String, correlation_data: Option<Bytes>) {
+        if self.responses.len() >= MAX_RESPONSE_ROUTES && !self.responses.contains_key(&id) {
+            let oldest = self.responses.iter().min_by_key(|(_, (_, _, order))| *order);
+            if let Some(oldest) = oldest.map(|(id, _)| id.clone()) {
+                self.responses.remove(&oldest);
+            }
+        }
+
+        self.actions += 1;
+        self.responses.insert(id, (topic, correlation_data, self.actions));
+    }
+
+    /// Response topic and correlation data of the action that `responses` are
+    /// about. Action status is published a response at a time
+    fn route(&self, responses: &[Response]) -> Option<(String, Option<Bytes>, Option<String>)> {
+        let response = responses.first()?;
+        let (topic, correlation_data, _) = self.responses.get(&response.id)?.clone();
+        let done = matches!(response.state.as_str(), "Completed" | "Failed");
+        Some((topic, correlation_data, done.then(|| response.id.clone())))
+    }
+
+    fn sent(&mut self, sent: Sent) {
+        if let Some(alias) = sent.announces {
+            self.announced.insert(alias);
+        }
+
+        if let Some(id) = sent.finishes {
+            self.responses.remove(&id);
+        }
+    }
+}
+
+/// Client handle to the MQTT 5 eventloop, through which serializer publishes
+#[derive(Clone)]
+pub struct Client {
+    client: AsyncClient,
+    session: Arc<Mutex<Session>>,
+    properties: Arc<Properties>,
+}
+
+impl Client {
+    /// Topic and properties of a publish, as configured for its stream. Publishes
+    /// that expire at a set time, e.g those read from persistence, expire in the
+    /// time they have left instead of the stream's max age. Responses to actions
+    /// that asked for it go to their response topic. Topics of other publishes
+    /// are aliased if `alias` is set
+    fn outgoing(
+        &self,
+        session: &mut Session,
+        publish: &Publish,
+        expiry: Option<u64>,
+        alias: bool,
+    ) -> (String, PublishProperties, Sent) {
+        let stream = self.properties.of(&publish.topic);
+        let mut topic = publish.topic.clone();
+        let mut properties = PublishProperties {
+            message_expiry_interval: expiry.map(remaining).or(stream.expiry),
+            user_properties: stream.user_properties.clone(),
+            ..Default::default()
+        };
+        let mut sent = Sent::default();
+
+        let action_status = self.properties.action_status.as_ref();
+        if let Some(responses) = action_status.and_then(|s| s.responses(&topic, &publish.payload)) {
+            if let Some((response_topic, correlation_data, done)) = session.route(&responses) {
+                properties.correlation_data = correlation_data;
+                sent.finishes = done;
+                return (response_topic, properties, sent);
+            }
+        }
+
+        if !alias {
+            return (topic, properties, sent);
+        }
+
+        match session.alias(&publish.topic) {
+            Some((alias, true)) => {
+                topic.clear();
+                properties.topic_alias = Some(alias);
+            }
+            Some((alias, false)) => {
+                properties.topic_alias = Some(alias);
+                sent.announces = Some(alias);
+            }
+            None => (),
+        }
+
+        (topic, properties, sent)
+    }
+
+    /// Hands over a publish, with its topic aliased, if there is space for it
+    /// in the channel
+    fn try_send(&self, publish: Publish, expiry: Option<u64>) -> Result<(), sink::Error> {
+        // Session stays locked till the publish is in the channel, for the eventloop
+        // to find it while restoring aliased topics on a connection error
+        let mut session = self.session.lock().unwrap();
+        let (topic, properties, sent) = self.outgoing(&mut session, &publish, expiry, true);
+        let payload = publish.payload.clone();
+        let qos = qos(publish.qos);
+        match self.client.try_publish_with_properties(
+            topic,
+            qos,
+            publish.retain,
+            payload,
+            properties,
+        ) {
+            Ok(_) => {
+                session.sent(sent);
+                Ok(())
+            }
+            Err(ClientError::TryRequest(_)) => Err(sink::Error::Full(publish)),
+            Err(ClientError::Request(_)) => Err(sink::Error::Closed(publish)),
+        }
+    }
+
+    /// Hands over a publish, waiting for space in the channel if there is none.
+    /// Topic is aliased like that of every other publish when there is space.
+    /// Publishes that wait go with their topic, as the eventloop can't see them
+    /// while restoring aliased topics on a connection error
+    async fn send(&self, publish: Publish, expiry: Option<u64>) -> Result<(), sink::Error> {
+        let publish = match self.try_send(publish, expiry) {
+            Err(sink::Error::Full(publish)) => publish,
+            sent => return sent,
+        };
+
+        let outgoing = self.outgoing(&mut self.session.lock().unwrap(), &publish, expiry, false);
+        let (topic, properties, sent) = outgoing;
+        let payload = publish.payload.clone();
+        let qos = qos(publish.qos);
+        match self
+            .client
+            .publish_with_properties(topic, qos, publish.retain, payload, properties)
+            .await
+        {
+            Ok(_) => {
+                self.session.lock().unwrap().sent(sent);
+                Ok(())
+            }
+            Err(_) => Err(sink::Error::Closed(publish)),
+        }
+    }
+}
+
+/// Seconds left till `expiry`, in milliseconds since unix epoch. Publishes that
+/// are about to expire get a second, as an interval of 0 doesn't expire them
+fn remaining(expiry: u64) -> u32 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let remaining = expiry.saturating_sub(now.as_millis() as u64) / 1000;
+    remaining.clamp(1, u32::MAX as u64) as u32
+}
+
+impl Sink for Client {
+    fn try_publish(&self, publish: Publish) -> Result<(), sink::Error> {
+        self.try_send(publish, None)
+    }
+
+    fn publish(&self, publish: Publish) -> BoxFuture<'_, Result<(), sink::Error>> {
+        self.send(publish, None).boxed()
+    }
+
+    fn publish_expiring(
+        &self,
+        publish: Publish,
+        expiry: u64,
+    ) -> BoxFuture<'_, Result<(), sink::Error>> {
+        self.send(publish, Some(expiry)).boxed()
+    }
+
+    fn disconnect(&self) -> BoxFuture<'_, Result<(), sink::Error>> {
+        async move { self.client.disconnect().await.map_err(|_| sink::Error::Disconnect) }.boxed()
+    }
+}
+
+/// Interface implementing MQTT 5 protocol to communicate with broker
+pub struct MqttV5 {
+    /// Client handle
+    client: AsyncClient,
+    /// Event loop handle
+    eventloop: EventLoop,
+    /// Topic aliases and routes of action responses
+    session: Arc<Mutex<Session>>,
+    properties: Arc<Properties>,
+    /// Handles to channels between threads
+    native_actions_tx: Sender<Action>,
+    /// Forwards publish acknowledgements to serializer
+    acks_tx: Sender<Ack>,
+    /// Currently subscribed topic
+    actions_subscription: String,
+    config: Arc<Config>,
+    /// Brokers to fail over between, along with their certificates and keys
+    endpoints: Endpoints,
+    /// Watches certificate files, to reconnect with them once they change
+    watcher: Option<Watcher>,
+    /// Broadcasts status of the connection and backs off between reconnects
+    connection: Connection,
+    /// Birth and last will messages, if enabled
+    presence: Option<Presence>,
+    birth: Birth,
+}
+
+impl MqttV5 {
+    pub fn new(
+        config: Arc<Config>,
+        actions_tx: Sender<Action>,
+        acks_tx: Sender<Ack>,
+        mut connection: Connection,
+    ) -> Result<MqttV5, Error> {
+        // Certificates of every broker are checked upfront, not when failing over
+        let endpoints = Endpoints::new(&config);
+        for endpoint in endpoints.all() {
+            mqttoptions(&config, endpoint)?;
+        }
+
+        // create a new eventloop and reuse it during every reconnection
+        let endpoint = endpoints.active();
+        let options = mqttoptions(&config, endpoint)?;
+        let (client, eventloop) = AsyncClient::new(options, 10);
+        let actions_subscription =
+            format!("/tenants/{}/devices/{}/actions", config.project_id, config.device_id);
+        let watcher = endpoint.authentication.as_ref().and_then(Watcher::new);
+        connection.set_endpoint(endpoint.to_string());
+
+        Ok(MqttV5 {
+            client,
+            eventloop,
+            session: Arc::new(Mutex::new(Session::default())),
+            properties: Arc::new(Properties::new(&config)?),
+            native_actions_tx: actions_tx,
+            acks_tx,
+            actions_subscription,
+            presence: Presence::new(&config),
+            config,
+            endpoints,
+            watcher,
+            connection,
+            birth: Birth::default(),
+        })
+    }
+
+    /// Returns a client handle to MQTT interface
+    pub fn client(&mut self) -> Client {
+        Client {
+            client: self.client.clone(),
+            session: self.session.clone(),
+            properties: self.properties.clone(),
+        }
+    }
+
+    /// Poll eventloop to receive packets from broker, till the client
+    /// disconnects
+    pub async fn start(mut self) {
+        loop {
+            if self.watcher.as_mut().is_some_and(Watcher::changed) {
+                self.reload();
+            }
+
+            if self.endpoints.retry_preferred() {
+                self.switch();
+                self.connection.reconnecting("Retrying preferred broker".to_owned());
+            }
+
+            match self.eventloop.poll().await {
+                Ok(Event::Incoming(Packet::ConnAck(connack))) => {
+                    let alias_max = connack.properties.and_then(|p| p.topic_alias_max);
+                    self.connected(alias_max.unwrap_or(0));
+                    self.endpoints.connected();
+                    self.connection.connected();
+                    self.announce();
+
+                    let subscription = self.actions_subscription.clone();
+                    let client = self.client.clone();
+
+                    // This can potentially block when client from other threads
+                    // have already filled the channel due to bad network. So we spawn
+                    task::spawn(async move {
+                        match client.subscribe(subscription.clone(), QoSV5::AtLeastOnce).await {
+                            Ok(..) => info!("Subscribe -> {:?}", subscription),
+                            Err(e) => error!("Failed to send subscription. Error = {:?}", e),
+                        }
+                    });
+                }
+                Ok(Event::Incoming(Packet::Publish(p))) => {
+                    if let Err(e) = self.handle_incoming_publish(p) {
+                        error!("Incoming publish handle failed. Error = {:?}", e);
+                    }
+                }
+                Ok(Event::Incoming(Packet::PubAck(ack))) if self.birth.acked(ack.pkid) => {
+                    debug!("Birth message acknowledged");
+                }
+                Ok(Event::Incoming(Packet::PubComp(comp))) if self.birth.acked(comp.pkid) => {
+                    debug!("Birth message completed");
+                }
+                Ok(Event::Incoming(Packet::PubAck(ack))) => {
+                    debug!("Incoming = {:?}", ack);
+                    // Serializer is gone only when uplink is shutting down
+                    let _ = self.acks_tx.send_async(Ack::Acked(ack.pkid)).await;
+                }
+                // Publishes with QoS 2 are done once the broker completes them
+                Ok(Event::Incoming(Packet::PubComp(comp))) => {
+                    debug!("Incoming = {:?}", comp);
+                    let _ = self.acks_tx.send_async(Ack::Acked(comp.pkid)).await;
+                }
+                Ok(Event::Outgoing(Outgoing::Publish(pkid))) if self.birth.outgoing(pkid) => {
+                    info!("Published birth message");
+                }
+                Ok(Event::Outgoing(Outgoing::Publish(pkid))) => {
+                    debug!("Outgoing = Publish({})", pkid);
+                    let _ = self.acks_tx.send_async(Ack::Outgoing(pkid)).await;
+                }
+                Ok(Event::Outgoing(Outgoing::Disconnect)) => {
+                    info!("Disconnected from broker");
+                    return;
+                }
+                Ok(Event::Incoming(i)) => info!("Incoming = {:?}", i),
+                Ok(Event::Outgoing(o)) => debug!("Outgoing = {:?}", o),
+                Err(e) => {
+                    error!("Connection error = {:?}", e.to_string());
+                    self.disconnected();
+                    let delay = self.connection.disconnected(e.to_string());
+                    if self.endpoints.failed() {
+                        self.switch();
+                    }
+                    tokio::time::sleep(delay).await;
+                    continue;
+                }
+            }
+        }
+    }
+
+    /// Publishes that didn't go out over the lost connection are sent again over
+    /// the next one, in which the broker knows none of the aliases. Restores
+    /// their topics
+    fn disconnected(&mut self) {
+        let mut session = self.session.lock().unwrap();
+        // Drops the connection, if it isn't already lost, without a DISCONNECT for the
+        // broker to keep the last will. Collects publishes handed over by clients
+        self.eventloop.clean();
+
+        for request in self.eventloop.pending.iter_mut() {
+            let publish = match request {
+                Request::Publish(publish) if publish.topic.is_empty() => publish,
+                _ => continue,
+            };
+
+            let alias = publish.properties.as_ref().and_then(|p| p.topic_alias);
+            let topic = session.aliases.iter().find(|(_, a)| Some(**a) == alias);
+            if let Some((topic, _)) = topic {
+                publish.topic = Bytes::copy_from_slice(topic.as_bytes());
+            }
+        }
+
+        session.announced.clear();
+        self.birth.reset();
+    }
+
+    /// Rebuilds TLS config from certificate files that changed and reconnects
+    /// with it
+    fn reload(&mut self) {
+        let authentication = match &self.endpoints.active().authentication {
+            Some(authentication) => authentication,
+            None => return,
+        };
+
+        let transport = match transport(authentication) {
+            Ok(transport) => transport,
+            Err(e) => {
+                error!("Failed to reload certificates. Error = {:?}", e);
+                return;
+            }
+        };
+
+        info!("Certificates changed, reconnecting");
+        self.eventloop.options.set_transport(transport);
+        self.disconnected();
+        self.connection.reconnecting("Reconnecting with new certificates".to_owned());
+    }
+
+    /// Points eventloop to the active broker, reconnecting if currently connected
+    fn switch(&mut self) {
+        let endpoint = self.endpoints.active();
+        let options = match mqttoptions(&self.config, endpoint) {
+            Ok(options) => options,
+            Err(e) => {
+                error!("Failed to switch to broker {}. Error = {:?}", endpoint, e);
+                return;
+            }
+        };
+
+        info!("Switching to broker {}", endpoint);
+        self.watcher = endpoint.authentication.as_ref().and_then(Watcher::new);
+        self.connection.set_endpoint(endpoint.to_string());
+        self.eventloop.options = options;
+        self.disconnected();
+    }
+
+    /// Aliases over the maximum of the broker are dropped, also from publishes
+    /// of the previous connection that are yet to be sent again
+    fn connected(&mut self, alias_max: u16) {
+        let mut session = self.session.lock().unwrap();
+        if alias_max < session.alias_max {
+            session.aliases.retain(|_, alias| *alias <= alias_max);
+        }
+        session.alias_max = alias_max;
+
+        for request in self.eventloop.pending.iter_mut() {
+            if let Request::Publish(PublishV5 { properties: Some(properties), .. }) = request {
+                if matches!(properties.topic_alias, Some(alias) if alias > alias_max) {
+                    properties.topic_alias = None;
+                }
+            }
+        }
+    }
+
+    /// Queues the birth message ahead of publishes of the previous connection, that
+    /// are sent again, and of those handed over by clients. Birth messages of
+    /// previous connections that are yet to be acknowledged are dropped
+    fn announce(&mut self) {
+        let presence = match &self.presence {
+            Some(presence) => presence,
+            None => return,
+        };
+
+        let mut birth = PublishV5::new(&presence.topic, qos(presence.qos), presence.birth(), None);
+        birth.retain = presence.retain;
+
+        let topic = presence.topic.as_bytes();
+        self.eventloop.pending.retain(
+            |request| !matches!(request, Request::Publish(publish) if publish.topic == topic),
+        );
+        self.eventloop.pending.push_front(Request::Publish(birth));
+        self.birth.queued();
+    }
+
+    fn handle_incoming_publish(&mut self, publish: PublishV5) -> Result<(), Error> {
+        if publish.topic != self.actions_subscription.as_bytes() {
+            error!("Unsolicited publish on {}", String::from_utf8_lossy(&publish.topic));
+            return Ok(());
+        }
+
+        let action: Action = serde_json::from_slice(&publish.payload)?;
+        debug!("Action = {:?}", action);
+
+        // Responses go to the topic that the one who triggered the action asked for
+        if let Some(properties) = publish.properties {
+            if let Some(topic) = properties.response_topic {
+                let mut session = self.session.lock().unwrap();
+                session.respond_to(action.action_id.clone(), topic, properties.correlation_data);
+            }
+        }
+
+        self.native_actions_tx.try_send(action)?;
+
+        Ok(())
+    }
+}
+
+fn qos(qos: QoS) -> QoSV5 {
+    match qos {
+        QoS::AtMostOnce => QoSV5::AtMostOnce,
+        QoS::AtLeastOnce => QoSV5::AtLeastOnce,
+        QoS::ExactlyOnce => QoSV5::ExactlyOnce,
+    }
+}
+
+fn mqttoptions(config: &Config, endpoint: &Endpoint) -> Result<MqttOptions, Error> {
+    let mut mqttoptions = MqttOptions::new(&config.device_id, &endpoint.host, endpoint.port);
+    mqttoptions.set_max_packet_size(Some(config.max_packet_size as u32));
+    mqttoptions.set_keep_alive(Duration::from_secs(60));
+    mqttoptions.set_outgoing_inflight_upper_limit(config.max_inflight);
+
+    if let Some(auth) = &endpoint.authentication {
+        mqttoptions.set_transport(transport(auth)?);
+    }
+
+    if let Some(presence) = Presence::new(config) {
+        let qos = qos(presence.qos);
+        let will = LastWill::new(&presence.topic, presence.will(), qos, presence.retain, None);
+        mqttoptions.set_last_will(will);
+    }
+
+    Ok(mqttoptions)
+}
+
+/// TLS transport with the device's certificate. Keys of any kind are read as is
+fn transport(auth: &Authentication) -> Result<Transport, tls::Error> {
+    let credentials = auth.load()?;
+    Ok(Transport::Tls(TlsConfiguration::Simple {
+        ca: credentials.ca,
+        alpn: credentials.alpn,
+        client_auth: Some((credentials.certificate, credentials.key)),
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+    use tokio::sync::watch;
+
+    use super::*;
+
+    fn config() -> Config {
+        let streams = json!({
+            "action_status": { "topic": "/action/status", "buf_size": 1 },
+            "can": { "topic": "/can", "buf_size": 1, "max_age": 60 }
+        });
+
+        Config {
+            device_id: "1".to_owned(),
+            broker: "localhost".to_owned(),
+            port: 1,
+            max_packet_size: 1024 * 1024,
+            max_inflight: 10,
+            streams: serde_json::from_value(streams).unwrap(),
+            ..Default::default()
+        }
+    }
+
+    /// Eventloop of a broker that accepts upto 10 aliases
+    fn mqtt(config: Config) -> MqttV5 {
+        let (actions_tx, _) = flume::bounded(1);
+        let (acks_tx, _) = flume::bounded(1);
+        let (status_tx, _) = watch::channel(Default::default());
+        let connection = Connection::new(&config, status_tx);
+        let mut mqtt = MqttV5::new(Arc::new(config), actions_tx, acks_tx, connection).unwrap();
+        mqtt.connected(10);
+        mqtt
+    }
+
+    /// Topic, alias and expiry of publishes handed over to the eventloop
+    fn pending(mqtt: &mut MqttV5) -> Vec<(String, Option<u16>, Option<u32>)> {
+        mqtt.eventloop.clean();
+        mqtt.eventloop
+            .pending
+            .drain(..)
+            .map(|request| match request {
+                Request::Publish(publish) => {
+                    let topic = String::from_utf8(publish.topic.to_vec()).unwrap();
+                    let properties = publish.properties.unwrap_or_default();
+                    (topic, properties.topic_alias, properties.message_expiry_interval)
+                }
+                request => panic!("unexpected request {:?}", request),
+            })
+            .collect()
+    }
+
+    fn publish(topic: &str, payload: &str) -> Publish {
+        Publish::new(topic, QoS::AtLeastOnce, payload.as_bytes().to_vec())
+    }
+
+    #[test]
+    fn aliases_are_assigned_upto_broker_maximum() {
+        let mut session = Session { alias_max: 2, ..Default::default() };
+        assert_eq!(session.alias("/a"), Some((1, false)));
+        assert_eq!(session.alias("/b"), Some((2, false)));
+        assert_eq!(session.alias("/c"), None);
+
+        session.sent(Sent { announces: Some(1), finishes: None });
+        assert_eq!(session.alias("/a"), Some((1, true)));
+        assert_eq!(session.alias("/b"), Some((2, false)));
+    }
+
+    fn response(id: &str, state: &str) -> Vec<Response> {
+        vec![Response { id: id.to_owned(), state: state.to_owned() }]
+    }
+
+    #[test]
+    fn responses_are_routed_to_the_topic_asked_for() {
+        let mut session = Session::default();
+        session.respond_to("1".to_owned(), "/reply".to_owned(), Some(Bytes::from_static(b"42")));
+
+        let (topic, correlation_data, done) = session.route(&response("1", "Running")).unwrap();
+        assert_eq!((topic.as_str(), correlation_data, done), ("/reply", Some("42".into()), None));
+
+        let completed = response("1", "Completed");
+        let (_, _, done) = session.route(&completed).unwrap();
+        assert_eq!(done.as_deref(), Some("1"));
+        session.sent(Sent { announces: None, finishes: done });
+
+        assert!(session.route(&completed).is_none());
+        assert!(session.route(&response("2", "Running")).is_none());
+        assert!(session.route(&[]).is_none());
+    }
+
+    #[test]
+    fn routes_of_oldest_actions_are_dropped() {
+        let mut session = Session::default();
+        for id in 0..MAX_RESPONSE_ROUTES + 10 {
+            session.respond_to(id.to_string(), "/reply".to_owned(), None);
+        }
+
+        assert_eq!(session.responses.len(), MAX_RESPONSE_ROUTES);
+        assert!(session.route(&response("9", "Running")).is_none());
+        assert!(session.route(&response("10", "Running")).is_some());
+    }
+
+    #[tokio::test]
+    async fn topics_are_aliased_on_both_publish_paths() {
+        let mut mqtt = mqtt(config());
+        let client = mqtt.client();
+
+        client.try_publish(publish("/can", "1")).unwrap();
+        client.publish(publish("/can", "2")).await.unwrap();
+        client.try_publish(publish("/other", "3")).unwrap();
+        client.publish(publish("/other", "4")).await.unwrap();
+
+        let expected = [
+            ("/can".to_owned(), Some(1), Some(60)),
+            ("".to_owned(), Some(1), Some(60)),
+            ("/other".to_owned(), Some(2), None),
+            ("".to_owned(), Some(2), None),
+        ];
+        assert_eq!(pending(&mut mqtt), expected);
+    }
+
+    #[tokio::test]
+    async fn publishes_waiting_for_space_keep_their_topic() {
+        let mut mqtt = mqtt(config());
+        let client = mqtt.client();
+
+        client.try_publish(publish("/can", "1")).unwrap();
+        while client.try_publish(publish("/can", "2")).is_ok() {}
+        let waiting = {
+            let client = client.clone();
+            task::spawn(async move { client.publish(publish("/can", "3")).await })
+        };
+
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(pending(&mut mqtt).len(), 10);
+        // Publish that waited is taken off the channel once there is space for it
+        let pending = pending(&mut mqtt);
+        waiting.await.unwrap().unwrap();
+        assert_eq!(pending, [("/can".to_owned(), None, Some(60))]);
+    }
+
+    #[tokio::test]
+    async fn persisted_publishes_expire_in_the_time_they_have_left() {
+        let mut mqtt = mqtt(config());
+        let client = mqtt.client();
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64;
+        client.publish_expiring(publish("/can", "1"), now + 30_500).await.unwrap();
+        client.publish_expiring(publish("/can", "2"), now - 1).await.unwrap();
+
+        let expiries: Vec<_> = pending(&mut mqtt).into_iter().map(|(_, _, e)| e).collect();
+        assert_eq!(expiries, [Some(30), Some(1)]);
+    }
+
+    #[tokio::test]
+    async fn aliased_topics_are_restored_after_a_connection_error() {
+        let mut mqtt = mqtt(config());
+        let client = mqtt.client();
+
+        client.try_publish(publish("/can", "1")).unwrap();
+        client.try_publish(publish("/can", "2")).unwrap();
+        mqtt.disconnected();
+        client.try_publish(publish("/can", "3")).unwrap();
+
+        let expected = [
+            ("/can".to_owned(), Some(1), Some(60)),
+            ("/can".to_owned(), Some(1), Some(60)),
+            ("/can".to_owned(), Some(1), Some(60)),
+        ];
+        assert_eq!(pending(&mut mqtt), expected);
+    }
+
+    #[tokio::test]
+    async fn action_responses_go_to_their_response_topic() {
+        let mut mqtt = mqtt(config());
+        let client = mqtt.client();
+        mqtt.session.lock().unwrap().respond_to("1".to_owned(), "/reply".to_owned(), None);
+
+        let response = r#"[{ "id": "1", "state": "Completed" }]"#;
+        client.try_publish(publish("/action/status", response)).unwrap();
+        client.publish(publish("/action/status", response)).await.unwrap();
+
+        let pending = pending(&mut mqtt);
+        assert_eq!(pending[0].0, "/reply");
+        // Action is done being responded to
+        assert_eq!((pending[1].0.as_str(), pending[1].1), ("/action/status", Some(1)));
+    }
+
+    #[tokio::test]
+    async fn compressed_action_responses_are_routed() {
+        let mut config = config();
+        let stream = config.streams.get_mut("action_status").unwrap();
+        stream.format = format::Format::MessagePack;
+        stream.compression = PayloadCompression::Lz4;
+        let encoding = Encoding::new(stream).unwrap();
+        let mut mqtt = mqtt(config);
+        let client = mqtt.client();
+        mqtt.session.lock().unwrap().respond_to("1".to_owned(), "/reply".to_owned(), None);
+
+        let response = json!([{ "id": "1", "state": "Completed" }]);
+        let payload = encoding.serialize(response.as_array().unwrap()).unwrap();
+        let payload = PayloadCompression::Lz4.compress(&payload).unwrap();
+        client.try_publish(Publish::new("/action/status/lz4", QoS::AtLeastOnce, payload)).unwrap();
+
+        assert_eq!(pending(&mut mqtt)[0].0, "/reply");
+        assert!(mqtt.session.lock().unwrap().responses.is_empty());
+    }
+}
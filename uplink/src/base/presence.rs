@@ -0,0 +1,227 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use rumqttc::QoS;
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::base::{default_qos, deserialize_qos, Config};
+
+/// Messages announcing presence of the device to the backend
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct PresenceConfig {
+    pub enabled: bool,
+    /// Topic that birth, last will and graceful disconnect messages are published
+    /// on. Defaults to `/tenants/{tenant_id}/devices/{device_id}/presence`
+    pub topic: Option<String>,
+    #[serde(deserialize_with = "deserialize_qos")]
+    pub qos: QoS,
+    /// Broker retains the last of these messages, for the backend to learn about
+    /// presence of the device whenever it subscribes
+    pub retain: bool,
+}
+
+impl Default for PresenceConfig {
+    fn default() -> Self {
+        PresenceConfig { enabled: false, topic: None, qos: default_qos(), retain: true }
+    }
+}
+
+/// Birth message published on every connection, last will published by the
+/// broker when the connection is lost and a message published before uplink
+/// disconnects on shutdown, all on the presence topic. These identify the
+/// build of uplink and its configuration, to tell apart data from different ones
+#[derive(Debug, Clone)]
+pub struct Presence {
+    pub topic: String,
+    pub qos: QoS,
+    pub retain: bool,
+    config_hash: String,
+}
+
+impl Presence {
+    /// None if presence messages aren't enabled
+    pub fn new(config: &Config) -> Option<Presence> {
+        let presence = &config.presence;
+        if !presence.enabled {
+            return None;
+        }
+
+        let tenant_id = config.project_id.trim();
+        let device_id = config.device_id.trim();
+        let topic = match &presence.topic {
+            Some(topic) => {
+                topic.replace("{tenant_id}", tenant_id).replace("{device_id}", device_id)
+            }
+            None => format!("/tenants/{}/devices/{}/presence", tenant_id, device_id),
+        };
+
+        Some(Presence {
+            topic,
+            qos: presence.qos,
+            retain: presence.retain,
+            config_hash: config.config_hash.clone(),
+        })
+    }
+
+    /// Announces that uplink is connected
+    pub fn birth(&self) -> Vec<u8> {
+        self.message("online", None, Some(timestamp()))
+    }
+
+    /// Published by the broker once the connection is lost without uplink
+    /// disconnecting, when the time of it isn't known yet
+    pub fn will(&self) -> Vec<u8> {
+        self.message("offline", Some("lost"), None)
+    }
+
+    /// Announces that uplink is disconnecting on shutdown
+    pub fn offline(&self) -> Vec<u8> {
+        self.message("offline", Some("shutdown"), Some(timestamp()))
+    }
+
+    fn message(&self, state: &str, reason: Option<&str>, timestamp: Option<u64>) -> Vec<u8> {
+        let message = json!({
+            "state": state,
+            "reason": reason,
+            "timestamp": timestamp,
+            "version": env!("VERGEN_BUILD_SEMVER"),
+            "commit": env!("VERGEN_GIT_SHA"),
+            "config_hash": self.config_hash,
+        });
+
+        message.to_string().into_bytes()
+    }
+}
+
+fn timestamp() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or(Duration::from_secs(0)).as_millis()
+        as u64
+}
+
+/// Tracks the birth message through the eventloop, so that its events aren't
+/// taken for those of publishes by serializer. Birth message is queued ahead of
+/// everything else on connection, so it is the first publish to go out
+#[derive(Debug, Default)]
+pub struct Birth {
+    /// queued and yet to go out
+    queued: bool,
+    /// pkid it went out with, till the broker acknowledges it
+    pkid: Option<u16>,
+}
+
+impl Birth {
+    pub fn queued(&mut self) {
+        self.queued = true;
+        self.pkid = None;
+    }
+
+    /// True if the outgoing publish is the birth message
+    pub fn outgoing(&mut self, pkid: u16) -> bool {
+        if !self.queued {
+            return false;
+        }
+
+        self.queued = false;
+        // Publishes with QoS 0 aren't acknowledged
+        self.pkid = Some(pkid).filter(|pkid| *pkid != 0);
+        true
+    }
+
+    /// True if the acknowledgement is of the birth message
+    pub fn acked(&mut self, pkid: u16) -> bool {
+        if self.pkid != Some(pkid) {
+            return false;
+        }
+
+        self.pkid = None;
+        true
+    }
+
+    /// Connection is lost, birth message is sent again on the next one
+    pub fn reset(&mut self) {
+        self.queued = false;
+        self.pkid = None;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn presence_topic_is_templated() {
+        let mut config = Config {
+            project_id: "demo".to_owned(),
+            device_id: "1".to_owned(),
+            ..Default::default()
+        };
+        assert!(Presence::new(&config).is_none());
+
+        config.presence.enabled = true;
+        assert_eq!(Presence::new(&config).unwrap().topic, "/tenants/demo/devices/1/presence");
+
+        config.presence.topic = Some("/{tenant_id}/{device_id}/online".to_owned());
+        assert_eq!(Presence::new(&config).unwrap().topic, "/demo/1/online");
+    }
+
+    #[test]
+    fn messages_carry_state_and_reason() {
+        let config = Config {
+            presence: PresenceConfig { enabled: true, ..Default::default() },
+            config_hash: "abc".to_owned(),
+            ..Default::default()
+        };
+        let presence = Presence::new(&config).unwrap();
+        let message =
+            |payload: Vec<u8>| -> serde_json::Value { serde_json::from_slice(&payload).unwrap() };
+
+        let birth = message(presence.birth());
+        assert_eq!((birth["state"].as_str(), birth["reason"].as_str()), (Some("online"), None));
+        assert_eq!(birth["config_hash"], "abc");
+        let will = message(presence.will());
+        assert_eq!(
+            (will["state"].as_str(), will["reason"].as_str()),
+            (Some("offline"), Some("lost"))
+        );
+        // Broker publishes the last will whenever the connection is lost
+        assert!(will["timestamp"].is_null());
+        let offline = message(presence.offline());
+        assert_eq!(offline["reason"], "shutdown");
+        assert!(offline["timestamp"].is_u64());
+    }
+
+    #[test]
+    fn birth_is_told_apart_from_other_publishes() {
+        let mut birth = Birth::default();
+        assert!(!birth.outgoing(1));
+
+        birth.queued();
+        assert!(birth.outgoing(2));
+        assert!(!birth.outgoing(3));
+        assert!(!birth.acked(3));
+        assert!(birth.acked(2));
+        assert!(!birth.acked(2));
+    }
+
+    #[test]
+    fn birth_with_qos_0_is_not_acknowledged() {
+        let mut birth = Birth::default();
+        birth.queued();
+        assert!(birth.outgoing(0));
+        assert!(!birth.acked(0));
+    }
+
+    #[test]
+    fn birth_is_forgotten_once_connection_is_lost() {
+        let mut birth = Birth::default();
+        birth.queued();
+        birth.reset();
+        assert!(!birth.outgoing(1));
+
+        birth.queued();
+        assert!(birth.outgoing(1));
+        birth.reset();
+        assert!(!birth.acked(1));
+    }
+}
@@ -1,15 +1,26 @@
+use crate::base::compression::PayloadCompression;
+use crate::base::connection::{ConnectionState, ConnectionStatus};
+use crate::base::format;
+use crate::base::link::Links;
+use crate::base::mqtt::Ack;
+use crate::base::shutdown::Shutdown;
+use crate::base::sink::{self, Sink};
+use crate::base::storages::{self, Record, Storages};
+use crate::base::throttle::Throttle;
 use crate::base::{Config, Package};
 
-use bytes::Bytes;
 use disk::Storage;
 use flume::{Receiver, RecvError};
+use futures_util::FutureExt;
 use log::{error, info};
 use rumqttc::*;
 use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
 use std::io;
 use std::sync::Arc;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use thiserror::Error;
+use tokio::sync::watch;
 use tokio::{select, time};
 
 #[derive(Error, Debug)]
@@ -18,21 +29,37 @@ pub enum Error {
     Collector(#[from] RecvError),
     #[error("Serde error {0}")]
     Serde(#[from] serde_json::Error),
+    #[error("Serialization error {0}")]
+    Format(#[from] format::Error),
     #[error("Io error {0}")]
     Io(#[from] io::Error),
-    #[error("Mqtt client error {0}")]
-    Client(#[from] ClientError),
+    #[error("Sink error {0}")]
+    Sink(#[from] sink::Error),
     #[error("Packet was not expected {0:?}")]
-    UnexpectedPacket(Packet),
+    UnexpectedPacket(Box<Packet>),
     #[error("Storage is disabled/missing")]
     MissingPersistence,
 }
 
 enum Status {
     Normal,
-    SlowEventloop(Publish),
+    SlowEventloop(Arc<String>, Publish),
     EventLoopReady,
-    EventLoopCrash(Publish),
+    EventLoopCrash,
+    Shutdown,
+}
+
+impl Status {
+    /// Name of the state, as reported in metrics
+    fn name(&self) -> &'static str {
+        match self {
+            Status::Normal => "normal",
+            Status::SlowEventloop(..) => "slow_eventloop",
+            Status::EventLoopReady => "catchup",
+            Status::EventLoopCrash => "crash",
+            Status::Shutdown => "shutdown",
+        }
+    }
 }
 
 /// The uplink Serializer is the component that deals with sending data to the Bytebeam platform.
@@ -45,19 +72,19 @@ enum Status {
 ///                  │
 ///                  │ State transitions happen
 ///                  │ within the loop{}             Load data in Storage from
-///                  │                               previouse sessions/iterations                  AsyncClient has crashed
+///                  │                               previouse sessions/iterations                  Broker is disconnected
 ///          ┌───────▼──────┐                       ┌─────────────────────┐                      ┌───────────────────────┐
-///          │EventLoopReady├───────────────────────►Serializer::catchup()├──────────────────────►EventLoopCrash(publish)│
+///          │EventLoopReady├───────────────────────►Serializer::catchup()├──────────────────────►    EventLoopCrash     │
 ///          └───────▲──────┘                       └──────────┬──────────┘                      └───────────┬───────────┘
 ///                  │                                         │                                             │
 ///                  │                                         │ No more data left in Storage                │
 ///                  │                                         │                                             │
 ///     ┌────────────┴────────────┐                        ┌───▼──┐                             ┌────────────▼─────────────┐
-///     │Serializer::disk(publish)│                        │Normal│                             │Serializer::crash(publish)├──┐
+///     │Serializer::disk(publish)│                        │Normal│                             │   Serializer::crash()    ├──┐
 ///     └────────────▲────────────┘                        └───┬──┘                             └─────────────────────────▲┘  │
 ///                  │                                         │                                 Write all data to Storage└───┘
-///                  │                                         │
-///                  │                                         │
+///                  │                                         │                                 till eventloop reconnects,
+///                  │                                         │                                 then back to EventLoopReady
 ///      ┌───────────┴──────────┐                   ┌──────────▼─────────┐
 ///      │SlowEventloop(publish)◄───────────────────┤Serializer::normal()│
 ///      └──────────────────────┘                   └────────────────────┘
@@ -68,55 +95,104 @@ enum Status {
 pub struct Serializer {
     config: Arc<Config>,
     collector_rx: Receiver<Box<dyn Package>>,
-    client: AsyncClient,
-    storage: Option<Storage>,
+    client: Arc<dyn Sink>,
+    storage: Option<Storages>,
     metrics: Metrics,
+    acks_rx: Receiver<Ack>,
+    /// Publishes that are yet to be acknowledged by the broker
+    inflight: Inflight,
+    shutdown: Shutdown,
+    /// Compression of payloads of every configured stream
+    compressions: Compressions,
+    /// Limits bandwidth used for publishing data
+    throttle: Throttle,
+    /// Tracks if uplink is on a link that data of bulk streams is uploaded over
+    links: Links,
+    /// Status of the connection to the broker
+    connection_status: watch::Receiver<ConnectionStatus>,
 }
 
 impl Serializer {
     pub fn new(
         config: Arc<Config>,
         collector_rx: Receiver<Box<dyn Package>>,
-        client: AsyncClient,
+        client: Arc<dyn Sink>,
+        acks_rx: Receiver<Ack>,
+        connection_status: watch::Receiver<ConnectionStatus>,
+        shutdown: Shutdown,
     ) -> Result<Serializer, Error> {
         let metrics_config = config.streams.get("metrics").expect("Missing metrics Stream in config");
-        let metrics = Metrics::new(&metrics_config.topic);
+        let mut metrics = Metrics::new(&metrics_config.topic);
 
+        let mut links = Links::new(&config);
         let storage = match &config.persistence {
             Some(persistence) => {
-                let storage = Storage::new(
-                    &persistence.path,
-                    persistence.max_file_size,
-                    persistence.max_file_count,
-                )?;
-                Some(storage)
+                let mut storages = Storages::new(&config, persistence)?;
+                storages.hold_bulk(!links.on_preferred());
+                metrics.add_discarded_disk_size(storages.sum(Storage::discarded));
+                Some(storages)
             }
             None => None,
         };
 
-        Ok(Serializer { config, collector_rx, client, storage, metrics })
+        let compressions = Compressions::new(&config);
+        let throttle = Throttle::new(&config);
+        Ok(Serializer {
+            config,
+            collector_rx,
+            client,
+            storage,
+            metrics,
+            acks_rx,
+            inflight: Inflight::default(),
+            shutdown,
+            compressions,
+            throttle,
+            links,
+            connection_status,
+        })
     }
 
-    /// Write all data received, from here-on, to disk only.
-    async fn crash(&mut self, mut publish: Publish) -> Result<Status, Error> {
+    /// Write all data received to disk only, while the eventloop is disconnected
+    /// from the broker or gone. Publishes handed over to the eventloop before
+    /// are sent again once it reconnects
+    async fn crash(&mut self) -> Result<Status, Error> {
         let storage = match &mut self.storage {
             Some(s) => s,
             None => return Err(Error::MissingPersistence),
         };
-        // Write failed publish to disk first
-        publish.pkid = 1;
+        info!("Switching to crash mode!!");
 
         loop {
-            let data = self.collector_rx.recv_async().await?;
-            let topic = data.topic();
-            let payload = data.serialize()?;
+            let data = select! {
+                data = self.collector_rx.recv_async() => data?,
+                Ok(ack) = self.acks_rx.recv_async() => {
+                    if let Some(record) = self.inflight.ack(ack) {
+                        commit(storage, record);
+                    }
+                    continue;
+                }
+                Ok(_) = self.connection_status.changed() => {
+                    // Data written to disk in the meantime is caught up
+                    if self.connection_status.borrow().state == ConnectionState::Connected {
+                        return Ok(Status::EventLoopReady);
+                    }
+                    continue;
+                }
+                _ = self.shutdown.recv() => return Ok(Status::Shutdown),
+            };
+            let (topic, payload) = self.compressions.encode(&*data, &mut self.metrics)?;
+            let payload_size = payload.len();
 
-            let mut publish = Publish::new(topic.as_ref(), QoS::AtLeastOnce, payload);
-            publish.pkid = 1;
+            let publish = new_publish(&*data, &topic, payload);
 
-            if let Err(e) = publish.write(storage.writer()) {
-                error!("Failed to fill write buffer during bad network. Error = {:?}", e);
-                continue;
+            match storage.write(&publish) {
+                Ok(_) => self.metrics.add_disk_size(&data.stream(), payload_size),
+                Err(e) => {
+                    error!("Failed to fill write buffer during bad network. Error = {:?}", e);
+                    self.metrics.add_dropped(&data.stream());
+                    continue;
+                }
             }
 
             match storage.flush_on_overflow() {
@@ -133,18 +209,20 @@ impl Serializer {
     }
 
     /// Write new data to disk until back pressure due to slow n/w is resolved
-    async fn disk(&mut self, publish: Publish) -> Result<Status, Error> {
+    async fn disk(&mut self, stream: Arc<String>, publish: Publish) -> Result<Status, Error> {
         let storage = match &mut self.storage {
             Some(s) => s,
             None => return Err(Error::MissingPersistence),
         };
         info!("Switching to slow eventloop mode!!");
 
-        // Note: self.client.publish() is executing code before await point
-        // in publish method every time. Verify this behaviour later
-        let publish =
-            self.client.publish(&publish.topic, QoS::AtLeastOnce, false, &publish.payload[..]);
-        tokio::pin!(publish);
+        // Throttled publishes go out once they are within the bandwidth limit
+        let payload_size = publish.payload.len();
+        let delay = self.throttle.reserve(&publish.topic, payload_size);
+        self.inflight.push(None);
+        let client = self.client.clone();
+        let send = send_publish(client, publish.clone(), None, delay);
+        tokio::pin!(send);
 
         loop {
             select! {
@@ -154,34 +232,47 @@ impl Serializer {
                         self.metrics.add_errors(errors, count);
                       }
 
-                      let topic = data.topic();
-                      let payload = data.serialize()?;
+                      let (topic, payload) = self.compressions.encode(&*data, &mut self.metrics)?;
                       let payload_size = payload.len();
-                      let mut publish = Publish::new(topic.as_ref(), QoS::AtLeastOnce, payload);
-                      publish.pkid = 1;
+                      let publish = new_publish(&*data, &topic, payload);
 
-                      match publish.write(storage.writer()) {
-                           Ok(_) => self.metrics.add_total_disk_size(payload_size),
+                      match storage.write(&publish) {
+                           Ok(_) => self.metrics.add_disk_size(&data.stream(), payload_size),
                            Err(e) => {
                                error!("Failed to fill disk buffer. Error = {:?}", e);
+                               self.metrics.add_dropped(&data.stream());
                                continue
                            }
                       }
 
                       match storage.flush_on_overflow() {
-                            Ok(deleted) => if deleted.is_some() {
-                                self.metrics.increment_lost_segments();
-                            },
+                            Ok(lost) => self.metrics.add_lost_segments(lost),
                             Err(e) => {
                                 error!("Failed to flush disk buffer. Error = {:?}", e);
                                 continue
                             }
                       }
                 }
-                o = &mut publish => {
+                o = &mut send => {
                     o?;
+                    self.metrics.add_sent_size(&stream, payload_size);
                     return Ok(Status::EventLoopReady)
                 }
+                Ok(ack) = self.acks_rx.recv_async() => {
+                    if let Some(record) = self.inflight.ack(ack) {
+                        commit(storage, record);
+                    }
+                }
+                _ = self.shutdown.recv() => {
+                    // Publish that is blocked on the eventloop is not lost on shutdown
+                    let mut publish = publish.clone();
+                    publish.pkid = 1;
+                    if let Err(e) = storage.write(&publish) {
+                        error!("Failed to fill disk buffer. Error = {:?}", e);
+                    }
+
+                    return Ok(Status::Shutdown)
+                }
             }
         }
     }
@@ -197,25 +288,38 @@ impl Serializer {
             None => return Err(Error::MissingPersistence),
         };
         info!("Switching to catchup mode!!");
+        storage.hold_bulk(!self.links.on_preferred());
+
+        // Publishes would only pile up in the eventloop till it reconnects
+        if is_disconnected(&self.connection_status) {
+            return Ok(Status::EventLoopCrash);
+        }
 
         let max_packet_size = self.config.max_packet_size;
         let client = self.client.clone();
 
-        // Done reading all the pending files
-        if storage.reload_on_eof().unwrap() {
-            return Ok(Status::Normal);
-        }
-
-        let publish = match read(storage.reader(), max_packet_size) {
-            Ok(Packet::Publish(publish)) => publish,
-            Ok(packet) => return Err(Error::UnexpectedPacket(packet)),
+        let (record, publish) = match storage.next(max_packet_size) {
+            Ok(Some(next)) => next,
+            // Done reading all the pending files
+            Ok(None) => return Ok(Status::Normal),
+            Err(storages::Error::UnexpectedPacket(packet)) => {
+                return Err(Error::UnexpectedPacket(packet))
+            }
             Err(e) => {
                 error!("Failed to read from storage. Forcing into Normal mode. Error = {:?}", e);
                 return Ok(Status::Normal);
             }
         };
 
-        let send = send_publish(client, publish.topic, publish.payload);
+        // Persisted data is sent within the bandwidth limit
+        let payload_size = publish.payload.len();
+        self.metrics.sub_total_disk_size(payload_size);
+        self.metrics.add_sent_size(storage.name(record.storage), payload_size);
+        let delay = self.throttle.reserve(&publish.topic, payload_size);
+        self.inflight.push(Some(record));
+        // Publish being sent, to send again if it can't be handed over
+        let mut sending = (record, publish.clone());
+        let send = send_publish(client, publish, record.expiry, delay);
         tokio::pin!(send);
 
         loop {
@@ -226,24 +330,21 @@ impl Serializer {
                         self.metrics.add_errors(errors, count);
                       }
 
-                      let topic = data.topic();
-                      let payload = data.serialize()?;
+                      let (topic, payload) = self.compressions.encode(&*data, &mut self.metrics)?;
                       let payload_size = payload.len();
-                      let mut publish = Publish::new(topic.as_ref(), QoS::AtLeastOnce, payload);
-                      publish.pkid = 1;
+                      let publish = new_publish(&*data, &topic, payload);
 
-                      match publish.write(storage.writer()) {
-                           Ok(_) => self.metrics.add_total_disk_size(payload_size),
+                      match storage.write(&publish) {
+                           Ok(_) => self.metrics.add_disk_size(&data.stream(), payload_size),
                            Err(e) => {
                                error!("Failed to fill disk buffer. Error = {:?}", e);
+                               self.metrics.add_dropped(&data.stream());
                                continue
                            }
                       }
 
                       match storage.flush_on_overflow() {
-                            Ok(deleted) => if deleted.is_some() {
-                                self.metrics.increment_lost_segments();
-                            },
+                            Ok(lost) => self.metrics.add_lost_segments(lost),
                             Err(e) => {
                                 error!("Failed to flush write buffer to disk during catchup. Error = {:?}", e);
                                 continue
@@ -252,42 +353,63 @@ impl Serializer {
                 }
                 o = &mut send => {
                     // Send failure implies eventloop crash. Switch state to
-                    // indefinitely write to disk to not loose data
+                    // write to disk, to not loose data. Failed publish is sent
+                    // again and publishes handed over before stay uncommitted
                     let client = match o {
                         Ok(c) => c,
-                        Err(ClientError::Request(request)) => match request.into_inner() {
-                            Request::Publish(publish) => return Ok(Status::EventLoopCrash(publish)),
-                            request => unreachable!("{:?}", request),
-                        },
+                        Err(sink::Error::Closed(publish)) => {
+                            if let Some(record) = self.inflight.pop() {
+                                storage.rewind(record, publish);
+                            }
+                            return Ok(Status::EventLoopCrash)
+                        }
                         Err(e) => return Err(e.into()),
                     };
 
-                    match storage.reload_on_eof() {
+                    let (record, publish) = match storage.next(max_packet_size) {
+                        Ok(Some(next)) => next,
                         // Done reading all pending files
-                        Ok(true) => return Ok(Status::Normal),
-                        Ok(false) => {},
-                        Err(e) => {
-                            error!("Failed to reload storage. Forcing into Normal mode. Error = {:?}", e);
-                            return Ok(Status::Normal)
+                        Ok(None) => return Ok(Status::Normal),
+                        Err(storages::Error::UnexpectedPacket(packet)) => {
+                            return Err(Error::UnexpectedPacket(packet))
                         }
-                    }
-
-                    let publish = match read(storage.reader(), max_packet_size) {
-                        Ok(Packet::Publish(publish)) => publish,
-                        Ok(packet) => return Err(Error::UnexpectedPacket(packet)),
                         Err(e) => {
                             error!("Failed to read from storage. Forcing into Normal mode. Error = {:?}", e);
                             return Ok(Status::Normal)
                         }
                     };
 
-
-                    let payload = publish.payload;
-                    let payload_size = payload.len();
+                    let payload_size = publish.payload.len();
                     self.metrics.sub_total_disk_size(payload_size);
-                    self.metrics.add_total_sent_size(payload_size);
-                    send.set(send_publish(client, publish.topic, payload));
+                    self.metrics.add_sent_size(storage.name(record.storage), payload_size);
+                    let delay = self.throttle.reserve(&publish.topic, payload_size);
+                    self.inflight.push(Some(record));
+                    sending = (record, publish.clone());
+                    send.set(send_publish(client, publish, record.expiry, delay));
+                }
+                Ok(ack) = self.acks_rx.recv_async() => {
+                    // Data on disk is deleted only after the broker has it
+                    if let Some(record) = self.inflight.ack(ack) {
+                        commit(storage, record);
+                    }
                 }
+                Ok(_) = self.connection_status.changed() => {
+                    // Publish that is yet to be handed over is sent after reconnecting.
+                    // One that is already handed over is sent again by the eventloop
+                    if is_disconnected(&self.connection_status) {
+                        match send.as_mut().now_or_never() {
+                            Some(Ok(_)) => {}
+                            Some(Err(sink::Error::Closed(_))) | None => {
+                                self.inflight.pop();
+                                let (record, publish) = sending;
+                                storage.rewind(record, publish);
+                            }
+                            Some(Err(e)) => return Err(e.into()),
+                        }
+                        return Ok(Status::EventLoopCrash)
+                    }
+                }
+                _ = self.shutdown.recv() => return Ok(Status::Shutdown),
             }
         }
     }
@@ -295,10 +417,19 @@ impl Serializer {
     async fn normal(&mut self) -> Result<Status, Error> {
         info!("Switching to normal mode!!");
         let mut interval = time::interval(time::Duration::from_secs(10));
+        // Publish held back till it is within the bandwidth limit, along with its stream
+        let mut throttled: Option<(Arc<String>, Publish)> = None;
+        let throttle = time::sleep(Duration::ZERO);
+        tokio::pin!(throttle);
+        // Data is written to disk while the eventloop is disconnected
+        let persisted = self.storage.is_some();
+        if persisted && is_disconnected(&self.connection_status) {
+            return Ok(Status::EventLoopCrash);
+        }
 
         loop {
             let failed = select! {
-                data = self.collector_rx.recv_async() => {
+                data = self.collector_rx.recv_async(), if throttled.is_none() => {
                     let data = data?;
 
                     // Extract anomalies detected by package during collection
@@ -306,55 +437,195 @@ impl Serializer {
                         self.metrics.add_errors(errors, count);
                     }
 
-                    let topic = data.topic();
-                    let payload = data.serialize()?;
+                    let (topic, payload) = self.compressions.encode(&*data, &mut self.metrics)?;
                     let payload_size = payload.len();
-                    match self.client.try_publish(topic.as_ref(), QoS::AtLeastOnce, false, payload) {
-                        Ok(_) => {
-                            self.metrics.add_total_sent_size(payload_size);
-                            continue;
+
+                    // Data of bulk streams waits in storage while on a link that isn't preferred
+                    if let Some(storage) = self.storage.as_mut().filter(|s| s.is_held(&topic)) {
+                        let publish = new_publish(&*data, &topic, payload);
+                        match storage.write(&publish) {
+                            Ok(_) => self.metrics.add_disk_size(&data.stream(), payload_size),
+                            Err(e) => {
+                                error!("Failed to fill disk buffer. Error = {:?}", e);
+                                self.metrics.add_dropped(&data.stream());
+                            }
                         }
-                        Err(ClientError::TryRequest(request)) => request,
-                        Err(e) => return Err(e.into()),
+
+                        match storage.flush_on_overflow() {
+                            Ok(lost) => self.metrics.add_lost_segments(lost),
+                            Err(e) => error!("Failed to flush disk buffer. Error = {:?}", e),
+                        }
+                        continue;
+                    }
+
+                    // Data over the bandwidth limit is written to disk, to be sent at the
+                    // limited rate during catchup
+                    let delay = self.throttle.delay(&topic, payload_size);
+                    if !delay.is_zero() {
+                        self.metrics.add_throttled_size(payload_size);
+                        if self.storage.is_some() {
+                            let publish = new_publish(&*data, &topic, payload);
+                            return Ok(Status::SlowEventloop(data.stream(), publish));
+                        }
+
+                        // Without persistence, data is held back till it is within the limit.
+                        // Acks and shutdown are handled in the meantime
+                        throttled = Some((data.stream(), new_publish(&*data, &topic, payload)));
+                        throttle.as_mut().reset(time::Instant::now() + delay);
+                        continue;
                     }
 
+                    match self.try_publish(&data.stream(), new_publish(&*data, &topic, payload))? {
+                        Some(publish) => (data.stream(), publish),
+                        None => continue,
+                    }
+                }
+                _ = &mut throttle, if throttled.is_some() => {
+                    let (stream, publish) = throttled.take().unwrap();
+                    match self.try_publish(&stream, publish)? {
+                        Some(publish) => (stream, publish),
+                        None => continue,
+                    }
                 }
                 _ = interval.tick() => {
+                    if let Some(storage) = &mut self.storage {
+                        // Held data of bulk streams is caught up once on a preferred link
+                        if storage.hold_bulk(!self.links.on_preferred()) {
+                            return Ok(Status::EventLoopReady);
+                        }
+
+                        self.metrics.set_flushed_sizes(
+                            storage.sum(Storage::flushed_size),
+                            storage.sum(Storage::flushed_disk_size),
+                        );
+                        self.metrics.set_disk_usage(
+                            storage.sum(Storage::disk_usage),
+                            storage.sum(Storage::quota_evictions),
+                        );
+                        self.metrics.set_expired_disk_size(storage.sum(Storage::expired));
+                    }
+
+                    self.metrics.set_inflight(self.inflight.depth(), self.inflight.take_latencies());
+                    self.metrics.set_broker(&self.connection_status.borrow().endpoint);
+
+                    // Metrics are published as configured for their stream
+                    let config = &self.config.streams["metrics"];
                     let (topic, payload) = self.metrics.next()?;
-                    let payload_size = payload.len();
-                    match self.client.try_publish(topic, QoS::AtLeastOnce, false, payload) {
-                        Ok(_) => {
-                            self.metrics.add_total_sent_size(payload_size);
-                            continue;
+                    let mut publish = Publish::new(topic, config.qos, payload);
+                    publish.retain = config.retain;
+                    match self.try_publish("metrics", publish)? {
+                        Some(publish) => (Arc::new("metrics".to_owned()), publish),
+                        None => continue,
+                    }
+                }
+                Ok(ack) = self.acks_rx.recv_async() => {
+                    // Catchup data that is acknowledged after switching to normal mode
+                    if let (Some(record), Some(storage)) = (self.inflight.ack(ack), &mut self.storage) {
+                        commit(storage, record);
+                    }
+                    continue;
+                }
+                Ok(_) = self.connection_status.changed(), if persisted => {
+                    if is_disconnected(&self.connection_status) {
+                        return Ok(Status::EventLoopCrash);
+                    }
+                    continue;
+                }
+                _ = self.shutdown.recv() => {
+                    // Handed over to the eventloop before it disconnects
+                    if let Some((stream, publish)) = throttled.take() {
+                        if let Err(e) = self.client.try_publish(publish) {
+                            error!("Failed to publish during shutdown. Error = {:?}", e);
+                            self.metrics.add_dropped(&stream);
                         }
-                        Err(ClientError::TryRequest(request)) => request,
-                        Err(e) => return Err(e.into()),
                     }
+
+                    return Ok(Status::Shutdown)
                 }
             };
 
-            match failed.into_inner() {
-                Request::Publish(publish) => return Ok(Status::SlowEventloop(publish)),
-                request => unreachable!("{:?}", request),
+            let (stream, publish) = failed;
+            return Ok(Status::SlowEventloop(stream, publish));
+        }
+    }
+
+    /// Hands data of `stream` over to the eventloop without waiting. Returns the
+    /// publish back if the eventloop is busy
+    fn try_publish(&mut self, stream: &str, publish: Publish) -> Result<Option<Publish>, Error> {
+        let topic = publish.topic.clone();
+        let payload_size = publish.payload.len();
+        self.inflight.push(None);
+        match self.client.try_publish(publish) {
+            Ok(_) => {
+                self.throttle.reserve(&topic, payload_size);
+                self.metrics.add_sent_size(stream, payload_size);
+                Ok(None)
+            }
+            Err(sink::Error::Full(publish)) => {
+                self.inflight.pop();
+                Ok(Some(publish))
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Persists data that is still in memory, before exiting. Collectors
+    /// have flushed their streams into the channel by now
+    fn shutdown(&mut self) -> Result<(), Error> {
+        info!("Shutting down serializer!!");
+        while let Ok(data) = self.collector_rx.try_recv() {
+            let (topic, payload) = self.compressions.encode(&*data, &mut self.metrics)?;
+            let payload_size = payload.len();
+            let storage = match &mut self.storage {
+                Some(storage) => storage,
+                None => {
+                    // Handed over to the eventloop before it disconnects
+                    if let Err(e) = self.client.try_publish(new_publish(&*data, &topic, payload)) {
+                        error!("Failed to publish during shutdown. Error = {:?}", e);
+                        self.metrics.add_dropped(&data.stream());
+                    }
+                    continue;
+                }
             };
+
+            let publish = new_publish(&*data, &topic, payload);
+            match storage.write(&publish) {
+                Ok(_) => self.metrics.add_disk_size(&data.stream(), payload_size),
+                Err(e) => {
+                    error!("Failed to fill disk buffer during shutdown. Error = {:?}", e);
+                    self.metrics.add_dropped(&data.stream());
+                }
+            }
         }
+
+        if let Some(storage) = &mut self.storage {
+            let lost = storage.flush_all()?;
+            self.metrics.add_lost_segments(lost);
+        }
+
+        Ok(())
     }
 
     pub async fn start(mut self) -> Result<(), Error> {
         if self.storage.is_none() {
             loop {
-                self.normal().await?;
+                self.metrics.enter_state(Status::Normal.name());
+                if let Status::Shutdown = self.normal().await? {
+                    return self.shutdown();
+                }
             }
         }
 
         let mut status = Status::EventLoopReady;
 
         loop {
+            self.metrics.enter_state(status.name());
             let next_status = match status {
                 Status::Normal => self.normal().await?,
-                Status::SlowEventloop(publish) => self.disk(publish).await?,
+                Status::SlowEventloop(stream, publish) => self.disk(stream, publish).await?,
                 Status::EventLoopReady => self.catchup().await?,
-                Status::EventLoopCrash(publish) => self.crash(publish).await?,
+                Status::EventLoopCrash => self.crash().await?,
+                Status::Shutdown => return self.shutdown(),
             };
 
             status = next_status;
@@ -362,15 +633,168 @@ impl Serializer {
     }
 }
 
+/// Hands over a publish once `delay` is over. Publishes read from persistence
+/// expire when they were set to while being persisted, not a whole max age
+/// after being sent
 async fn send_publish(
-    client: AsyncClient,
-    topic: String,
-    payload: Bytes,
-) -> Result<AsyncClient, ClientError> {
-    client.publish_bytes(topic, QoS::AtLeastOnce, false, payload).await?;
+    client: Arc<dyn Sink>,
+    publish: Publish,
+    expiry: Option<u64>,
+    delay: Duration,
+) -> Result<Arc<dyn Sink>, sink::Error> {
+    time::sleep(delay).await;
+    match expiry {
+        Some(expiry) => client.publish_expiring(publish, expiry).await?,
+        None => client.publish(publish).await?,
+    }
     Ok(client)
 }
 
+/// Publish of serialized data of a package, with QoS and retain flag of its
+/// stream. Storage needs a pkid to write publishes with QoS 1 or 2
+fn new_publish(data: &dyn Package, topic: &str, payload: Vec<u8>) -> Publish {
+    let mut publish = Publish::new(topic, data.qos(), payload);
+    publish.retain = data.retain();
+    publish.pkid = 1;
+    publish
+}
+
+/// Checks if the eventloop lost its connection to the broker and is yet to
+/// reconnect
+fn is_disconnected(status: &watch::Receiver<ConnectionStatus>) -> bool {
+    status.borrow().state == ConnectionState::Disconnected
+}
+
+/// Commits data read from storage, once the broker acknowledges it
+fn commit(storages: &mut Storages, record: Record) {
+    if let Err(e) = storages.commit(record) {
+        error!("Failed to commit acknowledged data in storage. Error = {:?}", e);
+    }
+}
+
+/// Compression of payloads of configured streams, along with the topic
+/// compressed payloads are sent to, by topic
+struct Compressions {
+    streams: HashMap<String, (PayloadCompression, Arc<String>)>,
+}
+
+impl Compressions {
+    fn new(config: &Config) -> Compressions {
+        let streams = config
+            .streams
+            .values()
+            .filter(|s| s.compression != PayloadCompression::None)
+            .map(|s| (s.topic.clone(), (s.compression, Arc::new(s.compression.topic(&s.topic)))))
+            .collect();
+
+        Compressions { streams }
+    }
+
+    /// Serializes data and compresses it, if its stream is configured to
+    fn encode(
+        &self,
+        data: &dyn Package,
+        metrics: &mut Metrics,
+    ) -> Result<(Arc<String>, Vec<u8>), Error> {
+        metrics.add_batch(&data.stream(), data.point_count());
+        let topic = data.topic();
+        let payload = data.serialize()?;
+        let (compression, topic) = match self.streams.get(topic.as_ref()) {
+            Some((compression, topic)) => (compression, topic.clone()),
+            None => return Ok((topic, payload)),
+        };
+
+        let compressed = compression.compress(&payload)?;
+        metrics.add_compressed_size(payload.len(), compressed.len());
+        Ok((topic, compressed))
+    }
+}
+
+/// Tracks publishes handed over to the eventloop till the broker acknowledges
+/// them. Publishes get a pkid in the order they are handed over, as the
+/// serializer is the only one publishing through the eventloop
+#[derive(Debug, Default)]
+struct Inflight {
+    /// publishes that are yet to be written to the network, in order. Record
+    /// to commit publishes read from storage by, along with when the publish
+    /// was handed over
+    queued: VecDeque<(Option<Record>, Instant)>,
+    /// publishes waiting for an acknowledgement, by pkid
+    pending: HashMap<u16, (Option<Record>, Instant)>,
+    /// time taken by the broker to acknowledge publishes, since last taken
+    latencies: Vec<Duration>,
+}
+
+impl Inflight {
+    /// Tracks a publish that is about to be handed over to the eventloop
+    fn push(&mut self, from_storage: Option<Record>) {
+        self.queued.push_back((from_storage, Instant::now()));
+    }
+
+    /// Forgets the last publish, when it couldn't be handed over. Returns
+    /// its record if it was read from storage
+    fn pop(&mut self) -> Option<Record> {
+        self.queued.pop_back()?.0
+    }
+
+    /// Returns record of the publish when a publish read from storage is acknowledged
+    fn ack(&mut self, ack: Ack) -> Option<Record> {
+        match ack {
+            // Publishes with QoS 0 aren't acknowledged, they are done once written
+            Ack::Outgoing(0) => self.queued.pop_front()?.0,
+            // Pending publishes are retransmitted with the same pkid on reconnection
+            Ack::Outgoing(pkid) => {
+                if !self.pending.contains_key(&pkid) {
+                    if let Some(publish) = self.queued.pop_front() {
+                        self.pending.insert(pkid, publish);
+                    }
+                }
+
+                None
+            }
+            Ack::Acked(pkid) => {
+                let (from_storage, handed_over) = self.pending.remove(&pkid)?;
+                // Samples are dropped when metrics aren't being published, e.g in catchup
+                if self.latencies.len() < 1024 {
+                    self.latencies.push(handed_over.elapsed());
+                }
+
+                from_storage
+            }
+        }
+    }
+
+    /// Number of publishes that are yet to be acknowledged
+    fn depth(&self) -> usize {
+        self.queued.len() + self.pending.len()
+    }
+
+    fn take_latencies(&mut self) -> Vec<Duration> {
+        std::mem::take(&mut self.latencies)
+    }
+}
+
+/// Counters of a stream, since uplink started
+#[derive(Debug, Default, Serialize)]
+struct StreamMetrics {
+    points: usize,
+    batches: usize,
+    sent_size: usize,
+    disk_size: usize,
+    /// batches lost as they couldn't be sent or persisted
+    dropped: usize,
+}
+
+/// Transitions into a state of the serializer and time spent in it, since
+/// uplink started
+#[derive(Debug, Default, Serialize)]
+struct StateMetrics {
+    transitions: usize,
+    time_ms: u64,
+    #[serde(skip_serializing)]
+    time: Duration,
+}
+
 #[derive(Debug, Default, Serialize)]
 struct Metrics {
     #[serde(skip_serializing)]
@@ -380,6 +804,40 @@ struct Metrics {
     total_sent_size: usize,
     total_disk_size: usize,
     lost_segments: usize,
+    discarded_disk_size: usize,
+    disk_uncompressed_size: usize,
+    disk_compressed_size: usize,
+    disk_usage: usize,
+    disk_quota_evictions: usize,
+    expired_disk_size: usize,
+    payload_uncompressed_size: usize,
+    payload_compressed_size: usize,
+    /// uncompressed size of compressed payloads divided by their compressed size
+    compression_ratio: f64,
+    /// bytes sent per second since the previous metrics
+    sent_rate: f64,
+    /// bytes held back or written to disk for being over the bandwidth limit
+    throttled_size: usize,
+    #[serde(skip_serializing)]
+    last_sent_size: usize,
+    /// counters of every stream, by name. Data sent from persistence is
+    /// counted under the stream it is persisted for
+    streams: HashMap<String, StreamMetrics>,
+    /// counters of every state of the serializer, by name
+    states: HashMap<&'static str, StateMetrics>,
+    /// current state and when it was last accounted for
+    #[serde(skip_serializing)]
+    state: Option<(&'static str, Instant)>,
+    /// publishes handed over to the eventloop that are yet to be acknowledged
+    inflight: usize,
+    /// milliseconds taken by the broker to acknowledge publishes, since the
+    /// previous metrics
+    latency_p50: u64,
+    latency_p90: u64,
+    latency_p99: u64,
+    latency_max: u64,
+    /// broker connected, or being connected, to
+    broker: String,
     errors: String,
     error_count: usize,
 }
@@ -389,20 +847,107 @@ impl Metrics {
         Metrics { topic: topic.into(), errors: String::with_capacity(1024), ..Default::default() }
     }
 
-    pub fn add_total_sent_size(&mut self, size: usize) {
+    fn stream(&mut self, name: &str) -> &mut StreamMetrics {
+        self.streams.entry(name.to_owned()).or_default()
+    }
+
+    pub fn add_batch(&mut self, stream: &str, points: usize) {
+        let stream = self.stream(stream);
+        stream.points = stream.points.saturating_add(points);
+        stream.batches = stream.batches.saturating_add(1);
+    }
+
+    pub fn add_sent_size(&mut self, stream: &str, size: usize) {
         self.total_sent_size = self.total_sent_size.saturating_add(size);
+        let stream = self.stream(stream);
+        stream.sent_size = stream.sent_size.saturating_add(size);
     }
 
-    pub fn add_total_disk_size(&mut self, size: usize) {
+    pub fn add_disk_size(&mut self, stream: &str, size: usize) {
         self.total_disk_size = self.total_disk_size.saturating_add(size);
+        let stream = self.stream(stream);
+        stream.disk_size = stream.disk_size.saturating_add(size);
+    }
+
+    pub fn add_dropped(&mut self, stream: &str) {
+        let stream = self.stream(stream);
+        stream.dropped = stream.dropped.saturating_add(1);
+    }
+
+    /// Accounts for time spent in the current state and switches to `state`
+    pub fn enter_state(&mut self, state: &'static str) {
+        self.update_state_time();
+        self.states.entry(state).or_default().transitions += 1;
+        self.state = Some((state, Instant::now()));
+    }
+
+    fn update_state_time(&mut self) {
+        if let Some((name, since)) = &mut self.state {
+            let now = Instant::now();
+            let state = self.states.entry(name).or_default();
+            state.time += now.duration_since(*since);
+            state.time_ms = state.time.as_millis() as u64;
+            *since = now;
+        }
+    }
+
+    pub fn set_inflight(&mut self, depth: usize, mut latencies: Vec<Duration>) {
+        self.inflight = depth;
+
+        latencies.sort_unstable();
+        let percentile = |p: usize| match latencies.len() {
+            0 => 0,
+            len => latencies[(len - 1) * p / 100].as_millis() as u64,
+        };
+        self.latency_p50 = percentile(50);
+        self.latency_p90 = percentile(90);
+        self.latency_p99 = percentile(99);
+        self.latency_max = percentile(100);
+    }
+
+    pub fn set_broker(&mut self, broker: &str) {
+        self.broker.clear();
+        self.broker.push_str(broker);
     }
 
     pub fn sub_total_disk_size(&mut self, size: usize) {
         self.total_disk_size = self.total_disk_size.saturating_sub(size);
     }
 
-    pub fn increment_lost_segments(&mut self) {
-        self.lost_segments += 1;
+    pub fn add_discarded_disk_size(&mut self, size: usize) {
+        self.discarded_disk_size = self.discarded_disk_size.saturating_add(size);
+    }
+
+    pub fn set_flushed_sizes(&mut self, uncompressed: usize, compressed: usize) {
+        self.disk_uncompressed_size = uncompressed;
+        self.disk_compressed_size = compressed;
+    }
+
+    pub fn set_disk_usage(&mut self, usage: usize, quota_evictions: usize) {
+        self.disk_usage = usage;
+        self.disk_quota_evictions = quota_evictions;
+    }
+
+    pub fn set_expired_disk_size(&mut self, size: usize) {
+        self.expired_disk_size = size;
+    }
+
+    pub fn add_compressed_size(&mut self, uncompressed: usize, compressed: usize) {
+        self.payload_uncompressed_size =
+            self.payload_uncompressed_size.saturating_add(uncompressed);
+        self.payload_compressed_size = self.payload_compressed_size.saturating_add(compressed);
+        if self.payload_compressed_size > 0 {
+            self.compression_ratio =
+                self.payload_uncompressed_size as f64 / self.payload_compressed_size as f64;
+        }
+    }
+
+    pub fn add_throttled_size(&mut self, size: usize) {
+        self.throttled_size = self.throttled_size.saturating_add(size);
+    }
+
+    pub fn add_lost_segments(&mut self, count: usize) {
+        self.lost_segments += count;
     }
 
     // pub fn add_error<S: Into<String>>(&mut self, error: S) {
@@ -428,8 +973,15 @@ impl Metrics {
     pub fn next(&mut self) -> Result<(&str, Vec<u8>), Error> {
         let timestamp =
             SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or(Duration::from_secs(0));
-        self.timestamp = timestamp.as_millis() as u64;
+        let timestamp = timestamp.as_millis() as u64;
+        if self.timestamp > 0 && timestamp > self.timestamp {
+            let sent = self.total_sent_size.saturating_sub(self.last_sent_size);
+            self.sent_rate = sent as f64 * 1000.0 / (timestamp - self.timestamp) as f64;
+        }
+        self.last_sent_size = self.total_sent_size;
+        self.timestamp = timestamp;
         self.sequence += 1;
+        self.update_state_time();
 
         let payload = serde_json::to_vec(&vec![&self])?;
         self.errors.clear();
@@ -437,3 +989,285 @@ impl Metrics {
         Ok((&self.topic, payload))
     }
 }
+
+#[cfg(test)]
+mod test {
+    use flume::{bounded, unbounded, Sender};
+    use serde_json::json;
+    use tempdir::TempDir;
+
+    use super::*;
+    use crate::base::shutdown::Stage;
+    use crate::base::sink::ChannelSink;
+    use crate::base::{Buffer, Persistence};
+    use crate::Payload;
+
+    fn config(streams: serde_json::Value) -> Config {
+        let mut config: HashMap<String, _> = serde_json::from_value(streams).unwrap();
+        let metrics = json!({ "topic": "/metrics", "buf_size": 10 });
+        config.insert("metrics".to_owned(), serde_json::from_value(metrics).unwrap());
+
+        Config { streams: config, max_packet_size: 1024 * 1024, ..Default::default() }
+    }
+
+    fn package(topic: &str, sequence: u32) -> Box<dyn Package> {
+        let mut buffer = Buffer::new(Arc::new("test".to_owned()), Arc::new(topic.to_owned()));
+        let payload = json!({ "data": sequence });
+        buffer.buffer.push(Payload { stream: "test".to_owned(), sequence, timestamp: 0, payload });
+        Box::new(buffer)
+    }
+
+    fn persistence(backup: &TempDir) -> Persistence {
+        Persistence {
+            path: backup.path().to_str().unwrap().to_owned(),
+            max_file_size: 1024,
+            max_file_count: 10,
+            compression: Default::default(),
+            max_disk_usage: None,
+            min_free_space: None,
+            max_age: None,
+        }
+    }
+
+    struct Setup {
+        data_tx: Sender<Box<dyn Package>>,
+        publishes: Receiver<Publish>,
+        status: watch::Sender<ConnectionStatus>,
+        stage: Stage,
+        serializer: tokio::task::JoinHandle<Result<(), Error>>,
+    }
+
+    /// Serializer publishing into a channel of `capacity` publishes
+    fn spawn(config: Config, capacity: usize) -> Setup {
+        let (data_tx, data_rx) = bounded(10);
+        let (publishes_tx, publishes) = bounded(capacity);
+        let (acks_tx, acks_rx) = unbounded();
+        let sink = Arc::new(ChannelSink::new(publishes_tx, acks_tx));
+        let (status, status_rx) = watch::channel(ConnectionStatus::default());
+        let stage = Stage::new();
+        let serializer =
+            Serializer::new(Arc::new(config), data_rx, sink, acks_rx, status_rx, stage.handle())
+                .unwrap();
+        let serializer = tokio::spawn(serializer.start());
+
+        Setup { data_tx, publishes, status, stage, serializer }
+    }
+
+    fn set_state(status: &watch::Sender<ConnectionStatus>, state: ConnectionState) {
+        status.send(ConnectionStatus { state, ..Default::default() }).unwrap();
+    }
+
+    /// Topics of publishes of data, other than metrics, that arrive within a while
+    async fn received(publishes: &Receiver<Publish>, count: usize) -> Vec<String> {
+        let mut topics = vec![];
+        while topics.len() < count {
+            let publish = time::timeout(Duration::from_secs(1), publishes.recv_async()).await;
+            let publish = publish.expect("publish didn't arrive in time").unwrap();
+            if publish.topic != "/metrics" {
+                topics.push(publish.topic);
+            }
+        }
+
+        topics
+    }
+
+    #[tokio::test]
+    async fn data_is_persisted_while_disconnected_and_caught_up_after_reconnecting() {
+        let backup = TempDir::new("/tmp/serializer").unwrap();
+        let config = Config {
+            persistence: Some(persistence(&backup)),
+            ..config(json!({ "can": { "topic": "/can", "buf_size": 1 } }))
+        };
+        let Setup { data_tx, publishes, status, mut stage, serializer } = spawn(config, 10);
+
+        // Eventloop lost its connection, nothing is handed over to it till it reconnects
+        set_state(&status, ConnectionState::Disconnected);
+        time::sleep(Duration::from_millis(100)).await;
+        for sequence in 1..=3 {
+            data_tx.send_async(package("/can", sequence)).await.unwrap();
+        }
+        time::sleep(Duration::from_millis(100)).await;
+        assert!(publishes.drain().all(|publish| publish.topic == "/metrics"));
+
+        // Persisted data is caught up, after which data is published as it comes
+        set_state(&status, ConnectionState::Connected);
+        assert_eq!(received(&publishes, 3).await, ["/can", "/can", "/can"]);
+        data_tx.send_async(package("/gps", 4)).await.unwrap();
+        assert_eq!(received(&publishes, 1).await, ["/gps"]);
+
+        let deadline = time::Instant::now() + Duration::from_secs(1);
+        assert!(stage.trigger(deadline).await);
+        serializer.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn publish_handed_over_as_connection_is_lost_is_not_sent_again() {
+        let backup = TempDir::new("/tmp/serializer").unwrap();
+        let config = Config {
+            persistence: Some(persistence(&backup)),
+            ..config(json!({ "can": { "topic": "/can", "buf_size": 1 } }))
+        };
+        let Setup { data_tx, publishes, status, mut stage, serializer } = spawn(config, 1);
+
+        set_state(&status, ConnectionState::Disconnected);
+        time::sleep(Duration::from_millis(100)).await;
+        for sequence in 1..=8 {
+            data_tx.send_async(package("/can", sequence)).await.unwrap();
+        }
+        time::sleep(Duration::from_millis(100)).await;
+        publishes.drain().for_each(drop);
+
+        let mut payloads = vec![];
+        for _ in 0..4 {
+            // Catchup fills the channel and waits to hand over the next publish
+            set_state(&status, ConnectionState::Connected);
+            time::sleep(Duration::from_millis(100)).await;
+
+            // Making space hands it over, just as the connection is lost
+            set_state(&status, ConnectionState::Disconnected);
+            payloads.push(publishes.try_recv().unwrap().payload);
+            time::sleep(Duration::from_millis(100)).await;
+        }
+
+        set_state(&status, ConnectionState::Connected);
+        while payloads.len() < 8 {
+            let publish = time::timeout(Duration::from_secs(1), publishes.recv_async()).await;
+            let publish = publish.expect("publish didn't arrive in time").unwrap();
+            if publish.topic != "/metrics" {
+                payloads.push(publish.payload);
+            }
+        }
+
+        // Every record is sent exactly once
+        payloads.sort();
+        payloads.dedup();
+        assert_eq!(payloads.len(), 8);
+
+        let deadline = time::Instant::now() + Duration::from_secs(1);
+        assert!(stage.trigger(deadline).await);
+        serializer.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn throttled_publish_does_not_block_shutdown() {
+        let streams = json!({ "can": { "topic": "/can", "buf_size": 1, "max_bandwidth": 1 } });
+        let Setup { data_tx, publishes, mut stage, serializer, .. } = spawn(config(streams), 10);
+
+        // Held back for tens of seconds, to stay within a byte per second
+        data_tx.send_async(package("/can", 1)).await.unwrap();
+        time::sleep(Duration::from_millis(100)).await;
+        assert!(publishes.drain().all(|publish| publish.topic == "/metrics"));
+
+        let deadline = time::Instant::now() + Duration::from_secs(1);
+        assert!(stage.trigger(deadline).await);
+        serializer.await.unwrap().unwrap();
+
+        // Handed over to the eventloop on shutdown
+        let publishes: Vec<_> = publishes.drain().map(|publish| publish.topic).collect();
+        assert_eq!(publishes, vec!["/can"]);
+    }
+
+    #[tokio::test]
+    async fn metrics_are_published_as_configured() {
+        let mut config = config(json!({}));
+        let metrics = config.streams.get_mut("metrics").unwrap();
+        metrics.qos = QoS::AtMostOnce;
+        metrics.retain = true;
+        let Setup { data_tx: _data_tx, publishes, mut stage, serializer, .. } = spawn(config, 10);
+
+        let publish = time::timeout(Duration::from_secs(1), publishes.recv_async()).await;
+        let publish = publish.unwrap().unwrap();
+        assert_eq!(publish.topic, "/metrics");
+        assert_eq!(publish.qos, QoS::AtMostOnce);
+        assert!(publish.retain);
+
+        let deadline = time::Instant::now() + Duration::from_secs(1);
+        assert!(stage.trigger(deadline).await);
+        serializer.await.unwrap().unwrap();
+    }
+
+    fn record(sequence: u64) -> Option<Record> {
+        Some(Record { storage: 0, sequence, expiry: None })
+    }
+
+    #[test]
+    fn records_are_returned_once_acknowledged() {
+        let mut inflight = Inflight::default();
+        inflight.push(record(0));
+        inflight.push(None);
+        inflight.push(record(1));
+        assert_eq!(inflight.depth(), 3);
+
+        // Written to the network in order, acknowledged in any order
+        assert_eq!(inflight.ack(Ack::Outgoing(1)), None);
+        assert_eq!(inflight.ack(Ack::Outgoing(2)), None);
+        assert_eq!(inflight.ack(Ack::Outgoing(3)), None);
+        assert_eq!(inflight.ack(Ack::Acked(3)), record(1));
+        assert_eq!(inflight.ack(Ack::Acked(2)), None);
+        assert_eq!(inflight.ack(Ack::Acked(1)), record(0));
+        assert_eq!(inflight.ack(Ack::Acked(1)), None);
+
+        assert_eq!(inflight.depth(), 0);
+        assert_eq!(inflight.take_latencies().len(), 3);
+        assert!(inflight.take_latencies().is_empty());
+    }
+
+    #[test]
+    fn retransmitted_publishes_keep_their_record() {
+        let mut inflight = Inflight::default();
+        inflight.push(record(0));
+        inflight.push(record(1));
+        assert_eq!(inflight.ack(Ack::Outgoing(1)), None);
+
+        // Sent again with the same pkid after reconnecting
+        assert_eq!(inflight.ack(Ack::Outgoing(1)), None);
+        assert_eq!(inflight.ack(Ack::Outgoing(2)), None);
+        assert_eq!(inflight.ack(Ack::Acked(1)), record(0));
+        assert_eq!(inflight.ack(Ack::Acked(2)), record(1));
+    }
+
+    #[test]
+    fn unacknowledged_publishes_are_done_once_written() {
+        let mut inflight = Inflight::default();
+        inflight.push(record(0));
+        inflight.push(record(1));
+
+        // Couldn't be handed over
+        assert_eq!(inflight.pop(), record(1));
+        assert_eq!(inflight.ack(Ack::Outgoing(0)), record(0));
+        assert_eq!(inflight.ack(Ack::Outgoing(0)), None);
+        assert_eq!(inflight.depth(), 0);
+    }
+
+    #[test]
+    fn metrics_count_streams_states_and_latencies() {
+        let mut metrics = Metrics::new("/metrics");
+        metrics.add_batch("can", 10);
+        metrics.add_batch("can", 5);
+        metrics.add_sent_size("can", 100);
+        metrics.add_disk_size("gps", 50);
+        metrics.add_dropped("gps");
+        metrics.enter_state("normal");
+        metrics.enter_state("catchup");
+        metrics.enter_state("normal");
+        let latencies = (1..=100).map(Duration::from_millis).collect();
+        metrics.set_inflight(3, latencies);
+
+        let (topic, payload) = metrics.next().unwrap();
+        assert_eq!(topic, "/metrics");
+        let payload: serde_json::Value = serde_json::from_slice(&payload).unwrap();
+        let metrics = &payload[0];
+        assert_eq!(metrics["sequence"], 1);
+        assert_eq!(metrics["total_sent_size"], 100);
+        assert_eq!(metrics["streams"]["can"]["points"], 15);
+        assert_eq!(metrics["streams"]["can"]["batches"], 2);
+        assert_eq!(metrics["streams"]["gps"]["disk_size"], 50);
+        assert_eq!(metrics["streams"]["gps"]["dropped"], 1);
+        assert_eq!(metrics["states"]["normal"]["transitions"], 2);
+        assert_eq!(metrics["states"]["catchup"]["transitions"], 1);
+        assert_eq!(metrics["inflight"], 3);
+        assert_eq!(metrics["latency_p50"], 50);
+        assert_eq!(metrics["latency_p90"], 90);
+        assert_eq!(metrics["latency_max"], 100);
+    }
+}
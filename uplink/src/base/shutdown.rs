@@ -0,0 +1,66 @@
+use flume::{bounded, Receiver, RecvTimeoutError, Sender};
+use tokio::time::{self, Duration, Instant};
+
+/// Handle with which a subsystem learns that uplink is shutting down. The
+/// subsystem is considered stopped once all its handles are dropped
+#[derive(Debug, Clone)]
+pub struct Shutdown {
+    /// disconnected when shutdown begins
+    signal: Receiver<()>,
+    /// dropped along with the handle
+    _done: Sender<()>,
+}
+
+impl Shutdown {
+    /// Resolves once shutdown begins
+    pub async fn recv(&self) {
+        let _ = self.signal.recv_async().await;
+    }
+
+    /// Blocks for at most `timeout`. Returns true if shutdown has begun
+    pub fn wait(&self, timeout: Duration) -> bool {
+        !matches!(self.signal.recv_timeout(timeout), Err(RecvTimeoutError::Timeout))
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        self.signal.is_disconnected()
+    }
+}
+
+/// Group of subsystems that are shut down together, holding handles
+/// created by the stage
+pub struct Stage {
+    signal_tx: Option<Sender<()>>,
+    signal_rx: Receiver<()>,
+    done_tx: Option<Sender<()>>,
+    done_rx: Receiver<()>,
+}
+
+impl Stage {
+    pub fn new() -> Stage {
+        let (signal_tx, signal_rx) = bounded(0);
+        let (done_tx, done_rx) = bounded(0);
+
+        Stage { signal_tx: Some(signal_tx), signal_rx, done_tx: Some(done_tx), done_rx }
+    }
+
+    pub fn handle(&self) -> Shutdown {
+        // Handles created after shutdown aren't waited upon
+        let done = match &self.done_tx {
+            Some(done_tx) => done_tx.clone(),
+            None => bounded(0).0,
+        };
+
+        Shutdown { signal: self.signal_rx.clone(), _done: done }
+    }
+
+    /// Signals subsystems of the stage to stop and waits for them till
+    /// `deadline`. Returns false if they didn't stop in time
+    pub async fn trigger(&mut self, deadline: Instant) -> bool {
+        self.signal_tx.take();
+        self.done_tx.take();
+
+        // Nothing is ever sent. Receiver errors out once all handles are dropped
+        time::timeout_at(deadline, self.done_rx.recv_async()).await.is_ok()
+    }
+}
@@ -0,0 +1,260 @@
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use flume::{Sender, TrySendError};
+use futures_util::future::BoxFuture;
+use futures_util::FutureExt;
+use log::{error, info};
+use rumqttc::{AsyncClient, ClientError, Publish, Request};
+use serde::Deserialize;
+use serde_json::json;
+use thiserror::Error;
+
+use crate::base::mqtt::Ack;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Sink can't take publishes right now")]
+    Full(Publish),
+    #[error("Sink is closed")]
+    Closed(Publish),
+    #[error("Failed to disconnect sink")]
+    Disconnect,
+}
+
+/// Local destination that publishes are written to, instead of a broker
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum SinkConfig {
+    /// JSON lines on standard output
+    Stdout,
+    /// JSON lines appended to a file
+    File { path: String },
+}
+
+/// Destination of publishes made by the serializer. Publishes handed over to
+/// sinks other than the broker are done once written. They are reported as
+/// written publishes with QoS 0, which aren't acknowledged any further
+pub trait Sink: Send + Sync {
+    /// Hands over a publish if the sink can take it right away
+    fn try_publish(&self, publish: Publish) -> Result<(), Error>;
+
+    /// Hands over a publish, waiting till the sink can take it
+    fn publish(&self, publish: Publish) -> BoxFuture<'_, Result<(), Error>>;
+
+    /// Hands over a publish that expires at `expiry`, in milliseconds since
+    /// unix epoch, waiting till the sink can take it. Sinks that don't expire
+    /// publishes hand it over like any other
+    fn publish_expiring(&self, publish: Publish, _expiry: u64) -> BoxFuture<'_, Result<(), Error>> {
+        self.publish(publish)
+    }
+
+    /// Disconnects from the broker after publishes handed over till now are
+    /// sent. Nothing to do for local sinks
+    fn disconnect(&self) -> BoxFuture<'_, Result<(), Error>> {
+        futures_util::future::ready(Ok(())).boxed()
+    }
+}
+
+impl Sink for AsyncClient {
+    fn try_publish(&self, publish: Publish) -> Result<(), Error> {
+        let Publish { topic, qos, retain, payload, .. } = publish;
+        match AsyncClient::try_publish(self, topic, qos, retain, payload.to_vec()) {
+            Ok(_) => Ok(()),
+            Err(ClientError::TryRequest(request)) => match request.into_inner() {
+                Request::Publish(publish) => Err(Error::Full(publish)),
+                request => unreachable!("{:?}", request),
+            },
+            Err(ClientError::Request(request)) => match request.into_inner() {
+                Request::Publish(publish) => Err(Error::Closed(publish)),
+                request => unreachable!("{:?}", request),
+            },
+        }
+    }
+
+    fn publish(&self, publish: Publish) -> BoxFuture<'_, Result<(), Error>> {
+        let Publish { topic, qos, retain, payload, .. } = publish;
+        async move {
+            match self.publish_bytes(topic, qos, retain, payload).await {
+                Ok(_) => Ok(()),
+                Err(ClientError::Request(request)) => match request.into_inner() {
+                    Request::Publish(publish) => Err(Error::Closed(publish)),
+                    request => unreachable!("{:?}", request),
+                },
+                Err(ClientError::TryRequest(request)) => match request.into_inner() {
+                    Request::Publish(publish) => Err(Error::Closed(publish)),
+                    request => unreachable!("{:?}", request),
+                },
+            }
+        }
+        .boxed()
+    }
+
+    fn disconnect(&self) -> BoxFuture<'_, Result<(), Error>> {
+        async move { AsyncClient::disconnect(self).await.map_err(|_| Error::Disconnect) }.boxed()
+    }
+}
+
+/// Writes publishes as JSON lines, e.g to bench test uplink without a broker
+pub struct WriterSink {
+    writer: Mutex<Box<dyn Write + Send>>,
+    acks: Sender<Ack>,
+}
+
+impl WriterSink {
+    pub fn new(config: &SinkConfig, acks: Sender<Ack>) -> io::Result<WriterSink> {
+        let writer: Box<dyn Write + Send> = match config {
+            SinkConfig::Stdout => Box::new(io::stdout()),
+            SinkConfig::File { path } => {
+                info!("Writing publishes to {}", path);
+                Box::new(OpenOptions::new().create(true).append(true).open(path)?)
+            }
+        };
+
+        Ok(WriterSink { writer: Mutex::new(writer), acks })
+    }
+
+    fn write(&self, publish: &Publish) -> io::Result<()> {
+        let timestamp =
+            SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or(Duration::from_secs(0));
+        // Payloads that aren't JSON, e.g compressed ones, are written lossily
+        let payload: serde_json::Value = serde_json::from_slice(&publish.payload)
+            .unwrap_or_else(|_| String::from_utf8_lossy(&publish.payload).into());
+        let line = json!({
+            "timestamp": timestamp.as_millis() as u64,
+            "topic": publish.topic,
+            "qos": publish.qos as u8,
+            "retain": publish.retain,
+            "size": publish.payload.len(),
+            "payload": payload,
+        });
+
+        let mut writer = self.writer.lock().unwrap();
+        writeln!(writer, "{}", line)?;
+        writer.flush()
+    }
+}
+
+impl Sink for WriterSink {
+    fn try_publish(&self, publish: Publish) -> Result<(), Error> {
+        if let Err(e) = self.write(&publish) {
+            error!("Failed to write publish to sink. Error = {:?}", e);
+            return Err(Error::Closed(publish));
+        }
+
+        let _ = self.acks.send(Ack::Outgoing(0));
+        Ok(())
+    }
+
+    fn publish(&self, publish: Publish) -> BoxFuture<'_, Result<(), Error>> {
+        futures_util::future::ready(self.try_publish(publish)).boxed()
+    }
+}
+
+/// Forwards publishes to a channel within the process, e.g for integration
+/// tests to assert on batches of data without a broker
+pub struct ChannelSink {
+    tx: Sender<Publish>,
+    acks: Sender<Ack>,
+}
+
+impl ChannelSink {
+    pub fn new(tx: Sender<Publish>, acks: Sender<Ack>) -> ChannelSink {
+        ChannelSink { tx, acks }
+    }
+}
+
+impl Sink for ChannelSink {
+    fn try_publish(&self, publish: Publish) -> Result<(), Error> {
+        match self.tx.try_send(publish) {
+            Ok(_) => {
+                let _ = self.acks.send(Ack::Outgoing(0));
+                Ok(())
+            }
+            Err(TrySendError::Full(publish)) => Err(Error::Full(publish)),
+            Err(TrySendError::Disconnected(publish)) => Err(Error::Closed(publish)),
+        }
+    }
+
+    fn publish(&self, publish: Publish) -> BoxFuture<'_, Result<(), Error>> {
+        async move {
+            match self.tx.send_async(publish).await {
+                Ok(_) => {
+                    let _ = self.acks.send(Ack::Outgoing(0));
+                    Ok(())
+                }
+                Err(e) => Err(Error::Closed(e.into_inner())),
+            }
+        }
+        .boxed()
+    }
+}
+
+/// Sink of publishes as configured, if not the broker
+pub fn open(config: &SinkConfig, acks: Sender<Ack>) -> io::Result<Arc<dyn Sink>> {
+    Ok(Arc::new(WriterSink::new(config, acks)?))
+}
+
+#[cfg(test)]
+mod test {
+    use flume::{bounded, unbounded};
+    use rumqttc::QoS;
+    use tempdir::TempDir;
+
+    use super::*;
+
+    fn publish(topic: &str, payload: &[u8]) -> Publish {
+        Publish::new(topic, QoS::AtLeastOnce, payload.to_vec())
+    }
+
+    #[tokio::test]
+    async fn publishes_are_written_to_file_as_json_lines() {
+        let dir = TempDir::new("sink").unwrap();
+        let path = dir.path().join("publishes.jsonl").to_str().unwrap().to_owned();
+        let (acks_tx, acks) = unbounded();
+        let sink = open(&SinkConfig::File { path: path.clone() }, acks_tx).unwrap();
+
+        sink.try_publish(publish("/can", br#"[{"sequence":1}]"#)).unwrap();
+        sink.publish(publish("/can/lz4", &[0xff, 0x00])).await.unwrap();
+        assert!(matches!(acks.try_recv(), Ok(Ack::Outgoing(0))));
+        assert!(matches!(acks.try_recv(), Ok(Ack::Outgoing(0))));
+
+        let lines: Vec<serde_json::Value> = std::fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["topic"], "/can");
+        assert_eq!(lines[0]["qos"], 1);
+        assert_eq!(lines[0]["retain"], false);
+        assert_eq!(lines[0]["payload"], json!([{ "sequence": 1 }]));
+        // Payloads that aren't JSON are written lossily
+        assert_eq!(lines[1]["size"], 2);
+        assert!(lines[1]["payload"].is_string());
+    }
+
+    #[tokio::test]
+    async fn channel_sink_is_full_or_closed_with_its_channel() {
+        let (tx, rx) = bounded(1);
+        let (acks_tx, acks) = unbounded();
+        let sink = ChannelSink::new(tx, acks_tx);
+
+        sink.try_publish(publish("/a", b"1")).unwrap();
+        match sink.try_publish(publish("/b", b"2")) {
+            Err(Error::Full(publish)) => assert_eq!(publish.topic, "/b"),
+            e => panic!("unexpected {:?}", e),
+        }
+        assert_eq!(rx.recv().unwrap().topic, "/a");
+        assert_eq!(acks.len(), 1);
+
+        drop(rx);
+        match sink.publish(publish("/c", b"3")).await {
+            Err(Error::Closed(publish)) => assert_eq!(publish.topic, "/c"),
+            e => panic!("unexpected {:?}", e),
+        }
+        assert_eq!(acks.len(), 1);
+    }
+}
@@ -0,0 +1,504 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bytes::BytesMut;
+use disk::Storage;
+use log::{info, warn};
+use rumqttc::{read, Packet, Publish};
+use thiserror::Error;
+
+use crate::base::link::LinkPolicy;
+use crate::base::{Config, Persistence};
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Io error {0}")]
+    Io(#[from] io::Error),
+    #[error("Mqtt error {0}")]
+    Mqtt(#[from] rumqttc::Error),
+    #[error("Packet was not expected {0:?}")]
+    UnexpectedPacket(Box<Packet>),
+}
+
+/// How data of a stream is persisted
+#[derive(Debug, Default, Clone, Copy)]
+struct Retention {
+    /// persisted data of higher priority is sent first and deleted last
+    priority: u8,
+    /// persisted data older than this is dropped
+    max_age: Option<Duration>,
+}
+
+/// Publish read from one of the storages, to commit it by once the broker
+/// acknowledges it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Record {
+    /// index of the storage it was read from
+    pub storage: usize,
+    /// number of publishes read from that storage before it
+    pub sequence: u64,
+    /// time, in milliseconds since unix epoch, past which the publish is
+    /// dropped instead of being sent
+    pub expiry: Option<u64>,
+}
+
+/// Storage of a stream, or of all the streams that aren't configured
+struct StreamStorage {
+    name: String,
+    storage: Storage,
+    /// number of publishes read from storage in this session
+    read: u64,
+    /// publish that couldn't be sent, handed out again before reading further
+    rewound: Option<(Record, Publish)>,
+    retention: Retention,
+    /// number of publishes caught up in each turn of this storage
+    weight: usize,
+    /// data is held till uplink is on a preferred link
+    bulk: bool,
+}
+
+/// Persists data of every configured stream in a `Storage` of its own, in a
+/// directory named after the stream, so that data of one stream doesn't wait
+/// behind backlog of another during catchup. Streams that aren't configured
+/// share a storage in the persistence directory. All the storages share the
+/// configured file count and disk usage budget, deleting data of lowest
+/// priority streams first
+pub struct Storages {
+    /// storages sorted by priority, highest first
+    storages: Vec<StreamStorage>,
+    /// index of storage of every configured stream, by topic
+    topics: HashMap<String, usize>,
+    /// index of storage shared by streams that aren't configured
+    default: usize,
+    max_file_count: usize,
+    max_disk_usage: Option<usize>,
+    /// storage being caught up and number of publishes left in its turn
+    turn: usize,
+    credits: usize,
+    /// data of bulk streams isn't caught up
+    hold_bulk: bool,
+}
+
+impl Storages {
+    pub fn new(config: &Config, persistence: &Persistence) -> io::Result<Storages> {
+        let max_age = persistence.max_age.map(Duration::from_secs);
+        let path = Path::new(&persistence.path);
+
+        let mut storages = vec![];
+        let mut streams: Vec<_> = config.streams.iter().collect();
+        streams.sort_unstable_by_key(|(name, stream)| (u8::MAX - stream.priority, *name));
+        for (name, stream) in streams {
+            let max_age = stream.max_age.map(Duration::from_secs).or(max_age);
+            let retention = Retention { priority: stream.priority, max_age };
+            let weight = stream.weight.unwrap_or(1).max(1);
+            let bulk = stream.link == LinkPolicy::Bulk;
+            let storage = open(&path.join(name), persistence, max_age)?;
+            let name = name.to_owned();
+            storages.push(StreamStorage {
+                name,
+                storage,
+                read: 0,
+                rewound: None,
+                retention,
+                weight,
+                bulk,
+            });
+        }
+
+        let retention = Retention { priority: 0, max_age };
+        let storage = open(path, persistence, max_age)?;
+        let name = "default".to_owned();
+        storages.push(StreamStorage {
+            name,
+            storage,
+            read: 0,
+            rewound: None,
+            retention,
+            weight: 1,
+            bulk: false,
+        });
+
+        let mut topics = HashMap::new();
+        for (index, stream) in storages.iter().enumerate() {
+            // Payloads of streams are written after being compressed
+            if let Some(config) = config.streams.get(&stream.name) {
+                topics.insert(config.compression.topic(&config.topic), index);
+            }
+        }
+
+        let default = storages.len() - 1;
+        let credits = storages[0].weight;
+
+        Ok(Storages {
+            storages,
+            topics,
+            default,
+            max_file_count: persistence.max_file_count,
+            max_disk_usage: persistence.max_disk_usage,
+            turn: 0,
+            credits,
+            hold_bulk: false,
+        })
+    }
+
+    /// Holds data of bulk streams in storage, or releases it to be caught up.
+    /// Returns true if held data is released
+    pub fn hold_bulk(&mut self, hold: bool) -> bool {
+        let released = self.hold_bulk && !hold;
+        self.hold_bulk = hold;
+        released
+    }
+
+    /// Checks if data published on `topic` is to be held in storage
+    pub fn is_held(&self, topic: &str) -> bool {
+        match self.topics.get(topic) {
+            Some(&index) => self.hold_bulk && self.storages[index].bulk,
+            None => false,
+        }
+    }
+
+    /// Sum of a statistic of all the storages
+    pub fn sum(&self, stat: impl Fn(&Storage) -> usize) -> usize {
+        self.storages.iter().map(|s| stat(&s.storage)).sum()
+    }
+
+    /// Serializes a publish and writes it into storage of its stream. Fails
+    /// if it is larger than a segment of the stream
+    pub fn write(&mut self, publish: &Publish) -> Result<usize, Error> {
+        let index = self.index(&publish.topic);
+        let stream = &mut self.storages[index];
+
+        let mut record = BytesMut::new();
+        let size = publish.write(&mut record)?;
+        stream.storage.write(&record, stream.retention.priority, stream.retention.max_age)?;
+        Ok(size)
+    }
+
+    /// Flushes write buffers that are full to disk. Returns number of
+    /// segments lost to stay within budget
+    pub fn flush_on_overflow(&mut self) -> io::Result<usize> {
+        let mut lost = 0;
+        for stream in self.storages.iter_mut() {
+            if stream.storage.flush_on_overflow()?.is_some() {
+                lost += 1;
+            }
+        }
+
+        Ok(lost + self.enforce_budget()?)
+    }
+
+    /// Flushes write buffers of all the storages to disk. Returns number
+    /// of segments lost to stay within budget
+    pub fn flush_all(&mut self) -> io::Result<usize> {
+        let mut lost = 0;
+        for stream in self.storages.iter_mut() {
+            if stream.storage.flush_all()?.is_some() {
+                lost += 1;
+            }
+        }
+
+        Ok(lost + self.enforce_budget()?)
+    }
+
+    fn over_budget(&self) -> bool {
+        if self.sum(Storage::file_count) > self.max_file_count {
+            return true;
+        }
+
+        matches!(self.max_disk_usage, Some(max) if self.sum(Storage::disk_usage) > max)
+    }
+
+    /// Deletes oldest segments of the lowest priority stream, with the most
+    /// segments, until all the storages together are within budget
+    fn enforce_budget(&mut self) -> io::Result<usize> {
+        let mut lost = 0;
+        while self.over_budget() {
+            let victim = self
+                .storages
+                .iter_mut()
+                .filter(|s| s.storage.file_count() > 0)
+                .min_by_key(|s| (s.retention.priority, usize::MAX - s.storage.file_count()));
+
+            let victim = match victim {
+                Some(victim) => victim,
+                None => break,
+            };
+
+            warn!("Persistence budget exceeded. Deleting oldest segment of {}", victim.name);
+            // Only segments that are being caught up are left
+            if victim.storage.delete_oldest()?.is_none() {
+                break;
+            }
+            lost += 1;
+        }
+
+        Ok(lost)
+    }
+
+    /// Reads next publish to catch up, along with the record to commit it by.
+    /// Storages take turns, highest priority first, handing out as many
+    /// publishes as their weight in a turn. Storages of bulk streams are
+    /// skipped while they are held and publishes that expired since they were
+    /// persisted are committed without being handed out. Returns `None` once
+    /// all the storages are caught up
+    pub fn next(&mut self, max_packet_size: usize) -> Result<Option<(Record, Publish)>, Error> {
+        let mut caught_up = 0;
+
+        loop {
+            if self.credits == 0 {
+                self.turn = (self.turn + 1) % self.storages.len();
+                self.credits = self.storages[self.turn].weight;
+            }
+
+            let stream = &mut self.storages[self.turn];
+            let held = self.hold_bulk && stream.bulk;
+            if !held {
+                if let Some((record, publish)) = stream.rewound.take() {
+                    self.credits -= 1;
+                    return Ok(Some((record, publish)));
+                }
+            }
+
+            if held || stream.storage.reload_on_eof()? {
+                self.credits = 0;
+                caught_up += 1;
+                if caught_up == self.storages.len() {
+                    return Ok(None);
+                }
+
+                continue;
+            }
+
+            let packet = read(stream.storage.reader(), max_packet_size)?;
+            // Every record in storage is a publish, read in the order storage hands them out
+            let sequence = stream.read;
+            stream.read += 1;
+            let expiry = match stream.storage.take_expiry() {
+                0 => None,
+                expiry if expiry <= now() => {
+                    stream.storage.commit(sequence)?;
+                    continue;
+                }
+                expiry => Some(expiry),
+            };
+
+            self.credits -= 1;
+            let record = Record { storage: self.turn, sequence, expiry };
+            match packet {
+                Packet::Publish(publish) => return Ok(Some((record, publish))),
+                packet => return Err(Error::UnexpectedPacket(Box::new(packet))),
+            }
+        }
+    }
+
+    /// Index of the storage that data published on `topic` is persisted in
+    fn index(&self, topic: &str) -> usize {
+        self.topics.get(topic).copied().unwrap_or(self.default)
+    }
+
+    /// Name of the stream whose data is persisted in storage at `index`
+    pub fn name(&self, index: usize) -> &str {
+        &self.storages[index].name
+    }
+
+    /// Hands out a publish read from storage again, the next time its storage
+    /// is caught up, when it couldn't be sent. Its record stays the same
+    pub fn rewind(&mut self, record: Record, publish: Publish) {
+        self.storages[record.storage].rewound = Some((record, publish));
+    }
+
+    /// Commits a publish read from storage, once the broker acknowledges it
+    pub fn commit(&mut self, record: Record) -> io::Result<()> {
+        self.storages[record.storage].storage.commit(record.sequence)
+    }
+}
+
+fn now() -> u64 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    now.as_millis() as u64
+}
+
+/// Opens storage in given directory, creating it if necessary. File count and
+/// disk usage budget is enforced across storages and not by individual storages
+fn open(path: &Path, persistence: &Persistence, max_age: Option<Duration>) -> io::Result<Storage> {
+    std::fs::create_dir_all(path)?;
+    let mut storage = Storage::new(path, persistence.max_file_size, usize::MAX)?;
+    storage.set_compression(persistence.compression);
+    storage.set_min_free_space(persistence.min_free_space);
+    storage.set_max_age(max_age);
+    info!("Opened persistence at {:?}", path);
+
+    let migrated = storage.migrate(legacy_record)?;
+    if migrated > 0 {
+        info!("Migrated {} segments of previous versions at {:?}", migrated, path);
+    }
+
+    Ok(storage)
+}
+
+/// Takes next publish off the contents of a segment written before records
+/// were framed, as a record of its own
+fn legacy_record(buf: &mut BytesMut) -> Option<BytesMut> {
+    match read(buf, usize::MAX) {
+        Ok(Packet::Publish(publish)) => {
+            let mut record = BytesMut::new();
+            publish.write(&mut record).ok()?;
+            Some(record)
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rumqttc::QoS;
+    use serde_json::json;
+    use tempdir::TempDir;
+
+    fn config(streams: serde_json::Value) -> Config {
+        Config { streams: serde_json::from_value(streams).unwrap(), ..Default::default() }
+    }
+
+    fn persistence(backup: &TempDir, max_file_count: usize) -> Persistence {
+        Persistence {
+            path: backup.path().to_str().unwrap().to_owned(),
+            max_file_size: 1024,
+            max_file_count,
+            compression: Default::default(),
+            max_disk_usage: None,
+            min_free_space: None,
+            max_age: None,
+        }
+    }
+
+    fn write(storages: &mut Storages, topic: &str, count: usize) {
+        for i in 0..count {
+            let mut publish = Publish::new(topic, QoS::AtLeastOnce, vec![i as u8; 100]);
+            publish.pkid = 1;
+            storages.write(&publish).unwrap();
+        }
+    }
+
+    fn topics(storages: &mut Storages) -> Vec<String> {
+        let mut topics = vec![];
+        while let Some((_, publish)) = storages.next(1024).unwrap() {
+            topics.push(publish.topic);
+        }
+
+        topics
+    }
+
+    #[test]
+    fn storages_take_turns_by_weight() {
+        let backup = TempDir::new("/tmp/storages").unwrap();
+        let config = config(json!({
+            "a": { "topic": "/a", "buf_size": 1, "weight": 2 },
+            "b": { "topic": "/b", "buf_size": 1 },
+        }));
+        let mut storages = Storages::new(&config, &persistence(&backup, 10)).unwrap();
+
+        write(&mut storages, "/a", 4);
+        write(&mut storages, "/b", 4);
+        write(&mut storages, "/c", 4);
+
+        let expected = ["/a", "/a", "/b", "/c", "/a", "/a", "/b", "/c", "/b", "/c", "/b", "/c"];
+        assert_eq!(topics(&mut storages), expected);
+    }
+
+    #[test]
+    fn higher_priority_stream_is_caught_up_first() {
+        let backup = TempDir::new("/tmp/storages").unwrap();
+        let config = config(json!({
+            "low": { "topic": "/low", "buf_size": 1 },
+            "high": { "topic": "/high", "buf_size": 1, "priority": 1 },
+        }));
+        let mut storages = Storages::new(&config, &persistence(&backup, 10)).unwrap();
+
+        write(&mut storages, "/low", 1);
+        write(&mut storages, "/high", 1);
+        assert_eq!(topics(&mut storages), ["/high", "/low"]);
+    }
+
+    #[test]
+    fn budget_is_shared_across_storages() {
+        let backup = TempDir::new("/tmp/storages").unwrap();
+        let config = config(json!({
+            "high": { "topic": "/high", "buf_size": 1, "priority": 1 },
+        }));
+        let mut storages = Storages::new(&config, &persistence(&backup, 2)).unwrap();
+
+        // A segment of each storage, twice
+        for _ in 0..2 {
+            write(&mut storages, "/high", 1);
+            write(&mut storages, "/c", 1);
+            storages.flush_all().unwrap();
+        }
+
+        // Segments of the lower priority storage are deleted first
+        assert_eq!(storages.sum(Storage::file_count), 2);
+        assert_eq!(topics(&mut storages), ["/high", "/high"]);
+    }
+
+    #[test]
+    fn held_bulk_streams_are_not_caught_up() {
+        let backup = TempDir::new("/tmp/storages").unwrap();
+        let config = config(json!({
+            "bulk": { "topic": "/bulk", "buf_size": 1, "link": "bulk" },
+        }));
+        let mut storages = Storages::new(&config, &persistence(&backup, 10)).unwrap();
+        storages.hold_bulk(true);
+        assert!(storages.is_held("/bulk"));
+
+        write(&mut storages, "/bulk", 1);
+        write(&mut storages, "/c", 1);
+        assert_eq!(topics(&mut storages), ["/c"]);
+
+        assert!(storages.hold_bulk(false));
+        assert_eq!(topics(&mut storages), ["/bulk"]);
+    }
+
+    #[test]
+    fn rewound_publish_is_handed_out_again() {
+        let backup = TempDir::new("/tmp/storages").unwrap();
+        let mut storages = Storages::new(&config(json!({})), &persistence(&backup, 10)).unwrap();
+        write(&mut storages, "/a", 1);
+        write(&mut storages, "/b", 1);
+        storages.flush_all().unwrap();
+
+        let (record, publish) = storages.next(1024).unwrap().unwrap();
+        assert_eq!(publish.topic, "/a");
+        storages.rewind(record, publish);
+
+        // Record stays the same, to be committed by once acknowledged
+        let (rewound, publish) = storages.next(1024).unwrap().unwrap();
+        assert_eq!((rewound, publish.topic.as_str()), (record, "/a"));
+        let (next, publish) = storages.next(1024).unwrap().unwrap();
+        assert_eq!((next.sequence, publish.topic.as_str()), (1, "/b"));
+        assert!(storages.next(1024).unwrap().is_none());
+    }
+
+    #[test]
+    fn publishes_that_expire_during_catchup_are_dropped() {
+        let backup = TempDir::new("/tmp/storages").unwrap();
+        let mut storages = Storages::new(&config(json!({})), &persistence(&backup, 10)).unwrap();
+        storages.storages[0].retention.max_age = Some(Duration::from_millis(100));
+        write(&mut storages, "/a", 2);
+        storages.flush_all().unwrap();
+
+        let start = now();
+        let (record, _) = storages.next(1024).unwrap().unwrap();
+        let expiry = record.expiry.unwrap();
+        assert!(expiry > start && expiry <= start + 100);
+
+        // Second publish expires after its segment is loaded, before it is sent
+        std::thread::sleep(Duration::from_millis(150));
+        assert!(storages.next(1024).unwrap().is_none());
+
+        // Dropped publish is committed along with the one that was sent
+        storages.commit(record).unwrap();
+        assert_eq!(storages.sum(Storage::file_count), 0);
+    }
+}
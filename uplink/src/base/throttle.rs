@@ -0,0 +1,152 @@
+use std::collections::HashMap;
+
+use tokio::time::{Duration, Instant};
+
+use crate::base::Config;
+
+/// Token bucket that refills at `rate` bytes per second, upto a second worth
+/// of tokens. Tokens are reserved even when there aren't enough of them, which
+/// makes the ones who come later wait longer
+#[derive(Debug)]
+struct Bucket {
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl Bucket {
+    fn new(rate: usize) -> Bucket {
+        let rate = rate.max(1) as f64;
+        Bucket { rate, tokens: rate, last: Instant::now() }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.last = now;
+    }
+
+    /// Time till `size` bytes can be sent without going over the rate
+    fn delay(&mut self, size: usize) -> Duration {
+        self.refill();
+        let deficit = size as f64 - self.tokens;
+        if deficit <= 0.0 {
+            return Duration::ZERO;
+        }
+
+        Duration::from_secs_f64(deficit / self.rate)
+    }
+
+    /// Takes tokens for `size` bytes and returns time to wait before sending them
+    fn reserve(&mut self, size: usize) -> Duration {
+        self.refill();
+        self.tokens -= size as f64;
+        if self.tokens >= 0.0 {
+            return Duration::ZERO;
+        }
+
+        Duration::from_secs_f64(-self.tokens / self.rate)
+    }
+}
+
+/// Limits bandwidth used for publishing data, across all streams and for
+/// individual streams, by topic
+#[derive(Debug)]
+pub struct Throttle {
+    global: Option<Bucket>,
+    streams: HashMap<String, Bucket>,
+}
+
+impl Throttle {
+    pub fn new(config: &Config) -> Throttle {
+        let global = config.max_bandwidth.map(Bucket::new);
+        let streams = config
+            .streams
+            .values()
+            .filter_map(|s| {
+                let rate = s.max_bandwidth?;
+                Some((s.compression.topic(&s.topic), Bucket::new(rate)))
+            })
+            .collect();
+
+        Throttle { global, streams }
+    }
+
+    /// Time till a publish of `size` bytes on `topic` is within limits. Nothing
+    /// is reserved
+    pub fn delay(&mut self, topic: &str, size: usize) -> Duration {
+        let global = self.global.as_mut().map_or(Duration::ZERO, |b| b.delay(size));
+        let stream = self.streams.get_mut(topic).map_or(Duration::ZERO, |b| b.delay(size));
+        global.max(stream)
+    }
+
+    /// Accounts for a publish of `size` bytes on `topic`. Returns time to wait
+    /// before sending it to stay within limits
+    pub fn reserve(&mut self, topic: &str, size: usize) -> Duration {
+        let global = self.global.as_mut().map_or(Duration::ZERO, |b| b.reserve(size));
+        let stream = self.streams.get_mut(topic).map_or(Duration::ZERO, |b| b.reserve(size));
+        global.max(stream)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::*;
+
+    fn assert_near(delay: Duration, secs: f64) {
+        let diff = (delay.as_secs_f64() - secs).abs();
+        assert!(diff < 0.05, "{:?} isn't close to {}s", delay, secs);
+    }
+
+    #[test]
+    fn reserved_tokens_delay_later_publishes() {
+        let mut bucket = Bucket::new(1000);
+        assert_eq!(bucket.delay(1000), Duration::ZERO);
+        assert_eq!(bucket.reserve(1000), Duration::ZERO);
+
+        // Bucket is empty, tokens for this one are borrowed from the next half second
+        assert_near(bucket.delay(500), 0.5);
+        assert_near(bucket.reserve(500), 0.5);
+        assert_near(bucket.delay(100), 0.6);
+        assert_near(bucket.reserve(100), 0.6);
+    }
+
+    #[test]
+    fn bucket_refills_upto_a_second_of_tokens() {
+        let mut bucket = Bucket::new(1000);
+        bucket.last -= Duration::from_secs(10);
+        assert_eq!(bucket.reserve(1000), Duration::ZERO);
+        assert_near(bucket.delay(1), 0.001);
+
+        bucket.last -= Duration::from_millis(500);
+        assert_eq!(bucket.reserve(500), Duration::ZERO);
+        assert!(bucket.delay(1) > Duration::ZERO);
+    }
+
+    #[test]
+    fn stricter_of_global_and_stream_limits_applies() {
+        let streams = json!({
+            "can": { "topic": "/can", "buf_size": 10, "max_bandwidth": 100 },
+            "gps": { "topic": "/gps", "buf_size": 10, "compression": "lz4", "max_bandwidth": 100 }
+        });
+        let config = Config {
+            streams: serde_json::from_value(streams).unwrap(),
+            max_bandwidth: Some(1000),
+            ..Default::default()
+        };
+        let mut throttle = Throttle::new(&config);
+
+        assert_eq!(throttle.reserve("/can", 100), Duration::ZERO);
+        assert_near(throttle.delay("/can", 50), 0.5);
+        // Compressed payloads are throttled by the topic they are sent to
+        assert_eq!(throttle.delay("/gps", 100), Duration::ZERO);
+        assert_near(throttle.delay("/gps/lz4", 150), 0.5);
+
+        assert_eq!(throttle.reserve("/other", 900), Duration::ZERO);
+        assert_near(throttle.delay("/other", 100), 0.1);
+        assert_near(throttle.delay("/can", 50), 0.5);
+    }
+}
@@ -0,0 +1,440 @@
+//! Inspects persistence directories of uplink offline. Lists segments, decodes
+//! the publishes in them, verifies their integrity, exports them as JSON lines
+//! and replays them to a broker. Segments are never modified. Segments of
+//! streams persisted in directories of their own are included, named after
+//! their directory, e.g `action_status/backup@0@3`.
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use anyhow::Error;
+use bytes::BytesMut;
+use disk::inspect::{self, Record, Segment};
+use flume::{Receiver, Sender};
+use rumqttc::{AsyncClient, Event, Incoming, MqttOptions, Outgoing, Packet, Publish};
+use serde_json::json;
+use structopt::StructOpt;
+use tokio::{select, task};
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "disk", about = "inspect, export and replay uplink persistence")]
+struct CommandLine {
+    /// persistence directory
+    #[structopt(short = "d", long = "dir", default_value = "/tmp/uplink")]
+    dir: PathBuf,
+    #[structopt(subcommand)]
+    command: Command,
+}
+
+#[derive(StructOpt, Debug)]
+enum Command {
+    /// List segments in the order uplink sends them
+    List,
+    /// Print publishes with a preview of their payload
+    Show {
+        /// only show publishes of this segment, e.g backup@0@3 or action_status/backup@0@3
+        #[structopt(short = "s", long = "segment")]
+        segment: Option<String>,
+        /// number of payload characters to preview
+        #[structopt(short = "p", long = "preview", default_value = "80")]
+        preview: usize,
+    },
+    /// Verify checksums of records and look for torn segments
+    Check,
+    /// Export publishes as JSON lines
+    Export {
+        /// output file, stdout if not given
+        #[structopt(short = "o", long = "output")]
+        output: Option<PathBuf>,
+    },
+    /// Publish valid, unexpired records to a broker
+    Replay {
+        #[structopt(long = "host")]
+        host: String,
+        #[structopt(long = "port", default_value = "1883")]
+        port: u16,
+        #[structopt(long = "client-id", default_value = "uplink-replay")]
+        client_id: String,
+        /// number of records to skip, to resume a replay that stopped midway
+        #[structopt(long = "skip", default_value = "0")]
+        skip: usize,
+    },
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    let commandline: CommandLine = StructOpt::from_args();
+    let segments = segments(&commandline.dir)?;
+
+    match commandline.command {
+        Command::List => list(&segments),
+        Command::Show { segment, preview } => show(&segments, segment, preview),
+        Command::Check => check(&segments),
+        Command::Export { output } => match output {
+            Some(path) => export(&segments, BufWriter::new(File::create(path)?)),
+            None => export(&segments, io::stdout().lock()),
+        },
+        Command::Replay { host, port, client_id, skip } => {
+            replay(segments, &host, port, &client_id, skip).await
+        }
+    }
+}
+
+/// Segments of the persistence directory and of every stream directory in
+/// it, along with their names relative to the persistence directory
+fn segments(dir: &Path) -> Result<Vec<(String, Segment)>, Error> {
+    let mut dirs = vec![];
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            dirs.push(path);
+        }
+    }
+    dirs.sort();
+
+    let mut segments = vec![];
+    for dir in std::iter::once(dir.to_owned()).chain(dirs) {
+        segments.extend(inspect::segments(&dir)?);
+    }
+
+    let segments = segments
+        .into_iter()
+        .map(|segment| {
+            let name = segment.path.strip_prefix(dir).unwrap_or(&segment.path);
+            (name.display().to_string(), segment)
+        })
+        .collect();
+
+    Ok(segments)
+}
+
+/// Decodes a record into the publish it was serialized from
+fn decode(record: &Record) -> Result<Publish, Error> {
+    let mut buf = BytesMut::from(&record.data[..]);
+    match rumqttc::read(&mut buf, record.data.len())? {
+        Packet::Publish(publish) => Ok(publish),
+        packet => Err(Error::msg(format!("Unexpected packet {:?}", packet))),
+    }
+}
+
+/// Payload of a publish as JSON, or as a string if it isn't JSON
+fn payload(publish: &Publish) -> serde_json::Value {
+    serde_json::from_slice(&publish.payload)
+        .unwrap_or_else(|_| String::from_utf8_lossy(&publish.payload).into())
+}
+
+fn list(segments: &[(String, Segment)]) -> Result<(), Error> {
+    println!("{:<24} {:>8} {:>12} {:>6} {:>10}", "segment", "priority", "size", "codec", "age");
+    for (name, segment) in segments {
+        let age = SystemTime::now().duration_since(segment.modified).unwrap_or_default();
+        println!(
+            "{:<24} {:>8} {:>12} {:>6} {:>9}s",
+            name,
+            segment.priority,
+            segment.size,
+            format!("{:?}", segment.compression).to_lowercase(),
+            age.as_secs()
+        );
+    }
+
+    println!("{} segments", segments.len());
+    Ok(())
+}
+
+fn show(
+    segments: &[(String, Segment)],
+    filter: Option<String>,
+    preview: usize,
+) -> Result<(), Error> {
+    for (name, segment) in segments {
+        if matches!(&filter, Some(filter) if filter != name) {
+            continue;
+        }
+
+        println!("{}", name);
+        for record in segment.records()? {
+            let record = match record {
+                Ok(record) => record,
+                Err(e) => {
+                    println!("    {}", e);
+                    break;
+                }
+            };
+
+            if !record.valid {
+                println!("    corrupt record of size {}", record.data.len());
+                continue;
+            }
+
+            let publish = match decode(&record) {
+                Ok(publish) => publish,
+                Err(e) => {
+                    println!("    undecodable record. Error = {}", e);
+                    continue;
+                }
+            };
+
+            let payload = String::from_utf8_lossy(&publish.payload);
+            let payload: String = payload.chars().take(preview).collect();
+            let expired = if record.is_expired() { " (expired)" } else { "" };
+            println!(
+                "    {} [{} bytes]{} {}",
+                publish.topic,
+                publish.payload.len(),
+                expired,
+                payload
+            );
+        }
+    }
+
+    Ok(())
+}
+
+fn check(segments: &[(String, Segment)]) -> Result<(), Error> {
+    let mut healthy = true;
+    for (name, segment) in segments {
+        let (mut records, mut corrupt, mut expired) = (0, 0, 0);
+        let mut torn = None;
+        for record in segment.records()? {
+            match record {
+                Ok(record) if !record.valid => corrupt += 1,
+                Ok(record) if record.is_expired() => expired += 1,
+                Ok(_) => records += 1,
+                Err(e) => torn = Some(e),
+            }
+        }
+
+        let status = match (&torn, corrupt) {
+            (None, 0) => "ok",
+            _ => {
+                healthy = false;
+                "damaged"
+            }
+        };
+
+        println!(
+            "{:<24} {:<8} valid = {}, corrupt = {}, expired = {}{}",
+            name,
+            status,
+            records,
+            corrupt,
+            expired,
+            torn.map(|e| format!(", {}", e)).unwrap_or_default()
+        );
+    }
+
+    if !healthy {
+        return Err(Error::msg("Found damaged segments"));
+    }
+
+    Ok(())
+}
+
+/// Writes valid records as lines of JSON. Undecodable records are skipped,
+/// for whatever can be read of a damaged backlog to still be exported
+fn export<W: Write>(segments: &[(String, Segment)], mut writer: W) -> Result<(), Error> {
+    let mut undecodable = 0;
+    for (name, segment) in segments {
+        for record in segment.records()? {
+            let record = match record {
+                Ok(record) if record.valid => record,
+                _ => continue,
+            };
+
+            let publish = match decode(&record) {
+                Ok(publish) => publish,
+                Err(e) => {
+                    eprintln!("Skipping undecodable record of {}. Error = {}", name, e);
+                    undecodable += 1;
+                    continue;
+                }
+            };
+
+            let line = json!({
+                "segment": name,
+                "topic": publish.topic,
+                "expiry": record.expiry,
+                "payload": payload(&publish),
+            });
+
+            serde_json::to_writer(&mut writer, &line)?;
+            writer.write_all(b"\n")?;
+        }
+    }
+
+    writer.flush()?;
+    if undecodable > 0 {
+        eprintln!("Skipped {} undecodable records", undecodable);
+    }
+
+    Ok(())
+}
+
+/// Publishes valid, unexpired records to a broker, reading them as they are
+/// published. Records are numbered in the order they are listed, the first
+/// `skip` of them aren't published. If replay stops midway, it can be resumed
+/// from the first record that the broker hasn't acknowledged yet
+async fn replay(
+    segments: Vec<(String, Segment)>,
+    host: &str,
+    port: u16,
+    client_id: &str,
+    skip: usize,
+) -> Result<(), Error> {
+    let options = MqttOptions::new(client_id, host, port);
+    let (client, mut eventloop) = AsyncClient::new(options, 10);
+    let (positions_tx, positions_rx) = flume::unbounded();
+    let mut publisher = task::spawn(publish(client, segments, skip, positions_tx));
+    let mut progress = Progress::new(skip, positions_rx);
+    let mut published = false;
+
+    loop {
+        select! {
+            event = eventloop.poll() => match event {
+                Ok(event) => progress.update(event),
+                Err(e) => {
+                    eprintln!("Replay stopped. Resume with --skip {}", progress.resume());
+                    return Err(e.into());
+                }
+            },
+            o = &mut publisher, if !published => {
+                if let Err(e) = o.map_err(Error::from).and_then(|o| o) {
+                    eprintln!("Replay stopped. Resume with --skip {}", progress.resume());
+                    return Err(e);
+                }
+
+                published = true;
+            }
+        }
+
+        // Done only after broker has acknowledged every publish
+        if published && progress.is_done() {
+            break;
+        }
+    }
+
+    println!("Replayed {} publishes to {}:{}", progress.acked, host, port);
+    Ok(())
+}
+
+/// Reads records of the segments and publishes them, sending position of every
+/// publish before handing it over to the eventloop
+async fn publish(
+    client: AsyncClient,
+    segments: Vec<(String, Segment)>,
+    skip: usize,
+    positions: Sender<usize>,
+) -> Result<(), Error> {
+    let mut position = 0;
+    for (name, segment) in segments {
+        for record in segment.records()? {
+            let current = position;
+            position += 1;
+            let record = match record {
+                Ok(record) if current >= skip && record.valid && !record.is_expired() => record,
+                _ => continue,
+            };
+
+            let publish = match decode(&record) {
+                Ok(publish) => publish,
+                Err(e) => {
+                    eprintln!("Skipping undecodable record of {}. Error = {}", name, e);
+                    continue;
+                }
+            };
+
+            // Sent with QoS and retain flag it was persisted with
+            positions.send(current)?;
+            let Publish { topic, qos, retain, payload, .. } = publish;
+            client.publish_bytes(topic, qos, retain, payload).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Tracks positions of publishes handed over to the eventloop till the broker
+/// acknowledges them
+struct Progress {
+    /// positions of publishes in the order they are handed over
+    positions: Receiver<usize>,
+    /// position after the last publish handed over
+    next: usize,
+    /// publishes that are yet to be written to the network, in order
+    outgoing: VecDeque<usize>,
+    /// publishes waiting for an acknowledgement, by pkid
+    pending: HashMap<u16, usize>,
+    acked: usize,
+    /// acknowledged publishes when progress was last reported
+    reported: usize,
+}
+
+impl Progress {
+    fn new(skip: usize, positions: Receiver<usize>) -> Progress {
+        Progress {
+            positions,
+            next: skip,
+            outgoing: VecDeque::new(),
+            pending: HashMap::new(),
+            acked: 0,
+            reported: 0,
+        }
+    }
+
+    /// Takes positions of publishes handed over since last time
+    fn receive(&mut self) {
+        for position in self.positions.try_iter() {
+            self.outgoing.push_back(position);
+            self.next = position + 1;
+        }
+    }
+
+    fn update(&mut self, event: Event) {
+        self.receive();
+        match event {
+            Event::Outgoing(Outgoing::Publish(pkid)) => {
+                let position = match self.outgoing.pop_front() {
+                    Some(position) => position,
+                    None => return,
+                };
+
+                // Publishes with QoS 0 aren't acknowledged
+                match pkid {
+                    0 => self.acked += 1,
+                    pkid => {
+                        self.pending.insert(pkid, position);
+                    }
+                }
+            }
+            Event::Incoming(Incoming::PubAck(ack)) => self.ack(ack.pkid),
+            Event::Incoming(Incoming::PubComp(comp)) => self.ack(comp.pkid),
+            _ => {}
+        }
+    }
+
+    fn ack(&mut self, pkid: u16) {
+        if self.pending.remove(&pkid).is_some() {
+            self.acked += 1;
+            if self.acked >= self.reported + 1000 {
+                self.reported = self.acked;
+                let resume = self.resume();
+                println!("Acknowledged {} publishes. Resume with --skip {}", self.acked, resume);
+            }
+        }
+    }
+
+    /// Position of the first publish that isn't acknowledged. Every record
+    /// before it is acknowledged or isn't to be published
+    fn resume(&mut self) -> usize {
+        self.receive();
+        let outgoing = self.outgoing.iter().copied();
+        outgoing.chain(self.pending.values().copied()).min().unwrap_or(self.next)
+    }
+
+    fn is_done(&mut self) -> bool {
+        self.receive();
+        self.outgoing.is_empty() && self.pending.is_empty()
+    }
+}
@@ -1,36 +1,32 @@
+use crate::base::shutdown::Shutdown;
 use crate::base::{Config, Package, Stream};
 use flume::Sender;
+use log::error;
 use serde::Serialize;
 use serde_json::json;
-use std::io;
 use std::sync::Arc;
-use thiserror::Error;
-
-#[derive(Error, Debug)]
-pub enum Error {
-    #[error("Io error {0}")]
-    Io(#[from] io::Error),
-    #[error("Serde error {0}")]
-    Json(#[from] serde_json::error::Error),
-}
 
 pub struct Simulator {
     config: Arc<Config>,
     partitions: HashMap<String, Stream<Payload>>,
     data_tx: Sender<Box<dyn Package>>,
+    shutdown: Shutdown,
 }
 
 impl Simulator {
-    pub fn new(config: Arc<Config>, data_tx: Sender<Box<dyn Package>>) -> Self {
+    pub fn new(config: Arc<Config>, data_tx: Sender<Box<dyn Package>>, shutdown: Shutdown) -> Self {
         let mut partitions = HashMap::new();
         for (stream, config) in config.streams.clone() {
-            partitions.insert(
-                stream.clone(),
-                Stream::new(stream, config.topic, config.buf_size, data_tx.clone()),
-            );
+            match Stream::with_config(stream.clone(), &config, data_tx.clone()) {
+                Ok(partition) => partitions.insert(stream, partition),
+                Err(e) => {
+                    error!("Failed to create {} stream. Error = {}", stream, e);
+                    continue;
+                }
+            };
         }
 
-        Simulator { config, partitions, data_tx }
+        Simulator { config, partitions, data_tx, shutdown }
     }
 
     pub async fn start(&mut self) {
@@ -40,6 +36,13 @@ impl Simulator {
             SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64;
 
         for i in 0..1_000_000 {
+            if self.shutdown.is_triggered() {
+                for partition in self.partitions.values_mut() {
+                    partition.flush().await.unwrap();
+                }
+                return;
+            }
+
             let sleep_millis = 10;
             tokio::time::sleep(Duration::from_millis(sleep_millis)).await;
             gps_timestamp += sleep_millis;
@@ -88,6 +91,14 @@ impl Simulator {
                 payload: json!(Gps::new()),
             };
             partition.fill(gps).await.unwrap();
+
+            // Send partially filled buffers that are past their flush period
+            let now = Instant::now();
+            for partition in self.partitions.values_mut() {
+                if matches!(partition.deadline(), Some(deadline) if deadline <= now) {
+                    partition.flush().await.unwrap();
+                }
+            }
         }
     }
 }
@@ -95,7 +106,7 @@ impl Simulator {
 use crate::collector::tcpjson::Payload;
 use rand::Rng;
 use std::collections::HashMap;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 use tokio::time::Duration;
 
 #[derive(Debug, Clone, PartialEq, Serialize)]
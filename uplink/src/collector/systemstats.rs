@@ -6,10 +6,12 @@ use tokio::time::Instant;
 
 use std::{
     collections::HashMap,
+    fmt::Debug,
     sync::Arc,
     time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
+use crate::base::shutdown::Shutdown;
 use crate::base::{self, Buffer, Config, Package, Point, Stream};
 
 #[derive(thiserror::Error, Debug)]
@@ -41,10 +43,7 @@ pub struct System {
 impl System {
     fn init(sys: &sysinfo::System) -> System {
         System {
-            kernel_version: match sys.kernel_version() {
-                Some(kv) => kv,
-                None => String::default(),
-            },
+            kernel_version: sys.kernel_version().unwrap_or_default(),
             total_memory: sys.total_memory(),
             ..Default::default()
         }
@@ -74,20 +73,6 @@ impl Point for System {
     }
 }
 
-impl Package for Buffer<System> {
-    fn topic(&self) -> Arc<String> {
-        self.topic.clone()
-    }
-
-    fn serialize(&self) -> serde_json::Result<Vec<u8>> {
-        serde_json::to_vec(&self.buffer)
-    }
-
-    fn anomalies(&self) -> Option<(String, usize)> {
-        self.anomalies()
-    }
-}
-
 struct SystemStats {
     stat: System,
     stream: Stream<System>,
@@ -143,20 +128,6 @@ impl Point for Network {
     }
 }
 
-impl Package for Buffer<Network> {
-    fn topic(&self) -> Arc<String> {
-        self.topic.clone()
-    }
-
-    fn serialize(&self) -> serde_json::Result<Vec<u8>> {
-        serde_json::to_vec(&self.buffer)
-    }
-
-    fn anomalies(&self) -> Option<(String, usize)> {
-        self.anomalies()
-    }
-}
-
 struct NetworkStats {
     sequence: u32,
     map: HashMap<String, Network>,
@@ -211,20 +182,6 @@ impl Point for Disk {
     }
 }
 
-impl Package for Buffer<Disk> {
-    fn topic(&self) -> Arc<String> {
-        self.topic.clone()
-    }
-
-    fn serialize(&self) -> serde_json::Result<Vec<u8>> {
-        serde_json::to_vec(&self.buffer)
-    }
-
-    fn anomalies(&self) -> Option<(String, usize)> {
-        self.anomalies()
-    }
-}
-
 struct DiskStats {
     sequence: u32,
     map: HashMap<String, Disk>,
@@ -275,20 +232,6 @@ impl Point for Processor {
     }
 }
 
-impl Package for Buffer<Processor> {
-    fn topic(&self) -> Arc<String> {
-        self.topic.clone()
-    }
-
-    fn serialize(&self) -> serde_json::Result<Vec<u8>> {
-        serde_json::to_vec(&self.buffer)
-    }
-
-    fn anomalies(&self) -> Option<(String, usize)> {
-        self.anomalies()
-    }
-}
-
 struct ProcessorStats {
     sequence: u32,
     map: HashMap<String, Processor>,
@@ -350,20 +293,6 @@ impl Point for Process {
     }
 }
 
-impl Package for Buffer<Process> {
-    fn topic(&self) -> Arc<String> {
-        self.topic.clone()
-    }
-
-    fn serialize(&self) -> serde_json::Result<Vec<u8>> {
-        serde_json::to_vec(&self.buffer)
-    }
-
-    fn anomalies(&self) -> Option<(String, usize)> {
-        self.anomalies()
-    }
-}
-
 struct ProcessStats {
     sequence: u32,
     map: HashMap<Pid, Process>,
@@ -406,11 +335,47 @@ pub struct StatCollector {
     disks: DiskStats,
     /// Uplink configuration.
     config: Arc<Config>,
+    /// Signals collector to flush its streams and stop.
+    shutdown: Shutdown,
+}
+
+/// Stream of statistics, serialized and flushed as per its stream config,
+/// if there is one
+fn stat_stream<T>(
+    name: &str,
+    config: &Config,
+    max_buf_size: usize,
+    tx: Sender<Box<dyn Package>>,
+) -> Stream<T>
+where
+    T: Point + Debug + Send + 'static,
+    Buffer<T>: Package,
+{
+    let mut stream =
+        Stream::dynamic_with_size(name, &config.project_id, &config.device_id, max_buf_size, tx);
+    if let Some(stream_config) = config.streams.get(name) {
+        if let Err(e) = stream.configure(stream_config) {
+            error!("Failed to configure {} stream. Error = {}", name, e);
+        }
+    }
+
+    stream
+}
+
+fn flush_due<T>(stream: &mut Stream<T>) -> Result<(), base::Error>
+where
+    T: Point + Debug + Send + 'static,
+    Buffer<T>: Package,
+{
+    match stream.deadline() {
+        Some(deadline) if deadline <= std::time::Instant::now() => stream.flush_sync(),
+        _ => Ok(()),
+    }
 }
 
 impl StatCollector {
     /// Create and initialize a stat collector
-    pub fn new(config: Arc<Config>, tx: Sender<Box<dyn Package>>) -> Self {
+    pub fn new(config: Arc<Config>, tx: Sender<Box<dyn Package>>, shutdown: Shutdown) -> Self {
         let mut sys = sysinfo::System::new();
         sys.refresh_disks_list();
         sys.refresh_networks_list();
@@ -419,13 +384,7 @@ impl StatCollector {
         let max_buf_size = config.stats.stream_size.unwrap_or(10);
 
         let mut map = HashMap::new();
-        let stream = Stream::dynamic_with_size(
-            "uplink_disk_stats",
-            &config.project_id,
-            &config.device_id,
-            max_buf_size,
-            tx.clone(),
-        );
+        let stream = stat_stream("uplink_disk_stats", &config, max_buf_size, tx.clone());
         for disk_data in sys.disks() {
             let disk_name = disk_data.name().to_string_lossy().to_string();
             map.insert(disk_name.clone(), Disk::init(disk_name, disk_data));
@@ -433,59 +392,49 @@ impl StatCollector {
         let disks = DiskStats { sequence: 0, map, stream };
 
         let mut map = HashMap::new();
-        let stream = Stream::dynamic_with_size(
-            "uplink_network_stats",
-            &config.project_id,
-            &config.device_id,
-            max_buf_size,
-            tx.clone(),
-        );
+        let stream = stat_stream("uplink_network_stats", &config, max_buf_size, tx.clone());
         for (net_name, _) in sys.networks() {
             map.insert(net_name.to_owned(), Network::init(net_name.to_owned()));
         }
         let networks = NetworkStats { sequence: 0, map, stream };
 
         let mut map = HashMap::new();
-        let stream = Stream::dynamic_with_size(
-            "uplink_processor_stats",
-            &config.project_id,
-            &config.device_id,
-            max_buf_size,
-            tx.clone(),
-        );
+        let stream = stat_stream("uplink_processor_stats", &config, max_buf_size, tx.clone());
         for proc in sys.processors().iter() {
             let proc_name = proc.name().to_owned();
             map.insert(proc_name.clone(), Processor::init(proc_name));
         }
         let processors = ProcessorStats { sequence: 0, map, stream };
 
-        let stream = Stream::dynamic_with_size(
-            "uplink_process_stats",
-            &config.project_id,
-            &config.device_id,
-            max_buf_size,
-            tx.clone(),
-        );
+        let stream = stat_stream("uplink_process_stats", &config, max_buf_size, tx.clone());
         let processes = ProcessStats { sequence: 0, map: HashMap::new(), stream };
 
-        let stream = Stream::dynamic_with_size(
-            "uplink_system_stats",
-            &config.project_id,
-            &config.device_id,
-            max_buf_size,
-            tx.clone(),
-        );
+        let stream = stat_stream("uplink_system_stats", &config, max_buf_size, tx.clone());
         let system = SystemStats { stat: System::init(&sys), stream };
 
         let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
 
-        StatCollector { sys, system, config, processes, disks, networks, processors, timestamp }
+        StatCollector {
+            sys,
+            system,
+            config,
+            processes,
+            disks,
+            networks,
+            processors,
+            timestamp,
+            shutdown,
+        }
     }
 
     /// Stat collector execution loop, sleeps for the duation of `config.stats.update_period` in seconds.
+    /// Flushes partially filled streams and stops on shutdown.
     pub fn start(mut self) {
         loop {
-            std::thread::sleep(Duration::from_secs(self.config.stats.update_period));
+            if self.shutdown.wait(Duration::from_secs(self.config.stats.update_period)) {
+                self.flush();
+                return;
+            }
             self.timestamp += self.config.stats.update_period;
 
             if let Err(e) = self.update() {
@@ -495,6 +444,21 @@ impl StatCollector {
         }
     }
 
+    /// Send partially filled buffers of all streams.
+    fn flush(&mut self) {
+        for flushed in [
+            self.system.stream.flush_sync(),
+            self.disks.stream.flush_sync(),
+            self.networks.stream.flush_sync(),
+            self.processors.stream.flush_sync(),
+            self.processes.stream.flush_sync(),
+        ] {
+            if let Err(e) = flushed {
+                error!("Couldn't flush stats: {}", e);
+            }
+        }
+    }
+
     /// Update system information values and increment sequence numbers, while sending to specific data streams.
     fn update(&mut self) -> Result<(), Error> {
         if let Err(e) = self.system.push(&self.sys, self.timestamp) {
@@ -541,6 +505,19 @@ impl StatCollector {
         }
         self.sys.refresh_processes();
 
+        // Send partially filled buffers that are past their flush period
+        for flushed in [
+            flush_due(&mut self.system.stream),
+            flush_due(&mut self.disks.stream),
+            flush_due(&mut self.networks.stream),
+            flush_due(&mut self.processors.stream),
+            flush_due(&mut self.processes.stream),
+        ] {
+            if let Err(e) = flushed {
+                error!("Couldn't flush stats: {}", e);
+            }
+        }
+
         Ok(())
     }
 }
@@ -4,6 +4,7 @@ use serde::{Deserialize, Serialize};
 use thiserror::Error;
 use tokio::io::AsyncWriteExt;
 use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
 use tokio::{select, time};
 use tokio_stream::StreamExt;
 use tokio_util::codec::Framed;
@@ -12,8 +13,10 @@ use tokio_util::codec::{LinesCodec, LinesCodecError};
 use std::io;
 
 use crate::base::actions::{Action, ActionResponse, Error as ActionsError};
-use crate::base::{Buffer, Config, Package, Point, Stream};
-use serde_json::Value;
+use crate::base::connection::ConnectionStatus;
+use crate::base::shutdown::Shutdown;
+use crate::base::{format, Config, Package, Point, Stream};
+use serde_json::{json, Value};
 use std::collections::HashMap;
 use std::sync::Arc;
 use tokio::time::{Duration, Instant};
@@ -30,6 +33,8 @@ pub enum Error {
     Codec(#[from] LinesCodecError),
     #[error("Serde error {0}")]
     Json(#[from] serde_json::error::Error),
+    #[error("Serialization error {0}")]
+    Format(#[from] format::Error),
     #[error("Download OTA error")]
     Actions(#[from] ActionsError),
 }
@@ -40,6 +45,10 @@ pub struct Bridge {
     actions_rx: Receiver<Action>,
     current_action: Option<String>,
     action_status: Stream<ActionResponse>,
+    /// Status of the connection to the broker, forwarded to the connected app
+    connection_status: watch::Receiver<ConnectionStatus>,
+    /// Signals bridge to flush its streams and stop
+    shutdown: Shutdown,
 }
 
 impl Bridge {
@@ -48,8 +57,18 @@ impl Bridge {
         data_tx: Sender<Box<dyn Package>>,
         actions_rx: Receiver<Action>,
         action_status: Stream<ActionResponse>,
+        connection_status: watch::Receiver<ConnectionStatus>,
+        shutdown: Shutdown,
     ) -> Bridge {
-        Bridge { config, data_tx, actions_rx, current_action: None, action_status }
+        Bridge {
+            config,
+            data_tx,
+            actions_rx,
+            current_action: None,
+            action_status,
+            connection_status,
+            shutdown,
+        }
     }
 
     pub async fn start(&mut self) -> Result<(), Error> {
@@ -78,6 +97,7 @@ impl Bridge {
                             error!("Failed to send busy status. Error = {:?}", e);
                         }
                     }
+                    _ = self.shutdown.recv() => return Ok(()),
                 }
             };
 
@@ -86,6 +106,10 @@ impl Bridge {
             if let Err(e) = self.collect(framed).await {
                 error!("Bridge failed. Error = {:?}", e);
             }
+
+            if self.shutdown.is_triggered() {
+                return Ok(());
+            }
         }
     }
 
@@ -97,15 +121,27 @@ impl Bridge {
         for (stream, config) in self.config.streams.clone() {
             bridge_partitions.insert(
                 stream.clone(),
-                Stream::new(stream, config.topic, config.buf_size, self.data_tx.clone()),
+                Stream::with_config(stream, &config, self.data_tx.clone())?,
             );
         }
 
         let mut action_status = self.action_status.clone();
         let action_timeout = time::sleep(Duration::from_secs(100));
 
+        // App learns the state of the connection as soon as it connects, if it asked for it
+        let connection_status = self.config.bridge_connection_status;
+        if connection_status {
+            let status = self.connection_status.borrow().clone();
+            write_connection_status(&mut framed, &status).await?;
+        }
+
         tokio::pin!(action_timeout);
         loop {
+            // Partially filled buffers are flushed when the earliest of them is due
+            let flush_deadline = bridge_partitions.values().filter_map(Stream::deadline).min();
+            let flush_timeout =
+                time::sleep_until(flush_deadline.map_or_else(Instant::now, Instant::from_std));
+
             select! {
                 frame = framed.next() => {
                     let frame = frame.ok_or(Error::StreamDone)??;
@@ -167,6 +203,33 @@ impl Bridge {
                     framed.get_mut().write_all(b"\n").await?;
                 }
 
+                // Sender is dropped when publishes go to a sink instead of the broker
+                Ok(_) = self.connection_status.changed(), if connection_status => {
+                    let status = self.connection_status.borrow().clone();
+                    write_connection_status(&mut framed, &status).await?;
+                }
+
+                _ = flush_timeout, if flush_deadline.is_some() => {
+                    let now = Instant::now().into_std();
+                    for partition in bridge_partitions.values_mut() {
+                        if matches!(partition.deadline(), Some(deadline) if deadline <= now) {
+                            if let Err(e) = partition.flush().await {
+                                error!("Failed to flush data. Error = {:?}", e.to_string());
+                            }
+                        }
+                    }
+                }
+
+                _ = self.shutdown.recv() => {
+                    for partition in bridge_partitions.values_mut() {
+                        if let Err(e) = partition.flush().await {
+                            error!("Failed to flush data. Error = {:?}", e.to_string());
+                        }
+                    }
+
+                    return Ok(())
+                }
+
                 _ = &mut action_timeout, if self.current_action.is_some() => {
                     let action = self.current_action.take().unwrap();
                     error!("Timeout waiting for action response. Action ID = {}", action);
@@ -182,6 +245,18 @@ impl Bridge {
     }
 }
 
+/// Writes status of the connection to the app as a line of its own,
+/// `{"connection_status": {..}}`, which isn't an action
+async fn write_connection_status(
+    framed: &mut Framed<TcpStream, LinesCodec>,
+    status: &ConnectionStatus,
+) -> Result<(), Error> {
+    let data = serde_json::to_vec(&json!({ "connection_status": status }))?;
+    framed.get_mut().write_all(&data).await?;
+    framed.get_mut().write_all(b"\n").await?;
+    Ok(())
+}
+
 // TODO Don't do any deserialization on payload. Read it a Vec<u8> which is in turn a json
 // TODO which cloud will double deserialize (Batch 1st and messages next)
 #[derive(Debug, Serialize, Deserialize)]
@@ -210,16 +285,93 @@ impl Point for Payload {
     }
 }
 
-impl Package for Buffer<Payload> {
-    fn topic(&self) -> Arc<String> {
-        self.topic.clone()
+#[cfg(test)]
+mod test {
+    use tokio::io::{AsyncBufReadExt, BufReader};
+    use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+    use tokio::task::JoinHandle;
+
+    use super::*;
+    use crate::base::connection::ConnectionState;
+    use crate::base::shutdown::Stage;
+
+    struct Setup {
+        lines: tokio::io::Lines<BufReader<OwnedReadHalf>>,
+        /// bridge stops once the app closes its end
+        _app: OwnedWriteHalf,
+        actions_tx: Sender<Action>,
+        status_tx: watch::Sender<ConnectionStatus>,
+        _stage: Stage,
+        _bridge: JoinHandle<Result<(), Error>>,
+    }
+
+    /// Bridge collecting from an app connected to it
+    async fn setup(bridge_connection_status: bool) -> Setup {
+        let config = Config { bridge_connection_status, ..Default::default() };
+        let (data_tx, _) = flume::bounded(10);
+        let (actions_tx, actions_rx) = flume::bounded(10);
+        let (status_tx, status_rx) = watch::channel(ConnectionStatus::default());
+        let action_status = Stream::new("action_status", "/action/status", 1, data_tx.clone());
+        let stage = Stage::new();
+        let mut bridge = Bridge::new(
+            Arc::new(config),
+            data_tx,
+            actions_rx,
+            action_status,
+            status_rx,
+            stage.handle(),
+        );
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let app = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        let bridge =
+            tokio::spawn(
+                async move { bridge.collect(Framed::new(stream, LinesCodec::new())).await },
+            );
+
+        let (app, _app) = app.into_split();
+        let lines = BufReader::new(app).lines();
+        Setup { lines, _app, actions_tx, status_tx, _stage: stage, _bridge: bridge }
+    }
+
+    fn action(id: &str) -> Action {
+        let (kind, name, payload) = ("process".to_owned(), "reboot".to_owned(), "{}".to_owned());
+        Action { action_id: id.to_owned(), kind, name, payload }
     }
 
-    fn serialize(&self) -> serde_json::Result<Vec<u8>> {
-        serde_json::to_vec(&self.buffer)
+    async fn next_line(setup: &mut Setup) -> Value {
+        let line = time::timeout(Duration::from_secs(1), setup.lines.next_line()).await;
+        serde_json::from_str(&line.unwrap().unwrap().unwrap()).unwrap()
     }
 
-    fn anomalies(&self) -> Option<(String, usize)> {
-        self.anomalies()
+    #[tokio::test]
+    async fn apps_are_only_told_about_connection_status_if_enabled() {
+        let mut setup = setup(false).await;
+        let status = ConnectionStatus { state: ConnectionState::Connected, ..Default::default() };
+        setup.status_tx.send(status).unwrap();
+        setup.actions_tx.send(action("1")).unwrap();
+
+        // Nothing but actions reaches the app
+        assert_eq!(next_line(&mut setup).await["action_id"], "1");
+    }
+
+    #[tokio::test]
+    async fn connection_status_is_sent_on_connect_and_every_change() {
+        let mut setup = setup(true).await;
+        let line = next_line(&mut setup).await;
+        assert_eq!(line["connection_status"]["state"], "connecting");
+        assert!(line.get("action_id").is_none());
+
+        let status = ConnectionStatus {
+            state: ConnectionState::Disconnected,
+            reason: Some("refused".to_owned()),
+            attempts: 1,
+            ..Default::default()
+        };
+        setup.status_tx.send(status).unwrap();
+        let line = next_line(&mut setup).await;
+        assert_eq!(line["connection_status"]["state"], "disconnected");
+        assert_eq!(line["connection_status"]["attempts"], 1);
     }
 }
@@ -5,15 +5,22 @@ use std::thread;
 
 use anyhow::Error;
 
-use flume::{bounded, Receiver, Sender};
-use log::error;
+use flume::{bounded, unbounded, Receiver, Sender};
+use futures_util::future::BoxFuture;
+use futures_util::FutureExt;
+use log::{error, info, warn};
+use tokio::sync::watch;
 use tokio::task;
+use tokio::time::{Duration, Instant};
 
 mod base;
 mod collector;
 
 pub mod config {
+    pub use crate::base::failover::{Endpoint, Failover};
+    pub use crate::base::presence::PresenceConfig;
     pub use crate::base::{Config, Ota, Persistence, Stats};
+    pub use disk::Compression;
 }
 
 pub use base::actions;
@@ -21,13 +28,21 @@ use base::actions::ota::OtaDownloader;
 use base::actions::tunshell::{Relay, TunshellSession};
 use base::actions::Actions;
 pub use base::actions::{Action, ActionResponse};
-use base::mqtt::Mqtt;
+use base::connection::{Connection, ConnectionStats};
+pub use base::connection::{ConnectionState, ConnectionStatus};
+use base::mqtt::{Mqtt, Protocol};
+use base::mqttv5::MqttV5;
+use base::presence::Presence;
 use base::serializer::Serializer;
+pub use base::shutdown::Shutdown;
+use base::shutdown::Stage;
+use base::sink::{self, ChannelSink, Sink};
 pub use base::{Config, Package, Point, Stream};
 pub use collector::simulator::Simulator;
 use collector::systemstats::StatCollector;
 pub use collector::tcpjson::{Bridge, Payload};
 pub use disk::Storage;
+pub use rumqttc::Publish;
 
 struct RxTx<T> {
     rx: Receiver<T>,
@@ -47,6 +62,15 @@ pub struct Uplink {
     action_channel: RxTx<Action>,
     data_channel: RxTx<Box<dyn Package>>,
     action_status: Stream<ActionResponse>,
+    /// Collectors of data, stopped first on shutdown, after flushing their streams
+    collectors: Stage,
+    /// Serializer, mqtt and all threads spawned by uplink
+    core: Stage,
+    /// Channel that publishes are forwarded to instead of the broker
+    loopback: Option<Sender<Publish>>,
+    /// Status of the connection to the broker, broadcast by the eventloop
+    connection_status: watch::Receiver<ConnectionStatus>,
+    connection_status_tx: Option<watch::Sender<ConnectionStatus>>,
 }
 
 impl Uplink {
@@ -54,15 +78,26 @@ impl Uplink {
         let action_channel = RxTx::bounded(10);
         let data_channel = RxTx::bounded(10);
 
-        let action_status_topic = &config
+        let action_status_config = config
             .streams
             .get("action_status")
-            .ok_or_else(|| Error::msg("Action status topic missing from config"))?
-            .topic;
-        let action_status =
-            Stream::new("action_status", action_status_topic, 1, data_channel.tx.clone());
+            .ok_or_else(|| Error::msg("Action status topic missing from config"))?;
+        let mut action_status =
+            Stream::new("action_status", &action_status_config.topic, 1, data_channel.tx.clone());
+        action_status.configure(action_status_config)?;
+        let (connection_status_tx, connection_status) = watch::channel(ConnectionStatus::default());
 
-        Ok(Uplink { config, action_channel, data_channel, action_status })
+        Ok(Uplink {
+            config,
+            action_channel,
+            data_channel,
+            action_status,
+            collectors: Stage::new(),
+            core: Stage::new(),
+            loopback: None,
+            connection_status,
+            connection_status_tx: Some(connection_status_tx),
+        })
     }
 
     pub fn spawn(&mut self) -> Result<(), Error> {
@@ -76,7 +111,11 @@ impl Uplink {
             tunshell_keys.rx,
             self.action_status.clone(),
         );
-        thread::spawn(move || tunshell_session.start());
+        let shutdown = self.core.handle();
+        thread::spawn(move || {
+            tunshell_session.start();
+            drop(shutdown);
+        });
 
         // Launch a thread to handle downloads for OTA updates
         let (ota_tx, ota_downloader) = OtaDownloader::new(
@@ -85,19 +124,83 @@ impl Uplink {
             self.action_channel.tx.clone(),
         )?;
         if self.config.ota.enabled {
-            thread::spawn(move || ota_downloader.start());
+            let shutdown = self.core.handle();
+            thread::spawn(move || {
+                if let Err(e) = ota_downloader.start() {
+                    info!("OTA downloader stopped. Error = {:?}", e);
+                }
+                drop(shutdown);
+            });
         }
 
         // Launch a thread to collect system statistics
-        let stat_collector = StatCollector::new(self.config.clone(), self.data_channel.tx.clone());
+        let stat_collector = StatCollector::new(
+            self.config.clone(),
+            self.data_channel.tx.clone(),
+            self.collectors.handle(),
+        );
         if self.config.stats.enabled {
             thread::spawn(move || stat_collector.start());
         }
 
         let raw_action_channel = RxTx::bounded(10);
-        let mut mqtt = Mqtt::new(self.config.clone(), raw_action_channel.tx);
-        let serializer =
-            Serializer::new(self.config.clone(), self.data_channel.rx.clone(), mqtt.client())?;
+        let raw_actions_tx = raw_action_channel.tx;
+
+        // Publishes go to the broker unless they are to be written locally. Local sinks
+        // acknowledge publishes while serializer is publishing, without waiting on it
+        let (sink, acks_rx, eventloop): (Arc<dyn Sink>, _, Option<BoxFuture<'static, ()>>) =
+            match (self.loopback.take(), &self.config.sink) {
+                (Some(tx), _) => {
+                    let (acks_tx, acks_rx) = unbounded();
+                    (Arc::new(ChannelSink::new(tx, acks_tx)), acks_rx, None)
+                }
+                (None, Some(config)) => {
+                    let (acks_tx, acks_rx) = unbounded();
+                    (sink::open(config, acks_tx)?, acks_rx, None)
+                }
+                (None, None) => {
+                    let acks = RxTx::bounded(10);
+                    let config = self.config.clone();
+                    let actions_tx = raw_actions_tx.clone();
+                    let status_tx = self
+                        .connection_status_tx
+                        .take()
+                        .ok_or_else(|| Error::msg("Uplink is already spawned"))?;
+                    let connection = Connection::new(&config, status_tx);
+                    match self.config.protocol {
+                        Protocol::V4 => {
+                            let mut mqtt = Mqtt::new(config, actions_tx, acks.tx, connection)?;
+                            (Arc::new(mqtt.client()), acks.rx, Some(mqtt.start().boxed()))
+                        }
+                        Protocol::V5 => {
+                            let mut mqtt = MqttV5::new(config, actions_tx, acks.tx, connection)?;
+                            (Arc::new(mqtt.client()), acks.rx, Some(mqtt.start().boxed()))
+                        }
+                    }
+                }
+            };
+
+        // Connect and disconnect events are published only when there is a broker
+        let connection_stats = eventloop.as_ref().map(|_| {
+            ConnectionStats::new(
+                &self.config,
+                self.connection_status.clone(),
+                self.data_channel.tx.clone(),
+                self.collectors.handle(),
+            )
+        });
+
+        let client = sink.clone();
+        let presence = Presence::new(&self.config);
+        let connection_status = self.connection_status.clone();
+        let serializer = Serializer::new(
+            self.config.clone(),
+            self.data_channel.rx.clone(),
+            sink,
+            acks_rx,
+            self.connection_status.clone(),
+            self.core.handle(),
+        )?;
 
         let controllers: HashMap<String, Sender<base::Control>> = HashMap::new();
         let actions = Actions::new(
@@ -112,28 +215,101 @@ impl Uplink {
 
         // Launch a thread to handle incoming and outgoing MQTT packets
         let rt = tokio::runtime::Runtime::new()?;
+        let shutdown = self.core.handle();
         thread::spawn(move || {
             rt.block_on(async {
                 // Collect and forward data from connected applications as MQTT packets
-                task::spawn(async move {
+                let serializer = task::spawn(async move {
                     if let Err(e) = serializer.start().await {
                         error!("Serializer stopped!! Error = {:?}", e);
                     }
                 });
 
                 // Receive [Action]s
-                task::spawn(async move {
-                    mqtt.start().await;
-                });
+                let eventloop = eventloop.map(task::spawn);
+                if let Some(connection_stats) = connection_stats {
+                    task::spawn(connection_stats.start());
+                }
 
                 // Process and forward received [Action]s to connected applications
-                actions.start().await;
-            })
+                let actions = task::spawn(async move {
+                    actions.start().await;
+                });
+
+                // Stopping actions closes channels of tunshell and ota threads, which then
+                // exit while serializer persists data that is in memory. Publishes that
+                // serializer handed over to the eventloop go out before disconnecting
+                shutdown.recv().await;
+                actions.abort();
+                let _ = actions.await;
+                let _ = serializer.await;
+                if let Some(eventloop) = eventloop {
+                    // Nothing goes out while the connection is down, eventloop would only
+                    // keep retrying till the deadline
+                    if connection_status.borrow().state != ConnectionState::Connected {
+                        eventloop.abort();
+                    }
+
+                    // Broker publishes the last will only if uplink doesn't disconnect
+                    if let Some(presence) = presence {
+                        let mut offline =
+                            Publish::new(&presence.topic, presence.qos, presence.offline());
+                        offline.retain = presence.retain;
+                        if let Err(e) = client.publish(offline).await {
+                            error!("Failed to publish offline message. Error = {:?}", e);
+                        }
+                    }
+                    if let Err(e) = client.disconnect().await {
+                        error!("Failed to disconnect from broker. Error = {:?}", e);
+                    }
+                    let _ = eventloop.await;
+                }
+            });
+
+            // Actions are received only from the broker. Channel is open till shutdown
+            drop(raw_actions_tx);
+
+            rt.shutdown_background();
+            drop(shutdown);
         });
 
         Ok(())
     }
 
+    /// Forwards publishes into the returned channel instead of to the broker, e.g for
+    /// tests to assert on batches of data. To be called before spawning uplink
+    pub fn loopback(&mut self, cap: usize) -> Receiver<Publish> {
+        let (tx, rx) = bounded(cap);
+        self.loopback = Some(tx);
+        rx
+    }
+
+    /// Handle for collectors of data outside uplink, e.g bridge, to learn about shutdown.
+    /// Uplink waits for collectors to flush their streams and drop the handle on shutdown
+    pub fn shutdown_handle(&self) -> Shutdown {
+        self.collectors.handle()
+    }
+
+    /// Stops collectors after they flush their streams, then lets serializer persist data
+    /// that is still in memory, disconnects from the broker and waits for all threads to
+    /// exit. Gives up after `shutdown_timeout` seconds
+    pub async fn shutdown(&mut self) -> Result<(), Error> {
+        let timeout = Duration::from_secs(self.config.shutdown_timeout.unwrap_or(10));
+        let deadline = Instant::now() + timeout;
+
+        info!("Shutting down collectors");
+        if !self.collectors.trigger(deadline).await {
+            warn!("Collectors didn't stop in time, data in their streams is lost");
+        }
+
+        info!("Shutting down serializer, mqtt and actions");
+        if !self.core.trigger(deadline).await {
+            return Err(Error::msg("Timed out waiting for uplink to shut down"));
+        }
+
+        Ok(())
+    }
+
     pub fn bridge_action_rx(&self) -> Receiver<Action> {
         self.action_channel.rx.clone()
     }
@@ -145,4 +321,100 @@ impl Uplink {
     pub fn action_status(&self) -> Stream<ActionResponse> {
         self.action_status.clone()
     }
+
+    /// Status of the connection to the broker, updated as the eventloop connects,
+    /// disconnects and retries
+    pub fn connection_status(&self) -> watch::Receiver<ConnectionStatus> {
+        self.connection_status.clone()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+    use tempdir::TempDir;
+
+    use super::*;
+    use crate::config::Ota;
+
+    fn config(ota: &TempDir) -> Config {
+        let streams = json!({
+            "action_status": { "topic": "/action/status", "buf_size": 1 },
+            "metrics": { "topic": "/metrics", "buf_size": 10 }
+        });
+
+        Config {
+            device_id: "1".to_owned(),
+            // Nothing listens on port 1, connections are refused
+            broker: "localhost".to_owned(),
+            port: 1,
+            max_packet_size: 1024 * 1024,
+            max_inflight: 10,
+            streams: serde_json::from_value(streams).unwrap(),
+            ota: Ota { enabled: true, path: ota.path().to_str().unwrap().to_owned() },
+            shutdown_timeout: Some(5),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn batches_are_forwarded_to_loopback_without_broker() {
+        let dir = TempDir::new("uplink").unwrap();
+        let mut uplink = Uplink::new(Arc::new(config(&dir))).unwrap();
+        let publishes = uplink.loopback(10);
+        uplink.spawn().unwrap();
+
+        let mut stream = Stream::new("can", "/can", 2, uplink.bridge_data_tx());
+        for sequence in 1..=4 {
+            let payload = json!({ "speed": sequence * 10 });
+            let data = Payload { stream: "can".to_owned(), sequence, timestamp: 0, payload };
+            stream.fill(data).await.unwrap();
+        }
+
+        let mut batches = vec![];
+        while batches.len() < 2 {
+            let publish = tokio::time::timeout(Duration::from_secs(1), publishes.recv_async());
+            let publish = publish.await.unwrap().unwrap();
+            if publish.topic == "/can" {
+                let batch: serde_json::Value = serde_json::from_slice(&publish.payload).unwrap();
+                batches.push(batch);
+            }
+        }
+
+        let batch = json!([
+            { "sequence": 1, "timestamp": 0, "speed": 10 },
+            { "sequence": 2, "timestamp": 0, "speed": 20 }
+        ]);
+        assert_eq!(batches[0], batch);
+        assert_eq!(batches[1][1]["speed"], 40);
+        uplink.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn shutdown_finishes_before_deadline() {
+        let dir = TempDir::new("uplink").unwrap();
+        let mut uplink = Uplink::new(Arc::new(config(&dir))).unwrap();
+        let _publishes = uplink.loopback(10);
+        uplink.spawn().unwrap();
+
+        let start = Instant::now();
+        uplink.shutdown().await.unwrap();
+        assert!(start.elapsed() < Duration::from_secs(1), "took {:?}", start.elapsed());
+    }
+
+    #[tokio::test]
+    async fn shutdown_while_disconnected_finishes_before_deadline() {
+        let dir = TempDir::new("uplink").unwrap();
+        let mut uplink = Uplink::new(Arc::new(config(&dir))).unwrap();
+        uplink.spawn().unwrap();
+
+        let mut status = uplink.connection_status();
+        while status.borrow().state != ConnectionState::Disconnected {
+            status.changed().await.unwrap();
+        }
+
+        let start = Instant::now();
+        uplink.shutdown().await.unwrap();
+        assert!(start.elapsed() < Duration::from_secs(1), "took {:?}", start.elapsed());
+    }
 }
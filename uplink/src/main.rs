@@ -46,12 +46,15 @@ use anyhow::{Context, Error};
 use figment::{
     providers::Toml,
     providers::{Data, Json},
+    value::Dict,
     Figment,
 };
-use log::error;
+use flate2::Crc;
+use log::{error, info};
 use simplelog::{ColorChoice, CombinedLogger, LevelFilter, LevelPadding, TermLogger, TerminalMode};
 use structopt::StructOpt;
-use tokio::task;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::{select, task};
 
 use uplink::{Bridge, Config, Simulator, Uplink};
 
@@ -110,6 +113,7 @@ const DEFAULT_CONFIG: &str = r#"
     [streams.action_status]
     topic = "/tenants/{tenant_id}/devices/{device_id}/action/status"
     buf_size = 1
+    priority = 10
 
     [ota]
     enabled = false
@@ -130,6 +134,13 @@ fn initalize_config(commandline: &CommandLine) -> Result<Config, Error> {
         config = config.merge(Data::<Toml>::file(c));
     }
 
+    // Hash of the configuration, announced in presence messages, leaves out credentials
+    // in the auth file. Dictionaries are ordered, for it to not change between runs
+    let dict: Dict = config.extract().with_context(|| "Config error".to_string())?;
+    let mut crc = Crc::new();
+    crc.update(&serde_json::to_vec(&dict)?);
+    let config_hash = format!("{:08x}", crc.sum());
+
     let mut config: Config = config
         .join(Data::<Json>::file(&commandline.auth))
         .extract()
@@ -145,8 +156,9 @@ fn initalize_config(commandline: &CommandLine) -> Result<Config, Error> {
         config.topic = topic;
 
         let topic = str::replace(&config.topic, "{device_id}", device_id);
-        config.topic = topic;
+        config.topic = config.format.topic(&topic);
     }
+    config.config_hash = config_hash;
 
     Ok(config)
 }
@@ -192,14 +204,33 @@ fn banner(commandline: &CommandLine, config: &Arc<Config>) {
     println!("    commit_date: {}", commandline.commit_date);
     println!("    project_id: {}", config.project_id);
     println!("    device_id: {}", config.device_id);
-    println!("    remote: {}:{}", config.broker, config.port);
-    println!("    secure_transport: {}", config.authentication.is_some());
+    let endpoints = config.endpoints();
+    println!("    remote: {}", endpoints[0]);
+    if endpoints.len() > 1 {
+        let failover: Vec<_> = endpoints[1..].iter().map(ToString::to_string).collect();
+        println!("    failover_remotes: {}", failover.join(", "));
+    }
+    println!("    protocol: {:?}", config.protocol);
+    if let Some(sink) = &config.sink {
+        println!("    sink: {:?}", sink);
+    }
+    println!("    secure_transport: {}", endpoints[0].authentication.is_some());
     println!("    max_packet_size: {}", config.max_packet_size);
     println!("    max_inflight_messages: {}", config.max_inflight);
     if let Some(persistence) = &config.persistence {
         println!("    persistence_dir: {}", persistence.path);
         println!("    persistence_max_segment_size: {}", persistence.max_file_size);
         println!("    persistence_max_segment_count: {}", persistence.max_file_count);
+        println!("    persistence_compression: {:?}", persistence.compression);
+        if let Some(max_disk_usage) = persistence.max_disk_usage {
+            println!("    persistence_max_disk_usage: {}", max_disk_usage);
+        }
+        if let Some(min_free_space) = persistence.min_free_space {
+            println!("    persistence_min_free_space: {}", min_free_space);
+        }
+        if let Some(max_age) = persistence.max_age {
+            println!("    persistence_max_age: {}s", max_age);
+        }
     }
     if config.ota.enabled {
         println!("    ota_path: {}", config.ota.path);
@@ -224,7 +255,8 @@ async fn main() -> Result<(), Error> {
     uplink.spawn()?;
 
     if enable_simulator {
-        let mut simulator = Simulator::new(config.clone(), uplink.bridge_data_tx());
+        let mut simulator =
+            Simulator::new(config.clone(), uplink.bridge_data_tx(), uplink.shutdown_handle());
         task::spawn(async move {
             simulator.start().await;
         });
@@ -235,9 +267,26 @@ async fn main() -> Result<(), Error> {
         uplink.bridge_data_tx(),
         uplink.bridge_action_rx(),
         uplink.action_status(),
+        uplink.connection_status(),
+        uplink.shutdown_handle(),
     );
-    if let Err(e) = bridge.start().await {
-        error!("Bridge stopped!! Error = {:?}", e);
+    let mut bridge = task::spawn(async move {
+        if let Err(e) = bridge.start().await {
+            error!("Bridge stopped!! Error = {:?}", e);
+        }
+    });
+
+    // Persist data buffered in memory before exiting on SIGTERM/SIGINT
+    let mut sigterm = signal(SignalKind::terminate())?;
+    let mut sigint = signal(SignalKind::interrupt())?;
+    select! {
+        _ = sigterm.recv() => info!("Received SIGTERM, shutting down"),
+        _ = sigint.recv() => info!("Received SIGINT, shutting down"),
+        _ = &mut bridge => info!("Bridge stopped, shutting down"),
+    }
+
+    if let Err(e) = uplink.shutdown().await {
+        error!("Failed to shut down cleanly. Error = {:?}", e);
     }
 
     Ok(())